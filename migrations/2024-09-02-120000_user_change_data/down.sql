@@ -0,0 +1 @@
+ALTER TABLE "user_change" DROP COLUMN IF EXISTS "data";
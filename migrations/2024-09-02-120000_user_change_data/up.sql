@@ -0,0 +1 @@
+ALTER TABLE "user_change" ADD COLUMN "data" JSONB;
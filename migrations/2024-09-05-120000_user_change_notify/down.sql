@@ -0,0 +1,2 @@
+DROP TRIGGER IF EXISTS notify_user_change ON "user_change";
+DROP FUNCTION IF EXISTS notify_user_change();
@@ -0,0 +1,9 @@
+CREATE OR REPLACE FUNCTION notify_user_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('user_change', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_user_change AFTER INSERT ON "user_change"
+    FOR EACH ROW EXECUTE PROCEDURE notify_user_change();
@@ -0,0 +1,19 @@
+CREATE TABLE "webhook"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"event" VARCHAR NOT NULL,
+	"url" VARCHAR NOT NULL,
+	"secret" VARCHAR NOT NULL
+);
+CREATE TABLE "webhook_delivery"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"webhook_id" INTEGER NOT NULL,
+	"payload" JSONB NOT NULL,
+	"status" VARCHAR NOT NULL,
+	"attempts" INTEGER NOT NULL,
+	"next_attempt" DOUBLE PRECISION NOT NULL,
+	"err" VARCHAR,
+	FOREIGN KEY ("webhook_id") REFERENCES "webhook"("id") ON DELETE CASCADE
+);
+CREATE INDEX "webhook_delivery_due" ON "webhook_delivery"("status", "next_attempt");
@@ -0,0 +1,3 @@
+ALTER TABLE "appuser"
+	DROP COLUMN IF EXISTS "created",
+	DROP COLUMN IF EXISTS "updated";
@@ -0,0 +1,8 @@
+ALTER TABLE "appuser"
+	ADD COLUMN "created" DOUBLE PRECISION NOT NULL
+		DEFAULT extract(epoch FROM now())::DOUBLE PRECISION,
+	ADD COLUMN "updated" DOUBLE PRECISION NOT NULL
+		DEFAULT extract(epoch FROM now())::DOUBLE PRECISION;
+ALTER TABLE "appuser"
+	ALTER COLUMN "created" DROP DEFAULT,
+	ALTER COLUMN "updated" DROP DEFAULT;
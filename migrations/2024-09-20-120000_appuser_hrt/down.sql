@@ -0,0 +1,3 @@
+-- hashes cannot be reverted, so all users are logged out
+UPDATE "appuser" SET "hrt" = NULL;
+ALTER TABLE "appuser" RENAME COLUMN "hrt" TO "rt";
@@ -0,0 +1,3 @@
+UPDATE "appuser" SET "rt" = encode(sha256("rt"::bytea), 'hex')
+	WHERE "rt" IS NOT NULL;
+ALTER TABLE "appuser" RENAME COLUMN "rt" TO "hrt";
@@ -0,0 +1,2 @@
+DROP INDEX "appuser_nusername_key";
+ALTER TABLE "appuser" DROP COLUMN "nusername";
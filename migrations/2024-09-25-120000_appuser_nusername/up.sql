@@ -0,0 +1,25 @@
+-- normalised username for case-insensitive uniqueness and lookup, archived
+-- users keep theirs but are excluded from the uniqueness
+ALTER TABLE "appuser" ADD COLUMN "nusername" VARCHAR;
+UPDATE "appuser" SET "nusername" = lower(normalize("username", NFKC));
+ALTER TABLE "appuser" ALTER COLUMN "nusername" SET NOT NULL;
+-- active users whose usernames only differ in case or compatibility forms
+-- must be renamed or archived by hand before they can become unique
+DO $$
+DECLARE
+	collisions TEXT;
+BEGIN
+	SELECT string_agg("names", '; ') INTO collisions FROM (
+		SELECT string_agg("username", ', ' ORDER BY "id") AS "names"
+		FROM "appuser"
+		WHERE "username" NOT LIKE 'archived::%'
+		GROUP BY "nusername"
+		HAVING count(*) > 1
+	) AS "collision";
+	IF collisions IS NOT NULL THEN
+		RAISE EXCEPTION 'usernames collide case-insensitively, rename or '
+			'archive all but one of each group: %', collisions;
+	END IF;
+END $$;
+CREATE UNIQUE INDEX "appuser_nusername_key" ON "appuser"("nusername")
+	WHERE "username" NOT LIKE 'archived::%';
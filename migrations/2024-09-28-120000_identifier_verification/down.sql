@@ -0,0 +1,7 @@
+DROP TABLE "verification";
+DROP INDEX "appuser_phone_key";
+DROP INDEX "appuser_email_key";
+ALTER TABLE "appuser" DROP COLUMN "phone_verified_at";
+ALTER TABLE "appuser" DROP COLUMN "phone";
+ALTER TABLE "appuser" DROP COLUMN "email_verified_at";
+ALTER TABLE "appuser" DROP COLUMN "email";
@@ -0,0 +1,22 @@
+ALTER TABLE "appuser" ADD COLUMN "email" VARCHAR;
+ALTER TABLE "appuser" ADD COLUMN "email_verified_at" DOUBLE PRECISION;
+ALTER TABLE "appuser" ADD COLUMN "phone" VARCHAR;
+ALTER TABLE "appuser" ADD COLUMN "phone_verified_at" DOUBLE PRECISION;
+-- only verified identifiers are used for login, so only they are unique
+CREATE UNIQUE INDEX "appuser_email_key" ON "appuser"("email")
+	WHERE "email_verified_at" IS NOT NULL
+		AND "username" NOT LIKE 'archived::%';
+CREATE UNIQUE INDEX "appuser_phone_key" ON "appuser"("phone")
+	WHERE "phone_verified_at" IS NOT NULL
+		AND "username" NOT LIKE 'archived::%';
+CREATE TABLE "verification"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"user_id" INTEGER NOT NULL,
+	"kind" VARCHAR NOT NULL,
+	"target" VARCHAR NOT NULL,
+	"hcode" VARCHAR NOT NULL,
+	"expires" DOUBLE PRECISION NOT NULL,
+	"attempts" INTEGER NOT NULL,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+CREATE TABLE "mail"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"template" VARCHAR NOT NULL,
+	"recipient" VARCHAR NOT NULL,
+	"subject" VARCHAR NOT NULL,
+	"body" VARCHAR NOT NULL,
+	"status" VARCHAR NOT NULL,
+	"attempts" INTEGER NOT NULL,
+	"next_attempt" DOUBLE PRECISION NOT NULL,
+	"err" VARCHAR
+);
+CREATE INDEX "mail_due" ON "mail"("status", "next_attempt");
@@ -0,0 +1,20 @@
+CREATE TABLE "mfa"(
+	"user_id" INTEGER PRIMARY KEY,
+	"required" BOOLEAN NOT NULL,
+	-- base32 secret, set on enrollment
+	"totp_secret" VARCHAR,
+	"totp_confirmed_at" DOUBLE PRECISION,
+	-- last accepted time step, so codes cannot be replayed
+	"totp_last_step" BIGINT NOT NULL,
+	-- wrong codes since the last login with password
+	"attempts" INTEGER NOT NULL,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
+CREATE TABLE "recovery_code"(
+	"id" SERIAL PRIMARY KEY,
+	"user_id" INTEGER NOT NULL,
+	"hcode" VARCHAR NOT NULL,
+	"used_at" DOUBLE PRECISION,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
+CREATE INDEX "recovery_code_user_id" ON "recovery_code"("user_id");
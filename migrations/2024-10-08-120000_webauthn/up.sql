@@ -0,0 +1,26 @@
+CREATE TABLE "webauthn_credential"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"user_id" INTEGER NOT NULL,
+	-- base64url credential id given by the authenticator
+	"credential_id" VARCHAR NOT NULL UNIQUE,
+	-- base64url SEC1 P-256 public key
+	"public_key" VARCHAR NOT NULL,
+	"sign_count" BIGINT NOT NULL,
+	"name" VARCHAR,
+	"last_used" DOUBLE PRECISION,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
+CREATE INDEX "webauthn_credential_user_id"
+	ON "webauthn_credential"("user_id");
+CREATE TABLE "webauthn_challenge"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"challenge" VARCHAR NOT NULL,
+	-- registration or assertion
+	"kind" VARCHAR NOT NULL,
+	-- unknown for assertions of discoverable credentials
+	"user_id" INTEGER,
+	"expires" DOUBLE PRECISION NOT NULL,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
@@ -0,0 +1,23 @@
+CREATE TABLE "oauth_client"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"client_id" VARCHAR NOT NULL UNIQUE,
+	-- kept as is, since id tokens are signed with it
+	"secret" VARCHAR NOT NULL,
+	"name" VARCHAR NOT NULL,
+	"redirect_uris" JSONB NOT NULL
+);
+CREATE TABLE "oauth_code"(
+	"hcode" VARCHAR PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"client_id" INTEGER NOT NULL,
+	"user_id" INTEGER NOT NULL,
+	"redirect_uri" VARCHAR NOT NULL,
+	"scope" VARCHAR NOT NULL,
+	"nonce" VARCHAR,
+	"code_challenge" VARCHAR,
+	"expires" DOUBLE PRECISION NOT NULL,
+	FOREIGN KEY ("client_id") REFERENCES "oauth_client"("id")
+		ON DELETE CASCADE,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- server scopes which the client can get service tokens for
+ALTER TABLE "oauth_client" ADD COLUMN "scopes" JSONB NOT NULL DEFAULT '[]';
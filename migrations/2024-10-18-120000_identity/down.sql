@@ -0,0 +1,2 @@
+DROP TABLE "upstream_login";
+DROP TABLE "identity";
@@ -0,0 +1,18 @@
+CREATE TABLE "identity"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"provider" VARCHAR NOT NULL,
+	-- `sub` claim of the provider
+	"subject" VARCHAR NOT NULL,
+	"user_id" INTEGER NOT NULL,
+	UNIQUE ("provider", "subject"),
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
+CREATE TABLE "upstream_login"(
+	"hstate" VARCHAR PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"provider" VARCHAR NOT NULL,
+	"nonce" VARCHAR NOT NULL,
+	"code_verifier" VARCHAR NOT NULL,
+	"expires" DOUBLE PRECISION NOT NULL
+);
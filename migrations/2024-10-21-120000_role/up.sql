@@ -0,0 +1,21 @@
+CREATE TABLE "role"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"name" VARCHAR NOT NULL UNIQUE
+);
+CREATE TABLE "role_permission"(
+	"role_id" INTEGER NOT NULL,
+	"permission" VARCHAR NOT NULL,
+	PRIMARY KEY ("role_id", "permission"),
+	FOREIGN KEY ("role_id") REFERENCES "role"("id") ON DELETE CASCADE
+);
+CREATE TABLE "user_role"(
+	"user_id" INTEGER NOT NULL,
+	"role_id" INTEGER NOT NULL,
+	-- empty for roles granted in all domains
+	"domain" VARCHAR NOT NULL DEFAULT '',
+	"created" DOUBLE PRECISION NOT NULL,
+	PRIMARY KEY ("user_id", "role_id", "domain"),
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE,
+	FOREIGN KEY ("role_id") REFERENCES "role"("id") ON DELETE CASCADE
+);
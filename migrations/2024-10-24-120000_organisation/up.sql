@@ -0,0 +1,15 @@
+CREATE TABLE "organisation"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"name" VARCHAR NOT NULL
+);
+CREATE TABLE "membership"(
+	"org_id" INTEGER NOT NULL,
+	"user_id" INTEGER NOT NULL,
+	"role" VARCHAR NOT NULL,
+	"created" DOUBLE PRECISION NOT NULL,
+	PRIMARY KEY ("org_id", "user_id"),
+	FOREIGN KEY ("org_id") REFERENCES "organisation"("id") ON DELETE CASCADE,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
+CREATE INDEX "membership_user_id" ON "membership"("user_id");
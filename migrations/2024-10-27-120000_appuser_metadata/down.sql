@@ -0,0 +1 @@
+ALTER TABLE "appuser" DROP COLUMN "metadata";
@@ -0,0 +1,3 @@
+-- `domain` keys are written only by domains, `user` keys by users too
+ALTER TABLE "appuser" ADD COLUMN "metadata" JSONB NOT NULL
+	DEFAULT '{"domain": {}, "user": {}}';
@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS reject_auth_event_change ON "auth_event";
+DROP FUNCTION IF EXISTS reject_auth_event_change();
+DROP TABLE "auth_event";
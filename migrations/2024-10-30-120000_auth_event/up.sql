@@ -0,0 +1,26 @@
+-- user_id has no foreign key, since events are written for calls about
+-- unknown users too
+CREATE TABLE "auth_event"(
+	"id" SERIAL PRIMARY KEY,
+	"created" DOUBLE PRECISION NOT NULL,
+	"kind" VARCHAR NOT NULL,
+	"user_id" INTEGER,
+	"domain" VARCHAR,
+	"rpc" VARCHAR,
+	"ip" VARCHAR,
+	"user_agent" VARCHAR,
+	"outcome" VARCHAR NOT NULL,
+	"reason" VARCHAR
+);
+CREATE INDEX "auth_event_user_id" ON "auth_event"("user_id");
+CREATE INDEX "auth_event_created" ON "auth_event"("created");
+
+CREATE OR REPLACE FUNCTION reject_auth_event_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'auth_event is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER reject_auth_event_change BEFORE UPDATE OR DELETE
+    ON "auth_event"
+    FOR EACH ROW EXECUTE PROCEDURE reject_auth_event_change();
@@ -0,0 +1 @@
+ALTER TABLE "mfa" DROP COLUMN "attempts_since";
@@ -0,0 +1,2 @@
+-- wrong codes are counted per user within a window, logins do not reset them
+ALTER TABLE "mfa" ADD COLUMN "attempts_since" DOUBLE PRECISION;
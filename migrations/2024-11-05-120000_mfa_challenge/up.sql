@@ -0,0 +1,7 @@
+-- login challenges are single-use, the token only carries the nonce
+CREATE TABLE "mfa_challenge"(
+	"hnonce" VARCHAR PRIMARY KEY,
+	"user_id" INTEGER NOT NULL,
+	"expires" DOUBLE PRECISION NOT NULL,
+	FOREIGN KEY ("user_id") REFERENCES "appuser"("id") ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- secrets cannot be restored, clients need new ones
+ALTER TABLE "oauth_client" RENAME COLUMN "hsecret" TO "secret";
@@ -0,0 +1,4 @@
+-- id tokens are signed by the provider key, so secrets are only compared
+ALTER TABLE "oauth_client" RENAME COLUMN "secret" TO "hsecret";
+UPDATE "oauth_client"
+	SET "hsecret" = encode(sha256(convert_to("hsecret", 'UTF8')), 'hex');
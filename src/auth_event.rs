@@ -0,0 +1,429 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use diesel::{pg::Pg, prelude::*};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    db::{Con, Id},
+    quco::{
+        self, col_cond, col_order, BoxedCond, BoxedOrder, Collection, Field,
+        FieldType, Op, QueryCollection, Search, Sort,
+    },
+    ryz::{
+        enm::StrEnum,
+        err::{self, Error},
+        res::Res,
+        time::{utc, Time},
+    },
+    schema, APPRC,
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AuthEventKind {
+    Login,
+    Logout,
+    /// Access token was issued by a refresh token.
+    Access,
+    /// Domain called a `/rpc/server/*` route.
+    Server,
+    /// Domain issued an access token of the user to an impersonator.
+    Impersonation,
+}
+
+impl StrEnum for AuthEventKind {
+    fn to_str(&self) -> &str {
+        match self {
+            AuthEventKind::Login => "login",
+            AuthEventKind::Logout => "logout",
+            AuthEventKind::Access => "access",
+            AuthEventKind::Server => "server",
+            AuthEventKind::Impersonation => "impersonation",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "login" => Ok(AuthEventKind::Login),
+            "logout" => Ok(AuthEventKind::Logout),
+            "access" => Ok(AuthEventKind::Access),
+            "server" => Ok(AuthEventKind::Server),
+            "impersonation" => Ok(AuthEventKind::Impersonation),
+            _ => err::res_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AuthOutcome {
+    Success,
+    Failure,
+}
+
+impl StrEnum for AuthOutcome {
+    fn to_str(&self) -> &str {
+        match self {
+            AuthOutcome::Success => "success",
+            AuthOutcome::Failure => "failure",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "success" => Ok(AuthOutcome::Success),
+            "failure" => Ok(AuthOutcome::Failure),
+            _ => err::res_default(),
+        }
+    }
+}
+
+/// Authentication event, events are never changed once written.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthEvent {
+    pub id: Id,
+    pub created: Time,
+    pub kind: AuthEventKind,
+    /// User the event is about, if known.
+    pub user_id: Option<Id>,
+    /// Domain an access token is requested for, the service client which
+    /// called a server route, or the OAuth client a user signs in to.
+    pub domain: Option<String>,
+    /// Name of the called server route, e.g. `reg`.
+    pub rpc: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuthOutcome,
+    /// Error code of a failure, or it's message for generic errors.
+    pub reason: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::auth_event)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuthEventTable {
+    pub id: Id,
+    pub created: Time,
+    pub kind: String,
+    pub user_id: Option<Id>,
+    pub domain: Option<String>,
+    pub rpc: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub reason: Option<String>,
+}
+
+impl Collection<AuthEvent> for AuthEventTable {
+    fn to_msg(&self) -> AuthEvent {
+        AuthEvent {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            kind: AuthEventKind::from_str(self.kind.as_str()).unwrap(),
+            user_id: self.user_id.to_owned(),
+            domain: self.domain.to_owned(),
+            rpc: self.rpc.to_owned(),
+            ip: self.ip.to_owned(),
+            user_agent: self.user_agent.to_owned(),
+            outcome: AuthOutcome::from_str(self.outcome.as_str()).unwrap(),
+            reason: self.reason.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::auth_event)]
+struct InsertAuthEvent {
+    created: Time,
+    kind: String,
+    user_id: Option<Id>,
+    domain: Option<String>,
+    rpc: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    outcome: String,
+    reason: Option<String>,
+}
+
+pub struct NewAuthEvent {
+    pub kind: AuthEventKind,
+    pub user_id: Option<Id>,
+    pub domain: Option<String>,
+    pub rpc: Option<String>,
+    pub outcome: AuthOutcome,
+    pub reason: Option<String>,
+}
+
+/// Where a request came from, extracted from it's headers.
+///
+/// The ip is taken from the connection if corund is served with connect
+/// info. Only if the connection comes from one of `audit.trusted_proxies`,
+/// it's taken from `X-Forwarded-For` or `X-Real-IP` set by the proxy.
+#[derive(Debug, Default, Clone)]
+pub struct Origin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Origin {
+    pub fn new(headers: &HeaderMap, addr: Option<SocketAddr>) -> Self {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        let is_trusted =
+            |ip: IpAddr| APPRC.audit.trusted_proxies.contains(&ip);
+        let forwarded = || {
+            let Some(chain) = get("x-forwarded-for") else {
+                return get("x-real-ip")?.parse::<IpAddr>().ok();
+            };
+            // each proxy appends the address it got the request from, so
+            // the client is the last address which isn't a trusted proxy
+            let hops: Vec<&str> = chain.split(',').map(|x| x.trim()).collect();
+            hops.iter()
+                .rev()
+                .find(|x| !x.parse().is_ok_and(is_trusted))
+                .or(hops.first())?
+                .parse()
+                .ok()
+        };
+        let ip = match addr.map(|x| x.ip()) {
+            Some(peer) if is_trusted(peer) => forwarded().or(Some(peer)),
+            peer => peer,
+        };
+        Self {
+            ip: ip.map(|x| x.to_string()),
+            user_agent: get(header::USER_AGENT.as_str()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Origin {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let addr = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|x| x.0);
+        Ok(Origin::new(&parts.headers, addr))
+    }
+}
+
+/// Reason of a failure to record, see `AuthEvent::reason`.
+pub fn reason(e: &Error) -> String {
+    if e.code() == "err" && !e.msg().is_empty() {
+        e.msg().to_string()
+    } else {
+        e.code().to_string()
+    }
+}
+
+/// Writes a new event.
+pub fn new(
+    data: &NewAuthEvent,
+    origin: &Origin,
+    con: &mut Con,
+) -> Res<AuthEvent> {
+    let event: AuthEventTable = diesel::insert_into(schema::auth_event::table)
+        .values(&InsertAuthEvent {
+            created: utc(),
+            kind: data.kind.to_str().to_string(),
+            user_id: data.user_id,
+            domain: data.domain.to_owned(),
+            rpc: data.rpc.to_owned(),
+            ip: origin.ip.to_owned(),
+            user_agent: origin.user_agent.to_owned(),
+            outcome: data.outcome.to_str().to_string(),
+            reason: data.reason.to_owned(),
+        })
+        .returning(AuthEventTable::as_returning())
+        .get_result(con)?;
+    Ok(event.to_msg())
+}
+
+/// Writes an event with the outcome of the RPC result.
+pub fn record<T>(
+    kind: AuthEventKind,
+    user_id: Option<Id>,
+    domain: Option<String>,
+    origin: &Origin,
+    res: &Res<T>,
+    con: &mut Con,
+) -> Res<()> {
+    let (outcome, reason) = match res {
+        Ok(_) => (AuthOutcome::Success, None),
+        Err(e) => (AuthOutcome::Failure, Some(reason(e))),
+    };
+    new(
+        &NewAuthEvent {
+            kind,
+            user_id,
+            domain,
+            rpc: None,
+            outcome,
+            reason,
+        },
+        origin,
+        con,
+    )?;
+    Ok(())
+}
+
+pub type GetAuthEvents = Search;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetAuthEventsRes<T> {
+    pub events: Vec<T>,
+    /// Amount of all events matching the query, if requested.
+    pub total: Option<i64>,
+    /// Cursor for the next page, if the page is full and the sort supports
+    /// cursors.
+    pub next: Option<Vec<Value>>,
+}
+
+/// Queryable auth event fields.
+pub const FIELDS: &[Field] = &[
+    Field::new("id", FieldType::Int),
+    Field::new("created", FieldType::Float),
+    Field::new(
+        "kind",
+        FieldType::Enum(&[
+            "login",
+            "logout",
+            "access",
+            "server",
+            "impersonation",
+        ]),
+    ),
+    Field::new("user_id", FieldType::Int).nullable(),
+    Field::new("domain", FieldType::Str).nullable(),
+    Field::new("rpc", FieldType::Str).nullable(),
+    Field::new("ip", FieldType::Str).nullable(),
+    Field::new("user_agent", FieldType::Str).nullable(),
+    Field::new("outcome", FieldType::Enum(&["success", "failure"])),
+    Field::new("reason", FieldType::Str).nullable(),
+];
+
+impl QueryCollection for AuthEventTable {
+    type Table = schema::auth_event::table;
+    type Query = schema::auth_event::BoxedQuery<'static, Pg>;
+
+    const FIELDS: &'static [Field] = FIELDS;
+
+    fn query() -> Self::Query {
+        schema::auth_event::table.into_boxed()
+    }
+
+    fn cond(field: &str, op: &Op) -> BoxedCond<Self::Table> {
+        match field {
+            "id" => col_cond!(schema::auth_event::id, op, as_int),
+            "created" => col_cond!(schema::auth_event::created, op, as_float),
+            "kind" => col_cond!(schema::auth_event::kind, op, as_str),
+            "user_id" => col_cond!(schema::auth_event::user_id, op, as_int),
+            "domain" => {
+                col_cond!(schema::auth_event::domain, op, as_str, text)
+            }
+            "rpc" => col_cond!(schema::auth_event::rpc, op, as_str, text),
+            "ip" => col_cond!(schema::auth_event::ip, op, as_str, text),
+            "user_agent" => {
+                col_cond!(schema::auth_event::user_agent, op, as_str, text)
+            }
+            "outcome" => col_cond!(schema::auth_event::outcome, op, as_str),
+            "reason" => {
+                col_cond!(schema::auth_event::reason, op, as_str, text)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn order(sort: &Sort) -> BoxedOrder<Self::Table> {
+        match sort.field {
+            "id" => col_order!(schema::auth_event::id, sort),
+            "created" => col_order!(schema::auth_event::created, sort),
+            "kind" => col_order!(schema::auth_event::kind, sort),
+            "user_id" => col_order!(schema::auth_event::user_id, sort),
+            "domain" => col_order!(schema::auth_event::domain, sort),
+            "rpc" => col_order!(schema::auth_event::rpc, sort),
+            "ip" => col_order!(schema::auth_event::ip, sort),
+            "user_agent" => col_order!(schema::auth_event::user_agent, sort),
+            "outcome" => col_order!(schema::auth_event::outcome, sort),
+            "reason" => col_order!(schema::auth_event::reason, sort),
+            _ => unreachable!(),
+        }
+    }
+
+    fn load(q: Self::Query, con: &mut Con) -> Res<Vec<Value>> {
+        Ok(q.select(AuthEventTable::as_select())
+            .get_results(con)?
+            .iter()
+            .map(|x| serde_json::to_value(x.to_msg()).unwrap())
+            .collect())
+    }
+
+    fn load_fields(
+        q: Self::Query,
+        fields: &[&Field],
+        con: &mut Con,
+    ) -> Res<Vec<Value>> {
+        Ok(q.select(quco::select_fields(fields)).get_results(con)?)
+    }
+
+    fn count(q: Self::Query, con: &mut Con) -> Res<i64> {
+        Ok(q.count().get_result(con)?)
+    }
+}
+
+/// Fetches events matching the search query.
+///
+/// See `quco::find` for the search semantics and `FIELDS` for the queryable
+/// fields.
+pub fn get_many(
+    inp: &GetAuthEvents,
+    con: &mut Con,
+) -> Res<GetAuthEventsRes<Value>> {
+    let found = quco::find::<AuthEventTable>(inp, con)?;
+    Ok(GetAuthEventsRes {
+        events: found.items,
+        total: found.total,
+        next: found.next,
+    })
+}
+
+/// Serializes events matching the search query as JSON Lines.
+///
+/// At most `audit.export_limit` events are exported at once, larger exports
+/// are rejected, so they should be split by the query or by pages.
+pub fn export(inp: &GetAuthEvents, con: &mut Con) -> Res<String> {
+    let max = APPRC.audit.export_limit;
+    let too_large = || {
+        err::res(
+            "export_too_large",
+            &format!("at most {} events can be exported at once", max),
+        )
+    };
+    if inp.limit.is_some_and(|x| x > max) {
+        return too_large();
+    }
+    let mut search = inp.clone();
+    // one more event tells whether the export would be cut
+    search.limit = Some(inp.limit.unwrap_or(max + 1));
+    let found = quco::find_up_to::<AuthEventTable>(&search, max + 1, con)?;
+    if found.items.len() as i64 > max {
+        return too_large();
+    }
+    Ok(found.items.iter().map(|x| x.to_string() + "\n").collect())
+}
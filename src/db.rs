@@ -1,4 +1,10 @@
-use crate::{ryz::res::Res, APPRC};
+use crate::{
+    ryz::{
+        err::{self, Error},
+        res::Res,
+    },
+    APPRC,
+};
 use diesel::{connection::SimpleConnection, Connection, PgConnection};
 
 pub type Con = PgConnection;
@@ -7,10 +13,18 @@ pub type Id = i32;
 #[allow(dead_code)]
 pub type Sid = String;
 
+impl From<diesel::result::Error> for Error {
+    fn from(e: diesel::result::Error) -> Self {
+        Error::new("db_err", e.to_string().as_str())
+    }
+}
+
 pub fn con() -> Res<PgConnection> {
     let cfg = &APPRC.sql;
-    Ok(PgConnection::establish(&cfg.url)
-        .unwrap_or_else(|_| panic!("cannot connect to db")))
+    match PgConnection::establish(&cfg.url) {
+        Ok(con) => Ok(con),
+        Err(_) => err::res_msg("cannot connect to db"),
+    }
 }
 
 pub fn truncate_tables_if_allowed() {
@@ -20,7 +34,11 @@ pub fn truncate_tables_if_allowed() {
     let con = &mut con().unwrap();
     con.batch_execute(
         "
-        TRUNCATE user_change, appuser RESTART IDENTITY;
+        TRUNCATE auth_event, membership, organisation, user_role,
+            role_permission, role, upstream_login, identity, oauth_code,
+            oauth_client, webauthn_challenge, webauthn_credential,
+            recovery_code, mfa_challenge, mfa, mail, verification, webhook_delivery,
+            webhook, user_change, appuser RESTART IDENTITY;
     ",
     )
     .unwrap();
@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{Con, Id},
+    password::{detect_hash, get_violations, hash_password},
+    quco::Collection,
+    ryz::{res::Res, time::utc},
+    schema,
+    user::{Metadata, UserTable},
+    user_change::{self, ChangeAction, NewUserChange},
+    username, InsertReg,
+};
+
+/// How many users are inserted by one statement.
+const BATCH: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum ImportFormat {
+    /// One JSON object per line.
+    Jsonl,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// Imported user, given either with plain `password`, which must satisfy
+/// the password policy, or with `hpassword` already hashed by one of the
+/// supported algorithms, see `password::detect_hash`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportUser {
+    pub username: String,
+    pub password: Option<String>,
+    pub hpassword: Option<String>,
+    pub firstname: Option<String>,
+    pub patronym: Option<String>,
+    pub surname: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportUsers {
+    pub format: ImportFormat,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ImportRowErr {
+    /// Line of the row in the source, starting from 1.
+    pub row: usize,
+    pub code: String,
+    pub msg: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportUsersRes {
+    /// Ids of the imported users, in order of the rows.
+    pub ids: Vec<Id>,
+    /// Errors of invalid rows. If there are any, nothing is imported.
+    pub errs: Vec<ImportRowErr>,
+}
+
+fn row_err(row: usize, code: &str, msg: String) -> ImportRowErr {
+    ImportRowErr {
+        row,
+        code: code.to_string(),
+        msg,
+    }
+}
+
+/// Parses rows, skipping blank lines of JSON Lines.
+fn parse(inp: &ImportUsers) -> (Vec<(usize, ImportUser)>, Vec<ImportRowErr>) {
+    let mut rows = vec![];
+    let mut errs = vec![];
+    match inp.format {
+        ImportFormat::Jsonl => {
+            for (i, line) in inp.data.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<ImportUser>(line) {
+                    Ok(user) => rows.push((i + 1, user)),
+                    Err(e) => {
+                        errs.push(row_err(i + 1, "parse_err", e.to_string()))
+                    }
+                }
+            }
+        }
+        ImportFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(inp.data.as_bytes());
+            let headers = match reader.headers() {
+                Ok(headers) => headers.clone(),
+                Err(e) => {
+                    errs.push(row_err(1, "parse_err", e.to_string()));
+                    return (rows, errs);
+                }
+            };
+            for record in reader.records() {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        let row = e.position().map_or(0, |x| x.line());
+                        errs.push(row_err(
+                            row as usize,
+                            "parse_err",
+                            e.to_string(),
+                        ));
+                        continue;
+                    }
+                };
+                let row = record.position().map_or(0, |x| x.line()) as usize;
+                match record.deserialize::<ImportUser>(Some(&headers)) {
+                    Ok(user) => rows.push((row, user)),
+                    Err(e) => {
+                        errs.push(row_err(row, "parse_err", e.to_string()))
+                    }
+                }
+            }
+        }
+    }
+    (rows, errs)
+}
+
+fn validate(
+    rows: &[(usize, ImportUser)],
+    con: &mut Con,
+) -> Res<Vec<ImportRowErr>> {
+    let nusernames: Vec<String> = rows
+        .iter()
+        .map(|(_, x)| username::fold(&x.username))
+        .collect();
+    let taken: HashSet<String> = schema::appuser::table
+        .filter(schema::appuser::nusername.eq_any(nusernames))
+        .filter(schema::appuser::username.not_like("archived::%"))
+        .select(schema::appuser::nusername)
+        .load::<String>(con)?
+        .into_iter()
+        .collect();
+
+    let mut errs = vec![];
+    let mut seen = HashSet::new();
+    for (row, user) in rows {
+        let row = *row;
+        let nusername = username::fold(&user.username);
+        if let Some(msg) = username::get_violation(&user.username) {
+            errs.push(row_err(row, "invalid_username", msg));
+        } else if taken.contains(&nusername) {
+            errs.push(row_err(
+                row,
+                "username_taken",
+                format!("username {} is taken", user.username),
+            ));
+        } else if !seen.insert(nusername) {
+            errs.push(row_err(
+                row,
+                "username_taken",
+                format!("username {} is duplicated", user.username),
+            ));
+        }
+        match (&user.password, &user.hpassword) {
+            (Some(password), None) => {
+                let violations = get_violations(&user.username, password);
+                if !violations.is_empty() {
+                    let msgs: Vec<String> = violations
+                        .iter()
+                        .map(|x| format!("{}: {}", x.rule, x.msg))
+                        .collect();
+                    errs.push(row_err(
+                        row,
+                        "password_policy",
+                        msgs.join("; "),
+                    ));
+                }
+            }
+            (None, Some(hpassword)) => {
+                if detect_hash(hpassword).is_none() {
+                    errs.push(row_err(
+                        row,
+                        "invalid_hpassword",
+                        "unsupported password hash".to_string(),
+                    ));
+                }
+            }
+            _ => errs.push(row_err(
+                row,
+                "invalid_password",
+                "exactly one of password and hpassword is required"
+                    .to_string(),
+            )),
+        }
+    }
+    Ok(errs)
+}
+
+/// Imports users in bulk.
+///
+/// All rows are validated upfront, and if any of them is invalid nothing is
+/// imported and the errors are returned. Otherwise users are inserted in
+/// batches within one transaction, along with their changes.
+///
+/// Plain passwords are hashed here, which takes a while per row, so this
+/// should be run via `spawn_blocking`.
+pub fn import(inp: &ImportUsers, con: &mut Con) -> Res<ImportUsersRes> {
+    let (rows, mut errs) = parse(inp);
+    errs.extend(validate(&rows, con)?);
+    if !errs.is_empty() {
+        errs.sort_by_key(|x| x.row);
+        return Ok(ImportUsersRes { ids: vec![], errs });
+    }
+
+    let now = utc();
+    let regs: Vec<InsertReg> = rows
+        .into_iter()
+        .map(|(_, x)| {
+            Ok(InsertReg {
+                hpassword: match (x.hpassword, x.password) {
+                    (Some(hpassword), _) => hpassword,
+                    (None, Some(password)) => hash_password(&password)?,
+                    (None, None) => unreachable!("rows are validated"),
+                },
+                username: username::normalize(&x.username),
+                nusername: username::fold(&x.username),
+                firstname: x.firstname,
+                patronym: x.patronym,
+                surname: x.surname,
+                email: None,
+                phone: None,
+                metadata: serde_json::to_value(Metadata::default()).unwrap(),
+                created: now,
+                updated: now,
+            })
+        })
+        .collect::<Res<_>>()?;
+
+    con.transaction(|con| {
+        let mut ids = vec![];
+        for batch in regs.chunks(BATCH) {
+            let users: Vec<UserTable> =
+                diesel::insert_into(schema::appuser::table)
+                    .values(batch)
+                    .returning(UserTable::as_returning())
+                    .get_results(con)?;
+            let changes: Vec<NewUserChange> = users
+                .iter()
+                .map(|x| NewUserChange {
+                    user_id: x.id,
+                    action: ChangeAction::New,
+                    data: Some(serde_json::to_value(x.to_msg()).unwrap()),
+                })
+                .collect();
+            user_change::new_many(&changes, con)?;
+            ids.extend(users.iter().map(|x| x.id));
+        }
+        Ok(ImportUsersRes { ids, errs: vec![] })
+    })
+}
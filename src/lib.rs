@@ -1,39 +1,78 @@
-use std::{env::var, fs::File, io::Read};
+use std::{env::var, fs::File, io::Read, net::IpAddr};
 
+use auth_event::{
+    AuthEventKind, AuthOutcome, GetAuthEvents, GetAuthEventsRes, NewAuthEvent,
+    Origin,
+};
 use axum::{
+    body::{to_bytes, Body},
     extract::Request,
-    http::{HeaderMap, Method, StatusCode},
+    http::{header, HeaderMap, Method, StatusCode},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::post,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
     Json, Router,
 };
-use diesel::prelude::Insertable;
-use password::check_password;
+use diesel::{prelude::Insertable, Connection};
+use import::{ImportUsers, ImportUsersRes};
+use log::warn;
+use mfa::{MfaStatus, TotpEnrollment};
+use oidc::{NewOauthClient, OauthClient};
+use org::{DelMember, Membership, NewOrg, Org, SetMember};
+use password::{check_password, check_policy, hash_password, Check};
 use quco::Query;
+use role::{AssignRole, Role, SetRole, UserRole};
 use ryz::{
     dict::dict,
+    enm::StrEnum,
     err::{self, Error},
     path,
     res::Res,
-    time::Time,
+    time::{utc, Time},
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use token::{new_at, verify_rt};
+use serde_json::{json, Map, Value};
+use token::{new_at, verify_rt, Actor, UserTokenPayload};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tower_http::catch_panic::CatchPanicLayer;
 use tower_http::cors::{Any, CorsLayer};
-use user::{get_by_id, get_by_rt, GetUsers, User};
-use user_change::UserChange;
+use upstream::{Identity, UpstreamRedirect};
+use user::{get_by_rt, GetUsers, GetUsersRes, Metadata, UpdateMetadata, User};
+use user_change::{
+    ChangeAction, GetUserChanges, GetUserChangesRes, NewUserChange,
+};
+use verification::{IdentifierKind, NewVerification, Verification, Verify};
+use webauthn::{
+    AssertionCredential, CeremonyOptions, Passkey, RegistrationCredential,
+};
+use webhook::{
+    GetWebhookDeliveries, NewWebhook, Webhook, WebhookDelivery, WebhookEvent,
+};
 
+pub mod auth_event;
 pub mod db;
-mod password;
+pub mod import;
+pub mod mailer;
+pub mod mfa;
+pub mod oidc;
+pub mod org;
+pub mod password;
 pub mod quco;
+pub mod role;
 pub mod ryz;
 mod schema;
 pub mod token;
+pub mod upstream;
 pub mod user;
 pub mod user_change;
+pub mod username;
+pub mod verification;
+pub mod webauthn;
+pub mod webhook;
 
 lazy_static::lazy_static! {
     static ref APPRC: Apprc = get_apprc();
@@ -51,16 +90,49 @@ fn get_apprc() -> Apprc {
 }
 
 pub fn get_mode() -> String {
-    return match var("CORUND_MODE") {
+    match var("CORUND_MODE") {
         Err(_) => "prod".to_string(),
         Ok(mode) => mode,
-    };
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct Apprc {
     sql: SqlCfg,
     domain: DomainCfg,
+    token: TokenCfg,
+    #[serde(default)]
+    webhook: WebhookCfg,
+    #[serde(default)]
+    password: PasswordCfg,
+    #[serde(default)]
+    password_policy: PasswordPolicyCfg,
+    #[serde(default)]
+    username: UsernameCfg,
+    #[serde(default)]
+    verification: VerificationCfg,
+    /// Without a mailer, verification codes are delivered by the domain.
+    #[serde(default)]
+    mailer: Option<MailerCfg>,
+    #[serde(default)]
+    mfa: MfaCfg,
+    /// Without it, passkeys cannot be used.
+    #[serde(default)]
+    webauthn: Option<WebauthnCfg>,
+    /// Without it, corund doesn't act as an OpenID Connect provider.
+    #[serde(default)]
+    oidc: Option<OidcCfg>,
+    /// External OpenID Connect providers users can sign in with, by name.
+    #[serde(default)]
+    upstream: dict<String, UpstreamCfg>,
+    #[serde(default)]
+    metadata: MetadataCfg,
+    #[serde(default)]
+    impersonation: ImpersonationCfg,
+    #[serde(default)]
+    audit: AuditCfg,
+    #[serde(default)]
+    query: QueryCfg,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,23 +140,556 @@ struct DomainCfg {
     secret: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct TokenCfg {
+    /// Secret the signing keys of service, MFA challenge and OAuth access
+    /// tokens are derived from, each kind with it's own key.
+    secret: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct SqlCfg {
     url: String,
     is_cleaning_allowed: bool,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct WebhookCfg {
+    /// After how many failed attempts a delivery becomes dead.
+    max_attempts: i32,
+    /// Delay before the first retry, doubled on each next one.
+    backoff: Time,
+    /// Seconds to wait for a receiver, the attempt fails after it.
+    timeout: Time,
+    /// Hosts which may receive webhooks even though they are, or resolve
+    /// to, private or loopback addresses.
+    allow_hosts: Vec<String>,
+}
+
+impl Default for WebhookCfg {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            backoff: 10.0,
+            timeout: 10.0,
+            allow_hosts: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct PasswordCfg {
+    /// Argon2 variant: `argon2id`, `argon2i` or `argon2d`.
+    algorithm: String,
+    /// Memory cost in KiB.
+    m_cost: u32,
+    /// Amount of iterations.
+    t_cost: u32,
+    /// Degree of parallelism.
+    p_cost: u32,
+    /// Server-side secret mixed into every argon2 hash. Hashes made without
+    /// it are upgraded on the next login.
+    pepper: Option<String>,
+}
+
+impl Default for PasswordCfg {
+    fn default() -> Self {
+        Self {
+            algorithm: "argon2id".to_string(),
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+            pepper: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct PasswordPolicyCfg {
+    min_len: usize,
+    max_len: usize,
+    require_lower: bool,
+    require_upper: bool,
+    require_digit: bool,
+    require_symbol: bool,
+    /// Whether passwords containing the username are rejected.
+    forbid_username: bool,
+    /// Minimal zxcvbn strength score, from 0 to 4.
+    min_score: u8,
+    /// File with one known breached password per line.
+    breached_file: Option<String>,
+}
+
+impl Default for PasswordPolicyCfg {
+    fn default() -> Self {
+        Self {
+            min_len: 8,
+            max_len: 128,
+            require_lower: false,
+            require_upper: false,
+            require_digit: false,
+            require_symbol: false,
+            forbid_username: true,
+            min_score: 0,
+            breached_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct UsernameCfg {
+    min_len: usize,
+    max_len: usize,
+    /// Characters allowed besides letters and digits.
+    symbols: String,
+    /// Usernames which cannot be registered, compared case-insensitively.
+    reserved: Vec<String>,
+}
+
+impl Default for UsernameCfg {
+    fn default() -> Self {
+        Self {
+            min_len: 3,
+            max_len: 64,
+            symbols: "._-".to_string(),
+            reserved: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct VerificationCfg {
+    /// For how long verification codes are valid.
+    ttl: Time,
+    /// How many wrong codes can be entered for one verification.
+    max_attempts: i32,
+    /// Seconds before another code can be requested for the same
+    /// identifier of a user.
+    resend_interval: Time,
+}
+
+impl Default for VerificationCfg {
+    fn default() -> Self {
+        Self {
+            ttl: 900.0,
+            max_attempts: 5,
+            resend_interval: 60.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MailSinkKind {
+    Smtp,
+    File,
+    Stdout,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct MailerCfg {
+    sink: MailSinkKind,
+    /// Sender address, e.g. `Corund <noreply@example.com>`.
+    from: String,
+    /// Locale of templates used when the requested one is missing.
+    locale: String,
+    /// Directory with templates, relative to the working directory.
+    templates: String,
+    /// File which mails are appended to, for the `file` sink.
+    file: String,
+    smtp: Option<SmtpCfg>,
+    /// After how many failed attempts a mail becomes dead.
+    max_attempts: i32,
+    /// Delay before the first retry, doubled on each next one.
+    backoff: Time,
+    /// Seconds to wait for the SMTP server, the attempt fails after it.
+    timeout: Time,
+}
+
+impl Default for MailerCfg {
+    fn default() -> Self {
+        Self {
+            sink: MailSinkKind::Stdout,
+            from: "corund@localhost".to_string(),
+            locale: "en".to_string(),
+            templates: "mail".to_string(),
+            file: "mail.jsonl".to_string(),
+            smtp: None,
+            max_attempts: 8,
+            backoff: 10.0,
+            timeout: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct SmtpCfg {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    /// Whether STARTTLS is required.
+    tls: bool,
+}
+
+impl Default for SmtpCfg {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 587,
+            username: None,
+            password: None,
+            tls: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct MfaCfg {
+    /// Name shown by authenticator apps.
+    issuer: String,
+    digits: u32,
+    /// Seconds per TOTP code.
+    period: u64,
+    /// How many steps before and after the current one are accepted.
+    skew: i64,
+    /// For how long the challenge issued on login is valid.
+    challenge_ttl: Time,
+    /// How many wrong codes can be entered per attempts window.
+    max_attempts: i32,
+    /// Seconds since the first wrong code in which `max_attempts` apply.
+    attempts_window: Time,
+    recovery_codes: usize,
+}
+
+impl Default for MfaCfg {
+    fn default() -> Self {
+        Self {
+            issuer: "corund".to_string(),
+            digits: 6,
+            period: 30,
+            skew: 1,
+            challenge_ttl: 300.0,
+            max_attempts: 5,
+            attempts_window: 900.0,
+            recovery_codes: 10,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebauthnCfg {
+    /// Relying party id, the domain passkeys are bound to.
+    rp_id: String,
+    /// Name shown by authenticators.
+    #[serde(default = "default_rp_name")]
+    rp_name: String,
+    /// Origin of the pages calling the WebAuthn API, e.g.
+    /// `https://example.com`.
+    origin: String,
+    /// For how long a ceremony can be finished.
+    #[serde(default = "default_ceremony_ttl")]
+    challenge_ttl: Time,
+}
+
+fn default_rp_name() -> String {
+    "corund".to_string()
+}
+
+fn default_ceremony_ttl() -> Time {
+    300.0
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct OidcCfg {
+    /// Public URL of corund, e.g. `https://auth.example.com`.
+    issuer: String,
+    /// For how long authorization codes can be exchanged.
+    code_ttl: Time,
+    /// For how long access and ID tokens are valid.
+    at_ttl: Time,
+    /// For how long service tokens of the client credentials grant are
+    /// valid.
+    service_at_ttl: Time,
+    /// Whether clients must use PKCE.
+    require_pkce: bool,
+    /// PKCS#8 PEM file with the P-256 key ID tokens are signed with by
+    /// ES256, published at `/oauth/jwks`. Without it, codes cannot be
+    /// exchanged.
+    signing_key_file: Option<String>,
+}
+
+impl Default for OidcCfg {
+    fn default() -> Self {
+        Self {
+            issuer: "http://localhost:9014".to_string(),
+            code_ttl: 60.0,
+            at_ttl: 3600.0,
+            service_at_ttl: 300.0,
+            require_pkce: true,
+            signing_key_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamCfg {
+    /// Expected `iss` of ID tokens.
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    client_id: String,
+    client_secret: String,
+    /// Page of the client app which receives the code, as registered at the
+    /// provider.
+    redirect_uri: String,
+    #[serde(default = "default_upstream_scope")]
+    scope: String,
+    /// Whether users are created on the first login, otherwise the identity
+    /// must be linked to an existing user first.
+    #[serde(default = "default_auto_provision")]
+    auto_provision: bool,
+    /// For how long the login can be finished.
+    #[serde(default = "default_ceremony_ttl")]
+    login_ttl: Time,
+}
+
+fn default_upstream_scope() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_auto_provision() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct MetadataCfg {
+    /// Metadata keys included into access tokens, prefixed by the
+    /// namespace, e.g. `domain.plan` or `user.locale`.
+    claims: Vec<String>,
+    /// Max length of an user's metadata serialized as JSON.
+    max_size: usize,
+}
+
+impl Default for MetadataCfg {
+    fn default() -> Self {
+        Self {
+            claims: vec![],
+            max_size: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ImpersonationCfg {
+    /// For how long impersonation tokens are valid.
+    ttl: Time,
+}
+
+impl Default for ImpersonationCfg {
+    fn default() -> Self {
+        Self { ttl: 300.0 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct QueryCfg {
+    /// Largest page size, larger limits are lowered to it.
+    max_limit: i64,
+}
+
+impl Default for QueryCfg {
+    fn default() -> Self {
+        Self { max_limit: 1000 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct AuditCfg {
+    /// Proxies whose `X-Forwarded-For` and `X-Real-IP` headers are trusted,
+    /// other peers are recorded by their connection address.
+    trusted_proxies: Vec<IpAddr>,
+    /// Most events returned by a single export.
+    export_limit: i64,
+}
+
+impl Default for AuditCfg {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: vec![],
+            export_limit: 10000,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct Login {
+    /// Username, verified email or verified phone.
     username: String,
     password: String,
 }
 
+#[derive(Deserialize)]
+struct LoginMfa {
+    /// Challenge returned with the `mfa_required` error of `/rpc/login`.
+    challenge: String,
+    /// TOTP or recovery code.
+    code: String,
+}
+
+/// Authenticates TOTP enrollment either by a refresh token, or by a login
+/// challenge if MFA is required for a user who hasn't enrolled yet.
+#[derive(Deserialize)]
+struct MfaAuth {
+    rt: Option<String>,
+    challenge: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ConfirmTotp {
+    #[serde(flatten)]
+    auth: MfaAuth,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct BeginUpstreamLogin {
+    /// Name of the provider in `upstream` config.
+    provider: String,
+}
+
+/// Query parameters the provider redirected the user back with.
+#[derive(Deserialize)]
+struct FinishUpstreamLogin {
+    state: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct LinkUpstream {
+    rt: String,
+    #[serde(flatten)]
+    finish: FinishUpstreamLogin,
+}
+
+#[derive(Deserialize)]
+struct FinishRegistration {
+    #[serde(flatten)]
+    auth: MfaAuth,
+    /// Id of the ceremony from `/rpc/webauthn/begin_registration`.
+    id: db::Id,
+    credential: RegistrationCredential,
+    /// Label to tell passkeys apart.
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BeginWebauthnLogin {
+    /// Username, verified email or verified phone. Without it, any
+    /// discoverable passkey can be used.
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebauthnLogin {
+    /// Id of the ceremony from `/rpc/webauthn/begin_login`.
+    id: db::Id,
+    credential: AssertionCredential,
+    /// Login challenge, required if the authenticator didn't verify the
+    /// user, so the passkey is only a second factor.
+    challenge: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DelPasskey {
+    rt: String,
+    id: db::Id,
+}
+
+#[derive(Deserialize)]
+struct UserIdData {
+    user_id: db::Id,
+}
+
+#[derive(Deserialize)]
+struct RequireMfa {
+    user_id: db::Id,
+    required: bool,
+}
+
+#[derive(Deserialize)]
+struct ChangePassword {
+    rt: String,
+    password: String,
+    new_password: String,
+}
+
 #[derive(Deserialize)]
 struct RtData {
     rt: String,
 }
 
+#[derive(Deserialize)]
+struct Access {
+    rt: String,
+    /// Domain to include the roles of.
+    #[serde(default)]
+    domain: Option<String>,
+    /// Organisation to act in, the user must be it's member.
+    #[serde(default)]
+    org_id: Option<db::Id>,
+}
+
+#[derive(Deserialize)]
+struct Impersonate {
+    user_id: db::Id,
+    /// Identifier of the impersonator, set as `act.sub` claim.
+    actor: String,
+    #[serde(default)]
+    reason: Option<String>,
+    /// Domain to include the roles of, as for `/rpc/access`.
+    #[serde(default)]
+    domain: Option<String>,
+    /// Organisation to act in, the user must be it's member.
+    #[serde(default)]
+    org_id: Option<db::Id>,
+}
+
+#[derive(Deserialize)]
+struct UpdateUserMetadata {
+    rt: String,
+    /// Keys to set in the `user` namespace, see `UpdateMetadata`.
+    user: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct OrgIdData {
+    org_id: db::Id,
+}
+
+#[derive(Deserialize)]
+struct NameData {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct IdData {
+    id: db::Id,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Reg {
     pub username: String,
@@ -92,21 +697,31 @@ pub struct Reg {
     pub firstname: Option<String>,
     pub patronym: Option<String>,
     pub surname: Option<String>,
+    /// Unverified until `/rpc/verify` is completed for it.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Unverified until `/rpc/verify` is completed for it.
+    #[serde(default)]
+    pub phone: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
 }
 
 #[derive(Insertable)]
 #[diesel(table_name=schema::appuser)]
 pub struct InsertReg {
     pub username: String,
+    /// See `username::fold`.
+    pub nusername: String,
     pub hpassword: String,
     pub firstname: Option<String>,
     pub patronym: Option<String>,
     pub surname: Option<String>,
-}
-
-#[derive(Deserialize)]
-struct GetChanges {
-    from: Time,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub metadata: Value,
+    pub created: Time,
+    pub updated: Time,
 }
 
 impl IntoResponse for Error {
@@ -116,16 +731,16 @@ impl IntoResponse for Error {
 }
 
 async fn rpc_reg(headers: HeaderMap, Json(reg): Json<Reg>) -> Res<Json<User>> {
-    verify_domain_secret_from_headers(headers)?;
+    verify_server_from_headers(headers, "users")?;
     let con = &mut db::con().unwrap();
-    let user = user::new(&reg, con).unwrap();
+    let user = user::new(&reg, con)?;
     Ok(Json(user))
 }
 
-async fn rpc_dereg(headers: HeaderMap, Json(query): Json<Query>) {
-    verify_domain_secret_from_headers(headers).unwrap();
+async fn rpc_dereg(headers: HeaderMap, Json(query): Json<Query>) -> Res<()> {
+    verify_server_from_headers(headers, "users")?;
     let con = &mut db::con().unwrap();
-    user::del(&query, con).unwrap();
+    user::del(&query, con)
 }
 
 /// Logins an user into the system.
@@ -133,27 +748,243 @@ async fn rpc_dereg(headers: HeaderMap, Json(query): Json<Query>) {
 /// All other login sessions are discarded (only 1 refresh token is possible
 /// by default).
 ///
+/// Outdated password hashes are replaced on successful login, see
+/// `password::check_password`.
+///
+/// If the user has TOTP enabled, or MFA is required for them, the
+/// `mfa_required` error is returned instead, with a challenge for
+/// `/rpc/login/mfa` and whether the user must enroll first.
+///
+/// Each attempt is written as a `Login` auth event, `mfa_required` as a
+/// failure.
+///
 /// Returns refresh token.
-async fn rpc_login(Json(login): Json<Login>) -> Res<String> {
+async fn rpc_login(origin: Origin, Json(login): Json<Login>) -> Res<String> {
     let con = &mut db::con().unwrap();
-    let Ok((user, hpassword)) = user::get_by_username(&login.username, con)
+    // failed attempts of existing users are attributed to them
+    let user_id = user::get_by_login(&login.username, con)
+        .ok()
+        .map(|x| x.0.id);
+    let res = check_login(&login, con)
+        .and_then(|user| new_session_or_mfa(user.id, con));
+    auth_event::record(
+        AuthEventKind::Login,
+        user_id,
+        None,
+        &origin,
+        &res,
+        con,
+    )?;
+    res
+}
+
+/// Starts a session of the user authenticated by the first factor, unless
+/// the second one is needed.
+fn new_session_or_mfa(user_id: db::Id, con: &mut db::Con) -> Res<String> {
+    let status = mfa::get_status(user_id, con)?;
+    if status.enabled || status.required {
+        return err::res_data(
+            "mfa_required",
+            "second factor is required",
+            json!({
+                "challenge": mfa::new_challenge(user_id, con)?,
+                "enroll": !status.enabled,
+                "totp": status.totp,
+                "webauthn": status.passkeys > 0
+            }),
+        );
+    }
+    new_session(user_id, con)
+}
+
+/// Checks the password of the user, rehashing it if it's outdated.
+fn check_login(login: &Login, con: &mut db::Con) -> Res<User> {
+    let Ok((user, hpassword)) = user::get_by_login(&login.username, con)
     else {
         return err::res_msg(
             format!("invalid username {}", login.username.to_owned()).as_str(),
         );
     };
-    if !check_password(&login.password, &hpassword) {
+    let check = check_password(&login.password, &hpassword);
+    if check == Check::Mismatch {
         return err::res_msg("incorrect password");
     }
-    let rt = token::new_rt(user.id).unwrap();
-    let con = &mut db::con().unwrap();
-    user::set_rt_for_username(&login.username, &rt, con).unwrap();
+    if check == Check::Outdated {
+        user::set_hpassword(user.id, &hash_password(&login.password)?, con)?;
+    }
+    Ok(user)
+}
+
+/// Issues a new refresh token, discarding the previous one.
+fn new_session(user_id: db::Id, con: &mut db::Con) -> Res<String> {
+    let rt = token::new_rt(user_id).unwrap();
+    con.transaction(|con| {
+        user::set_rt(user_id, &rt, con)?;
+        webhook::emit(WebhookEvent::Login, user_id, None, con)
+    })?;
     Ok(rt)
 }
 
-async fn rpc_logout(Json(rtdata): Json<RtData>) {
+/// Completes login with a TOTP or recovery code.
+///
+/// Each attempt is written as a `Login` auth event.
+///
+/// Returns refresh token.
+async fn rpc_login_mfa(
+    origin: Origin,
+    Json(inp): Json<LoginMfa>,
+) -> Res<String> {
+    let con = &mut db::con().unwrap();
+    let challenge_user_id = mfa::verify_challenge(&inp.challenge, con);
+    let user_id = challenge_user_id.as_ref().ok().copied();
+    let res = challenge_user_id.and_then(|user_id| {
+        mfa::check(user_id, &inp.code, con)?;
+        mfa::consume_challenge(&inp.challenge, con)?;
+        new_session(user_id, con)
+    });
+    auth_event::record(
+        AuthEventKind::Login,
+        user_id,
+        None,
+        &origin,
+        &res,
+        con,
+    )?;
+    res
+}
+
+fn get_mfa_user(auth: &MfaAuth, con: &mut db::Con) -> Res<User> {
+    match (&auth.rt, &auth.challenge) {
+        (Some(rt), None) => match get_by_rt(rt, con) {
+            Ok((user, _)) => Ok(user),
+            Err(_) => err::res_msg("no such refresh token for user"),
+        },
+        (None, Some(challenge)) => {
+            let user_id = mfa::verify_challenge(challenge, con)?;
+            // a challenge proves only the password, so it may enroll the
+            // first factor, but never add one next to an enabled factor
+            let status = mfa::get_status(user_id, con)?;
+            if !status.required || status.enabled {
+                return err::res(
+                    "mfa_enabled",
+                    "challenge can enroll only the first second factor",
+                );
+            }
+            user::get_by_id(user_id, con)
+        }
+        _ => err::res_msg("exactly one of rt and challenge is required"),
+    }
+}
+
+/// Starts TOTP enrollment of the current user.
+async fn rpc_new_totp(Json(inp): Json<MfaAuth>) -> Res<Json<TotpEnrollment>> {
+    let con = &mut db::con().unwrap();
+    let user = get_mfa_user(&inp, con)?;
+    Ok(Json(mfa::new_totp(user.id, &user.username, con)?))
+}
+
+/// Enables TOTP of the current user with the first code from the app.
+///
+/// Returns recovery codes, which are shown to the user once.
+async fn rpc_confirm_totp(
+    Json(inp): Json<ConfirmTotp>,
+) -> Res<Json<Vec<String>>> {
     let con = &mut db::con().unwrap();
-    user::del_rt(&rtdata.rt, con).unwrap();
+    let user = get_mfa_user(&inp.auth, con)?;
+    Ok(Json(mfa::confirm_totp(user.id, &inp.code, con)?))
+}
+
+/// Changes password of the current user.
+///
+/// The new password must satisfy the password policy, see
+/// `password::check_policy`.
+async fn rpc_change_password(Json(inp): Json<ChangePassword>) -> Res<()> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, hpassword)) = get_by_rt(&inp.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    if check_password(&inp.password, &hpassword) == Check::Mismatch {
+        return err::res_msg("incorrect password");
+    }
+    check_policy(&user.username, &inp.new_password)?;
+    let hpassword = hash_password(&inp.new_password)?;
+    con.transaction(|con| {
+        user::set_hpassword(user.id, &hpassword, con)?;
+        webhook::emit(WebhookEvent::PasswordChange, user.id, None, con)
+    })
+}
+
+/// Sets keys of the current user's own metadata, the `domain` namespace is
+/// writable only by domains.
+async fn rpc_update_user_metadata(
+    Json(inp): Json<UpdateUserMetadata>,
+) -> Res<Json<User>> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, _)) = get_by_rt(&inp.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    Ok(Json(user::update_metadata(
+        &UpdateMetadata {
+            user_id: user.id,
+            domain: None,
+            user: Some(inp.user),
+        },
+        con,
+    )?))
+}
+
+#[derive(Deserialize)]
+struct NewClientVerification {
+    rt: String,
+    kind: IdentifierKind,
+}
+
+/// Starts verification of the current user's email or phone.
+///
+/// Requires a sender, since the code cannot be given to the user directly.
+async fn rpc_new_verification(
+    Json(inp): Json<NewClientVerification>,
+) -> Res<Json<Verification>> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, _)) = get_by_rt(&inp.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    if !verification::has_sender() {
+        return err::res("no_sender", "codes can only be sent by domain");
+    }
+    let mut verification = verification::new(
+        &NewVerification {
+            user_id: user.id,
+            kind: inp.kind,
+        },
+        con,
+    )?;
+    verification.code = None;
+    Ok(Json(verification))
+}
+
+async fn rpc_verify(Json(inp): Json<Verify>) -> Res<()> {
+    let con = &mut db::con().unwrap();
+    verification::verify(&inp, con)
+}
+
+async fn rpc_logout(origin: Origin, Json(rtdata): Json<RtData>) -> Res<()> {
+    let con = &mut db::con().unwrap();
+    let user_id = get_by_rt(&rtdata.rt, con).ok().map(|x| x.0.id);
+    user::del_rt(&rtdata.rt, con)?;
+    // logging out with an unknown token still succeeds
+    let res = match user_id {
+        Some(_) => Ok(()),
+        None => err::res_msg("no such refresh token for user"),
+    };
+    auth_event::record(
+        AuthEventKind::Logout,
+        user_id,
+        None,
+        &origin,
+        &res,
+        con,
+    )
 }
 
 async fn rpc_current(Json(rtdata): Json<RtData>) -> Res<Json<User>> {
@@ -161,42 +992,603 @@ async fn rpc_current(Json(rtdata): Json<RtData>) -> Res<Json<User>> {
     Ok(Json(get_by_rt(&rtdata.rt, con)?.0))
 }
 
-async fn rpc_access(Json(rtdata): Json<RtData>) -> Res<String> {
-    let rt = rtdata.rt;
-    let claims = verify_rt(&rt).unwrap();
+/// Collects claims of an access token of the user: roles and permissions,
+/// global ones and the ones granted in the domain, the user's role in the
+/// organisation and metadata keys listed by `metadata.claims` config.
+fn new_access_payload(
+    user: &User,
+    domain: Option<String>,
+    org_id: Option<db::Id>,
+    con: &mut db::Con,
+) -> Res<UserTokenPayload> {
+    let grants = role::get_grants(user.id, domain.as_deref(), con)?;
+    let membership = match org_id {
+        Some(org_id) => match org::get_membership(org_id, user.id, con)? {
+            Some(membership) => Some(membership),
+            None => {
+                return err::res(
+                    "not_member",
+                    "user is not a member of the organisation",
+                )
+            }
+        },
+        None => None,
+    };
+    Ok(UserTokenPayload {
+        user_id: user.id,
+        domain,
+        roles: grants.roles,
+        permissions: grants.permissions,
+        org_id: membership.as_ref().map(|x| x.org_id),
+        org_role: membership.map(|x| x.role.to_str().to_string()),
+        metadata: user::get_metadata_claims(&user.metadata)
+            .map(|x| serde_json::to_value(x).unwrap()),
+        ..Default::default()
+    })
+}
+
+/// Issues an access token for the requested domain and organisation, see
+/// `new_access_payload`.
+///
+/// Each request is written as an `Access` auth event.
+async fn rpc_access(origin: Origin, Json(inp): Json<Access>) -> Res<String> {
     let con = &mut db::con().unwrap();
-    let user = get_by_id(claims.user_id, con).unwrap();
-    if user.rt != Some(rt) {
-        return err::res_msg("no such refresh token for user");
+    let user = match (verify_rt(&inp.rt), get_by_rt(&inp.rt, con)) {
+        (Ok(claims), Ok((user, _))) if user.id == claims.user_id => Some(user),
+        _ => None,
+    };
+    let res = match &user {
+        // we don't store access tokens since they intended to be short-lived
+        Some(user) => {
+            new_access_payload(user, inp.domain.to_owned(), inp.org_id, con)
+                .map(|x| new_at(x).unwrap())
+        }
+        None => err::res_msg("no such refresh token for user"),
+    };
+    auth_event::record(
+        AuthEventKind::Access,
+        user.map(|x| x.id),
+        inp.domain,
+        &origin,
+        &res,
+        con,
+    )?;
+    res
+}
+
+/// Issues an access token of the user to an impersonator, e.g. a support
+/// agent, to see what the user sees.
+///
+/// The token carries the `act` claim and expires after `impersonation.ttl`
+/// config, services may reject it, see `token::verify_at`. No refresh token
+/// is issued.
+///
+/// Each impersonation is written to the user's changes and as an
+/// `Impersonation` auth event with the calling service client, in the same
+/// transaction the token is issued in, so no token is issued unrecorded.
+async fn rpc_impersonate(
+    origin: Origin,
+    headers: HeaderMap,
+    Json(inp): Json<Impersonate>,
+) -> Res<String> {
+    let client_id = verify_server_from_headers(headers, "impersonate")?;
+    if inp.actor.is_empty() {
+        return err::res_msg("actor cannot be empty");
     }
-    // we don't store access tokens since they intended to be short-lived
-    Ok(new_at(claims.user_id).unwrap())
+    let con = &mut db::con()?;
+    con.transaction(|con| {
+        let user = user::get_by_id(inp.user_id, con)?;
+        let payload =
+            new_access_payload(&user, inp.domain.to_owned(), inp.org_id, con)?;
+        let at = new_at(UserTokenPayload {
+            exp: Some(utc() + APPRC.impersonation.ttl),
+            act: Some(Actor {
+                sub: inp.actor.to_owned(),
+            }),
+            ..payload
+        })?;
+        user_change::new(
+            &NewUserChange {
+                user_id: user.id,
+                action: ChangeAction::Impersonation,
+                data: Some(json!({
+                    "actor": inp.actor,
+                    "reason": inp.reason,
+                    "domain": inp.domain,
+                    "org_id": inp.org_id,
+                    "client_id": client_id,
+                })),
+            },
+            con,
+        )?;
+        auth_event::new(
+            &NewAuthEvent {
+                kind: AuthEventKind::Impersonation,
+                user_id: Some(user.id),
+                domain: client_id.to_owned(),
+                rpc: Some("impersonate".to_string()),
+                outcome: AuthOutcome::Success,
+                reason: None,
+            },
+            &origin,
+            con,
+        )?;
+        Ok(at)
+    })
 }
 
+/// Responds with the list of matching changes only, as it always did, see
+/// `rpc_search_user_changes` for the total count and the next page cursor.
 async fn rpc_get_user_changes(
     headers: HeaderMap,
-    Json(get_changes): Json<GetChanges>,
-) -> Res<Json<Vec<UserChange>>> {
+    Json(get_changes): Json<GetUserChanges>,
+) -> Res<Json<Vec<Value>>> {
+    verify_server_from_headers(headers, "users")?;
+    let con = &mut db::con()?;
+    Ok(Json(user_change::get_many(&get_changes, con)?.changes))
+}
+
+async fn rpc_search_user_changes(
+    headers: HeaderMap,
+    Json(get_changes): Json<GetUserChanges>,
+) -> Res<Json<GetUserChangesRes<Value>>> {
+    verify_server_from_headers(headers, "users")?;
+    let con = &mut db::con()?;
+    Ok(Json(user_change::get_many(&get_changes, con)?))
+}
+
+/// Streams user changes as Server-Sent Events.
+///
+/// Changes matching the input are replayed first, then new changes are
+/// pushed as they're written.
+async fn rpc_stream_user_changes(
+    headers: HeaderMap,
+    Json(get_changes): Json<GetUserChanges>,
+) -> Res<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    verify_server_from_headers(headers, "users")?;
+    // reject invalid queries before the stream is opened
+    quco::parse(&get_changes.search.sq, user_change::FIELDS)?;
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        if let Err(e) = user_change::stream(get_changes, tx).await {
+            warn!("user change stream failed: {:?}", e);
+        }
+    });
+    let stream = ReceiverStream::new(rx).map(|change| {
+        Event::default()
+            .id(change.id.to_string())
+            .event("user_change")
+            .json_data(change)
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn rpc_import_users(
+    headers: HeaderMap,
+    Json(inp): Json<ImportUsers>,
+) -> Res<Json<ImportUsersRes>> {
+    verify_server_from_headers(headers, "users")?;
+    // hashing of plain passwords would otherwise block the runtime
+    let imported = tokio::task::spawn_blocking(move || {
+        import::import(&inp, &mut db::con()?)
+    })
+    .await;
+    match imported {
+        Ok(x) => Ok(Json(x?)),
+        Err(_) => err::res_msg("import failed"),
+    }
+}
+
+/// Starts verification of an user's email or phone.
+///
+/// The code is returned to the domain for delivery, and is also passed to
+/// the sender, if it's set.
+async fn rpc_server_new_verification(
+    headers: HeaderMap,
+    Json(inp): Json<NewVerification>,
+) -> Res<Json<Verification>> {
+    verify_server_from_headers(headers, "users")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(verification::new(&inp, con)?))
+}
+
+/// Starts registration of a passkey for the current user.
+async fn rpc_begin_webauthn_registration(
+    Json(inp): Json<MfaAuth>,
+) -> Res<Json<CeremonyOptions>> {
+    let con = &mut db::con().unwrap();
+    let user = get_mfa_user(&inp, con)?;
+    Ok(Json(webauthn::begin_registration(&user, con)?))
+}
+
+async fn rpc_finish_webauthn_registration(
+    Json(inp): Json<FinishRegistration>,
+) -> Res<Json<Passkey>> {
+    let con = &mut db::con().unwrap();
+    let user = get_mfa_user(&inp.auth, con)?;
+    Ok(Json(webauthn::finish_registration(
+        user.id,
+        inp.id,
+        &inp.credential,
+        inp.name,
+        con,
+    )?))
+}
+
+async fn rpc_begin_webauthn_login(
+    Json(inp): Json<BeginWebauthnLogin>,
+) -> Res<Json<CeremonyOptions>> {
+    let con = &mut db::con().unwrap();
+    let user_id = match &inp.username {
+        Some(username) => match user::get_by_login(username, con) {
+            Ok((user, _)) => Some(user.id),
+            Err(_) => {
+                return err::res_msg(
+                    format!("invalid username {}", username).as_str(),
+                )
+            }
+        },
+        None => None,
+    };
+    Ok(Json(webauthn::begin_assertion(user_id, con)?))
+}
+
+/// Logins with a passkey.
+///
+/// Passkeys which verified the user are enough on their own, others only
+/// complete login started with password.
+///
+/// Returns refresh token.
+async fn rpc_webauthn_login(
+    origin: Origin,
+    Json(inp): Json<WebauthnLogin>,
+) -> Res<String> {
+    let con = &mut db::con()?;
+    let assertion = webauthn::finish_assertion(inp.id, &inp.credential, con);
+    let user_id = assertion.as_ref().ok().map(|x| x.0);
+    let res = assertion.and_then(|(user_id, user_verified)| {
+        if !user_verified {
+            let Some(challenge) = &inp.challenge else {
+                return err::res(
+                    "user_verification_required",
+                    "passkey without user verification is only a second \
+                    factor",
+                );
+            };
+            if mfa::verify_challenge(challenge, con)? != user_id {
+                return err::res_msg("challenge is issued for another user");
+            }
+            mfa::consume_challenge(challenge, con)?;
+        }
+        new_session(user_id, con)
+    });
+    auth_event::record(
+        AuthEventKind::Login,
+        user_id,
+        None,
+        &origin,
+        &res,
+        con,
+    )?;
+    res
+}
+
+async fn rpc_get_passkeys(
+    Json(rtdata): Json<RtData>,
+) -> Res<Json<Vec<Passkey>>> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, _)) = get_by_rt(&rtdata.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    Ok(Json(webauthn::get_many(user.id, con)?))
+}
+
+async fn rpc_del_passkey(Json(inp): Json<DelPasskey>) -> Res<()> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, _)) = get_by_rt(&inp.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    webauthn::del(user.id, inp.id, con)
+}
+
+async fn rpc_begin_upstream_login(
+    Json(inp): Json<BeginUpstreamLogin>,
+) -> Res<Json<UpstreamRedirect>> {
+    let con = &mut db::con().unwrap();
+    Ok(Json(upstream::begin(&inp.provider, con)?))
+}
+
+/// Logins an user by the code of an external provider, creating the user on
+/// the first login if the provider allows it.
+///
+/// Like `/rpc/login`, returns refresh token or the `mfa_required` error.
+async fn rpc_upstream_login(
+    origin: Origin,
+    Json(inp): Json<FinishUpstreamLogin>,
+) -> Res<String> {
+    let con = &mut db::con().unwrap();
+    let user = match upstream::finish(&inp.state, &inp.code, con).await {
+        Ok((provider, claims)) => {
+            upstream::get_or_provision(&provider, &claims, con)
+        }
+        Err(e) => Err(e),
+    };
+    let user_id = user.as_ref().ok().map(|x| x.id);
+    let res = user.and_then(|user| new_session_or_mfa(user.id, con));
+    auth_event::record(
+        AuthEventKind::Login,
+        user_id,
+        None,
+        &origin,
+        &res,
+        con,
+    )?;
+    res
+}
+
+/// Links an external provider's account to the logged in user, so they can
+/// sign in with it.
+async fn rpc_link_upstream(
+    Json(inp): Json<LinkUpstream>,
+) -> Res<Json<Identity>> {
+    let con = &mut db::con().unwrap();
+    let Ok((user, _)) = get_by_rt(&inp.rt, con) else {
+        return err::res_msg("no such refresh token for user");
+    };
+    let (provider, claims) =
+        upstream::finish(&inp.finish.state, &inp.finish.code, con).await?;
+    Ok(Json(upstream::link(user.id, &provider, &claims, con)?))
+}
+
+async fn rpc_get_auth_events(
+    headers: HeaderMap,
+    Json(inp): Json<GetAuthEvents>,
+) -> Res<Json<GetAuthEventsRes<Value>>> {
+    verify_server_from_headers(headers, "audit")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(auth_event::get_many(&inp, con)?))
+}
+
+/// Exports auth events matching the search as JSON Lines.
+async fn rpc_export_auth_events(
+    headers: HeaderMap,
+    Json(inp): Json<GetAuthEvents>,
+) -> Res<([(header::HeaderName, &'static str); 1], String)> {
+    verify_server_from_headers(headers, "audit")?;
+    let con = &mut db::con().unwrap();
+    Ok((
+        [(header::CONTENT_TYPE, "application/jsonl")],
+        auth_event::export(&inp, con)?,
+    ))
+}
+
+async fn rpc_update_metadata(
+    headers: HeaderMap,
+    Json(inp): Json<UpdateMetadata>,
+) -> Res<Json<User>> {
+    verify_server_from_headers(headers, "users")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(user::update_metadata(&inp, con)?))
+}
+
+async fn rpc_get_identities(
+    headers: HeaderMap,
+    Json(inp): Json<UserIdData>,
+) -> Res<Json<Vec<Identity>>> {
+    verify_server_from_headers(headers, "users")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(upstream::get_many(inp.user_id, con)?))
+}
+
+async fn rpc_new_oauth_client(
+    headers: HeaderMap,
+    Json(inp): Json<NewOauthClient>,
+) -> Res<Json<OauthClient>> {
+    verify_domain_secret_from_headers(headers)?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(oidc::new_client(&inp, con)?))
+}
+
+async fn rpc_del_oauth_client(
+    headers: HeaderMap,
+    Json(inp): Json<IdData>,
+) -> Res<()> {
+    verify_domain_secret_from_headers(headers)?;
+    let con = &mut db::con().unwrap();
+    oidc::del_client(inp.id, con)
+}
+
+async fn rpc_get_oauth_clients(
+    headers: HeaderMap,
+) -> Res<Json<Vec<OauthClient>>> {
     verify_domain_secret_from_headers(headers)?;
     let con = &mut db::con().unwrap();
-    let changes = user_change::get_many(get_changes.from, con).unwrap();
-    Ok(Json(changes))
+    Ok(Json(oidc::get_clients(con)?))
+}
+
+async fn rpc_set_role(
+    headers: HeaderMap,
+    Json(inp): Json<SetRole>,
+) -> Res<Json<Role>> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(role::set(&inp, con)?))
+}
+
+async fn rpc_del_role(
+    headers: HeaderMap,
+    Json(inp): Json<NameData>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    role::del(&inp.name, con)
+}
+
+async fn rpc_get_roles(headers: HeaderMap) -> Res<Json<Vec<Role>>> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(role::get_many(con)?))
+}
+
+async fn rpc_assign_role(
+    headers: HeaderMap,
+    Json(inp): Json<AssignRole>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    role::assign(&inp, con)
+}
+
+async fn rpc_revoke_role(
+    headers: HeaderMap,
+    Json(inp): Json<AssignRole>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    role::revoke(&inp, con)
+}
+
+async fn rpc_get_user_roles(
+    headers: HeaderMap,
+    Json(inp): Json<UserIdData>,
+) -> Res<Json<Vec<UserRole>>> {
+    verify_server_from_headers(headers, "roles")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(role::get_user_roles(inp.user_id, con)?))
+}
+
+async fn rpc_new_org(
+    headers: HeaderMap,
+    Json(inp): Json<NewOrg>,
+) -> Res<Json<Org>> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(org::new(&inp, con)?))
+}
+
+async fn rpc_del_org(headers: HeaderMap, Json(inp): Json<IdData>) -> Res<()> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    org::del(inp.id, con)
+}
+
+async fn rpc_get_orgs(headers: HeaderMap) -> Res<Json<Vec<Org>>> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(org::get_many(con)?))
+}
+
+async fn rpc_set_member(
+    headers: HeaderMap,
+    Json(inp): Json<SetMember>,
+) -> Res<Json<Membership>> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(org::set_member(&inp, con)?))
+}
+
+async fn rpc_del_member(
+    headers: HeaderMap,
+    Json(inp): Json<DelMember>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    org::del_member(&inp, con)
+}
+
+async fn rpc_get_members(
+    headers: HeaderMap,
+    Json(inp): Json<OrgIdData>,
+) -> Res<Json<Vec<Membership>>> {
+    verify_server_from_headers(headers, "orgs")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(org::get_members(inp.org_id, con)?))
+}
+
+async fn rpc_get_mfa(
+    headers: HeaderMap,
+    Json(inp): Json<UserIdData>,
+) -> Res<Json<MfaStatus>> {
+    verify_server_from_headers(headers, "mfa")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(mfa::get_status(inp.user_id, con)?))
+}
+
+async fn rpc_require_mfa(
+    headers: HeaderMap,
+    Json(inp): Json<RequireMfa>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "mfa")?;
+    let con = &mut db::con().unwrap();
+    mfa::set_required(inp.user_id, inp.required, con)
+}
+
+/// Removes TOTP of an user, who then logs in with password only, or enrolls
+/// again if MFA is required.
+async fn rpc_reset_mfa(
+    headers: HeaderMap,
+    Json(inp): Json<UserIdData>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "mfa")?;
+    let con = &mut db::con().unwrap();
+    mfa::reset(inp.user_id, con)
 }
 
 async fn rpc_get_users(
     headers: HeaderMap,
     Json(inp): Json<GetUsers>,
-) -> Res<Json<Vec<User>>> {
-    verify_domain_secret_from_headers(headers)?;
+) -> Res<Json<GetUsersRes<Value>>> {
+    verify_server_from_headers(headers, "users")?;
     let con = &mut db::con().unwrap();
-    let users = user::get_many(inp.sq, con).unwrap();
-    Ok(Json(users))
+    Ok(Json(user::get_many(&inp, con)?))
+}
+
+async fn rpc_new_webhook(
+    headers: HeaderMap,
+    Json(inp): Json<NewWebhook>,
+) -> Res<Json<Webhook>> {
+    verify_server_from_headers(headers, "webhooks")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(webhook::new(&inp, con)?))
+}
+
+async fn rpc_del_webhook(
+    headers: HeaderMap,
+    Json(inp): Json<IdData>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "webhooks")?;
+    let con = &mut db::con().unwrap();
+    webhook::del(inp.id, con)
+}
+
+async fn rpc_get_webhooks(headers: HeaderMap) -> Res<Json<Vec<Webhook>>> {
+    verify_server_from_headers(headers, "webhooks")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(webhook::get_many(con)?))
+}
+
+async fn rpc_get_webhook_deliveries(
+    headers: HeaderMap,
+    Json(inp): Json<GetWebhookDeliveries>,
+) -> Res<Json<Vec<WebhookDelivery>>> {
+    verify_server_from_headers(headers, "webhooks")?;
+    let con = &mut db::con().unwrap();
+    Ok(Json(webhook::get_deliveries(&inp, con)?))
+}
+
+async fn rpc_redeliver_webhook(
+    headers: HeaderMap,
+    Json(inp): Json<IdData>,
+) -> Res<()> {
+    verify_server_from_headers(headers, "webhooks")?;
+    let con = &mut db::con().unwrap();
+    webhook::redeliver(inp.id, con)
 }
 
 fn verify_domain_secret_from_headers(headers: HeaderMap) -> Res<()> {
     match headers.get("domain_secret") {
         Some(secret) => {
-            if secret.to_str().unwrap() != &APPRC.domain.secret {
+            if secret.to_str().unwrap() != APPRC.domain.secret {
                 return err::res_msg("invalid secret");
             }
         }
@@ -205,6 +1597,95 @@ fn verify_domain_secret_from_headers(headers: HeaderMap) -> Res<()> {
     Ok(())
 }
 
+/// Authenticates a backend service either by a service token with the
+/// scope, or by the domain secret, which grants all scopes.
+///
+/// Returns id of the service client, none for the domain secret.
+fn verify_server_from_headers(
+    headers: HeaderMap,
+    scope: &str,
+) -> Res<Option<String>> {
+    let at = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    match at {
+        Some(at) => {
+            let claims =
+                oidc::verify_service_token(at, scope, &mut db::con()?)?;
+            Ok(Some(claims.client_id))
+        }
+        None => {
+            verify_domain_secret_from_headers(headers)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Request bodies of server routes are buffered up to the limit of the `Json`
+/// extractor, larger ones would be rejected by the route anyway.
+const SERVER_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Writes a `Server` auth event for each call of a `/rpc/server/*` route,
+/// with the calling service client as the domain and `user_id` of the
+/// request body, if any.
+async fn server_event_middleware(
+    origin: Origin,
+    req: Request,
+    next: Next,
+) -> Response {
+    let rpc = req
+        .uri()
+        .path()
+        .trim_start_matches("/rpc/server/")
+        .to_string();
+    let domain = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .and_then(token::get_service_client_id);
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, SERVER_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let user_id = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|x| x.get("user_id")?.as_i64())
+        .and_then(|x| db::Id::try_from(x).ok());
+
+    let res = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (res, reason) = if res.status().is_success() {
+        (res, None)
+    } else {
+        let (parts, body) = res.into_parts();
+        let body = to_bytes(body, SERVER_BODY_LIMIT).await.unwrap_or_default();
+        let reason = match serde_json::from_slice::<Error>(&body) {
+            Ok(e) => auth_event::reason(&e),
+            Err(_) => parts.status.to_string(),
+        };
+        (Response::from_parts(parts, Body::from(body)), Some(reason))
+    };
+
+    let event = NewAuthEvent {
+        kind: AuthEventKind::Server,
+        user_id,
+        domain,
+        rpc: Some(rpc),
+        outcome: match reason {
+            Some(_) => AuthOutcome::Failure,
+            None => AuthOutcome::Success,
+        },
+        reason,
+    };
+    if let Err(e) = db::con()
+        .and_then(|mut con| auth_event::new(&event, &origin, &mut con))
+    {
+        warn!("cannot record server event: {:?}", e);
+    }
+    res
+}
+
 async fn err_middleware(req: Request, next: Next) -> Response {
     let res = next.run(req).await;
 
@@ -229,29 +1710,110 @@ fn panic_middleware(
         "msg": msg
     });
 
-    (
-        StatusCode::BAD_REQUEST,
-        Json(body),
-    )
-        .into_response()
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+/// Routes called by domains, each call is written as an auth event.
+fn get_server_router() -> Router {
+    Router::new()
+        .route("/rpc/server/reg", post(rpc_reg))
+        .route("/rpc/server/dereg", post(rpc_dereg))
+        .route("/rpc/server/get_user_changes", post(rpc_get_user_changes))
+        .route(
+            "/rpc/server/search_user_changes",
+            post(rpc_search_user_changes),
+        )
+        .route(
+            "/rpc/server/stream_user_changes",
+            post(rpc_stream_user_changes),
+        )
+        .route("/rpc/server/get_users", post(rpc_get_users))
+        .route("/rpc/server/import_users", post(rpc_import_users))
+        .route("/rpc/server/update_metadata", post(rpc_update_metadata))
+        .route("/rpc/server/impersonate", post(rpc_impersonate))
+        .route("/rpc/server/get_identities", post(rpc_get_identities))
+        .route("/rpc/server/set_role", post(rpc_set_role))
+        .route("/rpc/server/del_role", post(rpc_del_role))
+        .route("/rpc/server/get_roles", post(rpc_get_roles))
+        .route("/rpc/server/assign_role", post(rpc_assign_role))
+        .route("/rpc/server/revoke_role", post(rpc_revoke_role))
+        .route("/rpc/server/get_user_roles", post(rpc_get_user_roles))
+        .route("/rpc/server/new_org", post(rpc_new_org))
+        .route("/rpc/server/del_org", post(rpc_del_org))
+        .route("/rpc/server/get_orgs", post(rpc_get_orgs))
+        .route("/rpc/server/set_member", post(rpc_set_member))
+        .route("/rpc/server/del_member", post(rpc_del_member))
+        .route("/rpc/server/get_members", post(rpc_get_members))
+        .route("/rpc/server/get_mfa", post(rpc_get_mfa))
+        .route("/rpc/server/require_mfa", post(rpc_require_mfa))
+        .route("/rpc/server/reset_mfa", post(rpc_reset_mfa))
+        .route(
+            "/rpc/server/new_verification",
+            post(rpc_server_new_verification),
+        )
+        .route("/rpc/server/new_webhook", post(rpc_new_webhook))
+        .route("/rpc/server/del_webhook", post(rpc_del_webhook))
+        .route("/rpc/server/get_webhooks", post(rpc_get_webhooks))
+        .route(
+            "/rpc/server/get_webhook_deliveries",
+            post(rpc_get_webhook_deliveries),
+        )
+        .route("/rpc/server/redeliver_webhook", post(rpc_redeliver_webhook))
+        .route("/rpc/server/new_oauth_client", post(rpc_new_oauth_client))
+        .route("/rpc/server/del_oauth_client", post(rpc_del_oauth_client))
+        .route("/rpc/server/get_oauth_clients", post(rpc_get_oauth_clients))
+        .route("/rpc/server/get_auth_events", post(rpc_get_auth_events))
+        .route(
+            "/rpc/server/export_auth_events",
+            post(rpc_export_auth_events),
+        )
+        .route_layer(middleware::from_fn(server_event_middleware))
 }
 
 pub fn get_router() -> Router {
     Router::new()
         .route("/rpc/login", post(rpc_login))
+        .route("/rpc/login/mfa", post(rpc_login_mfa))
         .route("/rpc/logout", post(rpc_logout))
+        .route("/rpc/new_totp", post(rpc_new_totp))
+        .route("/rpc/confirm_totp", post(rpc_confirm_totp))
+        .route(
+            "/rpc/webauthn/begin_registration",
+            post(rpc_begin_webauthn_registration),
+        )
+        .route(
+            "/rpc/webauthn/finish_registration",
+            post(rpc_finish_webauthn_registration),
+        )
+        .route("/rpc/webauthn/begin_login", post(rpc_begin_webauthn_login))
+        .route("/rpc/webauthn/login", post(rpc_webauthn_login))
+        .route("/rpc/webauthn/get_passkeys", post(rpc_get_passkeys))
+        .route("/rpc/webauthn/del_passkey", post(rpc_del_passkey))
+        .route("/rpc/upstream/begin_login", post(rpc_begin_upstream_login))
+        .route("/rpc/upstream/login", post(rpc_upstream_login))
+        .route("/rpc/upstream/link", post(rpc_link_upstream))
+        .route("/rpc/change_password", post(rpc_change_password))
+        .route("/rpc/new_verification", post(rpc_new_verification))
+        .route("/rpc/verify", post(rpc_verify))
         .route("/rpc/current", post(rpc_current))
         .route("/rpc/access", post(rpc_access))
+        .route("/rpc/update_metadata", post(rpc_update_user_metadata))
         // domain-only
-        .route("/rpc/server/reg", post(rpc_reg))
-        .route("/rpc/server/dereg", post(rpc_dereg))
-        .route("/rpc/server/get_user_changes", post(rpc_get_user_changes))
-        .route("/rpc/server/get_users", post(rpc_get_users))
+        .merge(get_server_router())
+        // openid connect
+        .route("/.well-known/openid-configuration", get(oidc::discovery))
+        .route("/oauth/jwks", get(oidc::jwks))
+        .route(
+            "/oauth/authorize",
+            get(oidc::authorize_page).post(oidc::authorize),
+        )
+        .route("/oauth/token", post(oidc::token))
+        .route("/oauth/userinfo", get(oidc::userinfo).post(oidc::userinfo))
         .layer(CatchPanicLayer::custom(panic_middleware))
         .layer(middleware::from_fn(err_middleware))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::POST])
+                .allow_methods([Method::GET, Method::POST])
                 .allow_origin(Any)
                 .allow_headers(Any),
         )
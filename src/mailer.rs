@@ -0,0 +1,402 @@
+use std::{fs, io::Write, time::Duration};
+
+use diesel::prelude::*;
+use lettre::{
+    transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::{self, Con, Id},
+    get_mode,
+    quco::Collection,
+    ryz::{
+        dict::dict,
+        enm::StrEnum,
+        err, path,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema,
+    verification::{IdentifierKind, Sender, Verification},
+    webhook::{self, DeliveryStatus},
+    MailSinkKind, MailerCfg, APPRC,
+};
+
+/// How often the worker checks for due mails.
+const WORKER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where mails are delivered to.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    Smtp {
+        host: String,
+        port: u16,
+        credentials: Option<(String, String)>,
+        /// Whether to require STARTTLS.
+        tls: bool,
+    },
+    /// Appends mails as JSON lines to the file.
+    File(String),
+    Stdout,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Mail {
+    pub id: Id,
+    pub created: Time,
+    pub template: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt: Time,
+    /// Error of the last failed attempt.
+    pub err: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::mail)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MailTable {
+    pub id: Id,
+    pub created: Time,
+    pub template: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: Time,
+    pub err: Option<String>,
+}
+
+impl Collection<Mail> for MailTable {
+    fn to_msg(&self) -> Mail {
+        Mail {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            template: self.template.to_owned(),
+            recipient: self.recipient.to_owned(),
+            subject: self.subject.to_owned(),
+            body: self.body.to_owned(),
+            status: DeliveryStatus::from_str(self.status.as_str()).unwrap(),
+            attempts: self.attempts.to_owned(),
+            next_attempt: self.next_attempt.to_owned(),
+            err: self.err.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name=schema::mail)]
+struct InsertMail {
+    pub created: Time,
+    pub template: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: Time,
+}
+
+/// Message template, stored as `<templates>/[<mode>/]<locale>/<name>.yml`
+/// next to `corund.cfg.yml`.
+///
+/// `{{var}}` placeholders in subject and body are replaced by the passed
+/// variables.
+#[derive(Deserialize, Debug)]
+struct Template {
+    subject: String,
+    body: String,
+}
+
+fn cfg() -> Res<&'static MailerCfg> {
+    match &APPRC.mailer {
+        Some(cfg) => Ok(cfg),
+        None => err::res("no_mailer", "mailer is not configured"),
+    }
+}
+
+pub fn is_enabled() -> bool {
+    APPRC.mailer.is_some()
+}
+
+/// Finds a template, preferring the current mode over the common ones and
+/// the given locale over the default one.
+fn load_template(name: &str, locale: Option<&str>) -> Res<Template> {
+    let cfg = cfg()?;
+    let dir = path::cwd().unwrap().join(&cfg.templates);
+    let mode = get_mode();
+    let mut locales = vec![];
+    if let Some(locale) = locale {
+        locales.push(locale);
+    }
+    locales.push(cfg.locale.as_str());
+    for locale in locales {
+        for file in [
+            dir.join(&mode).join(locale).join(name.to_string() + ".yml"),
+            dir.join(locale).join(name.to_string() + ".yml"),
+        ] {
+            if let Ok(content) = fs::read_to_string(file) {
+                return Ok(serde_yml::from_str(&content).unwrap());
+            }
+        }
+    }
+    err::res("no_template", format!("no mail template {}", name).as_str())
+}
+
+fn render(text: &str, vars: &dict<&str, String>) -> String {
+    let mut text = text.to_string();
+    for (k, v) in vars {
+        text = text.replace(&format!("{{{{{}}}}}", k), v);
+    }
+    text
+}
+
+/// Renders a template and puts the mail into the outbox, from which it's
+/// sent by the worker.
+pub fn enqueue(
+    template: &str,
+    recipient: &str,
+    locale: Option<&str>,
+    vars: &dict<&str, String>,
+    con: &mut Con,
+) -> Res<Id> {
+    let t = load_template(template, locale)?;
+    let now = utc();
+    Ok(diesel::insert_into(schema::mail::table)
+        .values(&InsertMail {
+            created: now,
+            template: template.to_string(),
+            recipient: recipient.to_string(),
+            subject: render(&t.subject, vars),
+            body: render(&t.body, vars),
+            status: DeliveryStatus::Pending.to_str().to_string(),
+            attempts: 0,
+            next_attempt: now,
+        })
+        .returning(schema::mail::id)
+        .get_result(con)?)
+}
+
+pub fn get(id: Id, con: &mut Con) -> Res<Mail> {
+    Ok(schema::mail::table
+        .filter(schema::mail::id.eq(id))
+        .select(MailTable::as_select())
+        .first(con)?
+        .to_msg())
+}
+
+/// Sends verification codes of emails by mail, with the `verification`
+/// template.
+pub struct MailSender;
+
+impl Sender for MailSender {
+    fn send(&self, verification: &Verification) -> Res<()> {
+        if verification.kind != IdentifierKind::Email {
+            return err::res("no_sender", "mailer can only send to emails");
+        }
+        let vars = dict::from([
+            ("id", verification.id.to_string()),
+            ("code", verification.code.clone().unwrap()),
+            ("target", verification.target.to_owned()),
+        ]);
+        let con = &mut db::con()?;
+        enqueue("verification", &verification.target, None, &vars, con)?;
+        Ok(())
+    }
+}
+
+/// Sink set by the config.
+pub fn configured_sink() -> Res<Sink> {
+    let cfg = cfg()?;
+    Ok(match cfg.sink {
+        MailSinkKind::Smtp => {
+            let Some(smtp) = &cfg.smtp else {
+                return err::res("no_mailer", "smtp is not configured");
+            };
+            Sink::Smtp {
+                host: smtp.host.to_owned(),
+                port: smtp.port,
+                credentials: smtp.username.to_owned().map(|x| {
+                    (x, smtp.password.to_owned().unwrap_or_default())
+                }),
+                tls: smtp.tls,
+            }
+        }
+        MailSinkKind::File => Sink::File(cfg.file.to_owned()),
+        MailSinkKind::Stdout => Sink::Stdout,
+    })
+}
+
+/// Claims due mails, so other workers won't pick them up until the lease
+/// expires.
+fn claim_due(batch: i64, lease: Time, con: &mut Con) -> Res<Vec<MailTable>> {
+    let now = utc();
+    Ok(con.transaction::<_, diesel::result::Error, _>(|con| {
+        let ids = schema::mail::table
+            .filter(schema::mail::status.eq(DeliveryStatus::Pending.to_str()))
+            .filter(schema::mail::next_attempt.le(now))
+            .order(schema::mail::id.asc())
+            .limit(batch)
+            .select(schema::mail::id)
+            .for_update()
+            .skip_locked()
+            .load::<Id>(con)?;
+        diesel::update(
+            schema::mail::table.filter(schema::mail::id.eq_any(&ids)),
+        )
+        .set(schema::mail::next_attempt.eq(now + lease))
+        .execute(con)?;
+        schema::mail::table
+            .filter(schema::mail::id.eq_any(&ids))
+            .order(schema::mail::id.asc())
+            .select(MailTable::as_select())
+            .load(con)
+    })?)
+}
+
+async fn send(
+    sink: &Sink,
+    cfg: &MailerCfg,
+    mail: &MailTable,
+) -> Result<(), String> {
+    match sink {
+        Sink::Smtp {
+            host,
+            port,
+            credentials,
+            tls,
+        } => {
+            let mut builder = if *tls {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                    .map_err(|x| x.to_string())?
+            } else {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            }
+            .port(*port)
+            .timeout(Some(Duration::from_secs_f64(cfg.timeout)));
+            if let Some((username, password)) = credentials {
+                builder = builder.credentials(Credentials::new(
+                    username.to_owned(),
+                    password.to_owned(),
+                ));
+            }
+            let message = Message::builder()
+                .from(cfg.from.parse().map_err(|x| format!("{}", x))?)
+                .to(mail.recipient.parse().map_err(|x| format!("{}", x))?)
+                .subject(mail.subject.to_owned())
+                .body(mail.body.to_owned())
+                .map_err(|x| x.to_string())?;
+            builder
+                .build()
+                .send(message)
+                .await
+                .map_err(|x| x.to_string())?;
+        }
+        Sink::File(file) => {
+            let line = json!({
+                "id": mail.id,
+                "from": cfg.from,
+                "to": mail.recipient,
+                "subject": mail.subject,
+                "body": mail.body,
+            })
+            .to_string();
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path::cwd().map_err(|x| x.to_string())?.join(file))
+                .map_err(|x| x.to_string())?;
+            writeln!(file, "{}", line).map_err(|x| x.to_string())?;
+        }
+        Sink::Stdout => {
+            info!(
+                "mail {} from {} to {}\n{}\n\n{}",
+                mail.id, cfg.from, mail.recipient, mail.subject, mail.body
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sends all due mails to the sink.
+///
+/// Failed mails are retried with exponential backoff until the configured
+/// amount of attempts is reached, after which they become dead.
+///
+/// Returns amount of processed mails.
+pub async fn deliver_due(sink: &Sink, con: &mut Con) -> Res<usize> {
+    let cfg = cfg()?;
+    let (batch, lease) = webhook::claim_size(cfg.timeout);
+    let due = claim_due(batch, lease, con)?;
+    for mail in due.iter() {
+        let attempts = mail.attempts + 1;
+        let q = schema::mail::table.filter(schema::mail::id.eq(mail.id));
+        match send(sink, cfg, mail).await {
+            Ok(()) => {
+                diesel::update(q)
+                    .set((
+                        schema::mail::status.eq(DeliveryStatus::Done.to_str()),
+                        schema::mail::attempts.eq(attempts),
+                        schema::mail::err.eq::<Option<String>>(None),
+                    ))
+                    .execute(con)?;
+            }
+            Err(e) => {
+                warn!("mail {} failed: {}", mail.id, e);
+                let status = if attempts >= cfg.max_attempts {
+                    DeliveryStatus::Dead
+                } else {
+                    DeliveryStatus::Pending
+                };
+                let next_attempt =
+                    utc() + cfg.backoff * 2f64.powi(attempts - 1);
+                diesel::update(q)
+                    .set((
+                        schema::mail::status.eq(status.to_str()),
+                        schema::mail::attempts.eq(attempts),
+                        schema::mail::next_attempt.eq(next_attempt),
+                        schema::mail::err.eq(Some(e)),
+                    ))
+                    .execute(con)?;
+            }
+        }
+    }
+    Ok(due.len())
+}
+
+/// Sends mails to the configured sink in the background for as long as the
+/// server runs.
+///
+/// Errors are logged and the connection is reestablished on the next tick.
+pub async fn run_worker() {
+    let sink = match configured_sink() {
+        Ok(sink) => sink,
+        Err(e) => {
+            warn!("mail worker is not started: {:?}", e);
+            return;
+        }
+    };
+    let mut con = None;
+    loop {
+        if con.is_none() {
+            con = db::con()
+                .inspect_err(|e| warn!("mail worker: {:?}", e))
+                .ok();
+        }
+        if let Some(x) = con.as_mut() {
+            if let Err(e) = deliver_due(&sink, x).await {
+                warn!("mail worker: {:?}", e);
+                con = None;
+            }
+        }
+        tokio::time::sleep(WORKER_INTERVAL).await;
+    }
+}
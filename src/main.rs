@@ -1,14 +1,75 @@
-use corund_lib::get_router;
+use std::{
+    env, fs, net::SocketAddr, path::Path, process, sync::Arc, time::Duration,
+};
+
+use corund_lib::{
+    db, get_router,
+    import::{self, ImportFormat, ImportUsers},
+    mailer, password, verification, webhook,
+};
 use log::info;
 
+/// Imports users from a JSON Lines or CSV file, chosen by it's extension.
+fn import_users(path: &str) {
+    let format = match Path::new(path).extension().and_then(|x| x.to_str()) {
+        Some("csv") => ImportFormat::Csv,
+        _ => ImportFormat::Jsonl,
+    };
+    let inp = ImportUsers {
+        format,
+        data: fs::read_to_string(path).unwrap(),
+    };
+    let con = &mut db::con().unwrap();
+    let res = import::import(&inp, con).unwrap();
+    for e in res.errs.iter() {
+        eprintln!("row {}: {}: {}", e.row, e.code, e.msg);
+    }
+    if !res.errs.is_empty() {
+        process::exit(1);
+    }
+    info!("imported {} users", res.ids.len());
+}
+
+/// Prints argon2 parameters for which hashing with the configured algorithm
+/// takes about the target time, 500ms by default.
+fn bench_password(target_ms: Option<&String>) {
+    let target_ms: u64 = target_ms.map_or(500, |x| x.parse().unwrap());
+    let (algorithm, params) =
+        password::bench(Duration::from_millis(target_ms));
+    println!("password:");
+    println!("  algorithm: {}", algorithm);
+    println!("  m_cost: {}", params.m_cost());
+    println!("  t_cost: {}", params.t_cost());
+    println!("  p_cost: {}", params.p_cost());
+}
+
 #[tokio::main]
 async fn main() {
     colog::init();
 
-    // let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().collect();
+    match (args.get(1).map(|x| x.as_str()), args.get(2)) {
+        (Some("import"), Some(path)) => return import_users(path),
+        (Some("bench_password"), target_ms) => {
+            return bench_password(target_ms)
+        }
+        _ => (),
+    }
+
+    tokio::spawn(webhook::run_worker());
+    if mailer::is_enabled() {
+        verification::set_sender(Some(Arc::new(mailer::MailSender)));
+        tokio::spawn(mailer::run_worker());
+    }
 
     info!("start server http://0.0.0.0:9014");
     let listener =
         tokio::net::TcpListener::bind("0.0.0.0:9014").await.unwrap();
-    axum::serve(listener, get_router()).await.unwrap();
+    // connect info gives auth events the client ip when not behind a proxy
+    axum::serve(
+        listener,
+        get_router().into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
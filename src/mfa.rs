@@ -0,0 +1,454 @@
+use data_encoding::BASE32_NOPAD;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{Con, Id},
+    ryz::{
+        err,
+        hex::to_hex,
+        res::Res,
+        time::{utc, Time},
+        url::encode_component,
+    },
+    schema, token, webauthn, APPRC,
+};
+
+/// TOTP secret given to the user's authenticator app.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TotpEnrollment {
+    /// Base32 secret, for manual entry.
+    pub secret: String,
+    /// `otpauth://` provisioning URI, usually shown as a QR code.
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MfaStatus {
+    pub user_id: Id,
+    /// Whether the domain requires the user to pass MFA on login.
+    pub required: bool,
+    /// Whether the user has any second factor.
+    pub enabled: bool,
+    /// Whether the user has confirmed TOTP enrollment.
+    pub totp: bool,
+    pub passkeys: i64,
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::mfa)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MfaTable {
+    pub user_id: Id,
+    pub required: bool,
+    pub totp_secret: Option<String>,
+    pub totp_confirmed_at: Option<Time>,
+    /// Last accepted time step, codes of it and earlier steps are rejected.
+    pub totp_last_step: i64,
+    /// Wrong codes entered since `attempts_since`.
+    pub attempts: i32,
+    /// Start of the window in which wrong codes are counted.
+    pub attempts_since: Option<Time>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::mfa)]
+struct InsertMfa {
+    user_id: Id,
+    required: bool,
+    totp_last_step: i64,
+    attempts: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::recovery_code)]
+struct InsertRecoveryCode {
+    user_id: Id,
+    hcode: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::mfa_challenge)]
+struct InsertMfaChallenge {
+    hnonce: String,
+    user_id: Id,
+    expires: Time,
+}
+
+fn hash_code(code: &str) -> String {
+    to_hex(&Sha256::digest(code.as_bytes()))
+}
+
+/// Time step of the given unix time.
+pub fn get_step(time: Time) -> i64 {
+    (time / APPRC.mfa.period as Time).floor() as i64
+}
+
+/// Computes TOTP code of the base32 secret for the time step, as defined by
+/// RFC 6238 with HMAC-SHA1.
+pub fn get_totp_code(secret: &str, step: i64) -> Res<String> {
+    let Ok(key) = BASE32_NOPAD.decode(secret.as_bytes()) else {
+        return err::res_msg("invalid totp secret");
+    };
+    let mut mac: Hmac<Sha1> = Hmac::new_from_slice(&key).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bin = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    let digits = APPRC.mfa.digits as usize;
+    Ok(format!(
+        "{:0digits$}",
+        bin % 10u32.pow(digits as u32),
+        digits = digits
+    ))
+}
+
+fn get_row(user_id: Id, con: &mut Con) -> Res<Option<MfaTable>> {
+    Ok(schema::mfa::table
+        .filter(schema::mfa::user_id.eq(user_id))
+        .select(MfaTable::as_select())
+        .first(con)
+        .optional()?)
+}
+
+/// Creates the MFA row of the user if it doesn't exist yet.
+fn ensure_row(user_id: Id, con: &mut Con) -> Res<()> {
+    diesel::insert_into(schema::mfa::table)
+        .values(&InsertMfa {
+            user_id,
+            required: false,
+            totp_last_step: 0,
+            attempts: 0,
+        })
+        .on_conflict_do_nothing()
+        .execute(con)?;
+    Ok(())
+}
+
+pub fn get_status(user_id: Id, con: &mut Con) -> Res<MfaStatus> {
+    let row = get_row(user_id, con)?;
+    let recovery_codes_left = schema::recovery_code::table
+        .filter(schema::recovery_code::user_id.eq(user_id))
+        .filter(schema::recovery_code::used_at.is_null())
+        .count()
+        .get_result(con)?;
+    let totp = row.as_ref().is_some_and(|x| x.totp_confirmed_at.is_some());
+    let passkeys = webauthn::count(user_id, con)?;
+    Ok(MfaStatus {
+        user_id,
+        required: row.as_ref().is_some_and(|x| x.required),
+        enabled: totp || passkeys > 0,
+        totp,
+        passkeys,
+        recovery_codes_left,
+    })
+}
+
+/// Issues a login challenge for the user whose password is correct,
+/// discarding earlier challenges of the user.
+///
+/// Only the hash of the challenge nonce is stored, and the challenge is
+/// accepted until it's consumed by a completed login or expires.
+pub fn new_challenge(user_id: Id, con: &mut Con) -> Res<String> {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let nonce = to_hex(&bytes);
+    let now = utc();
+    con.transaction::<_, diesel::result::Error, _>(|con| {
+        diesel::delete(
+            schema::mfa_challenge::table.filter(
+                schema::mfa_challenge::user_id
+                    .eq(user_id)
+                    .or(schema::mfa_challenge::expires.lt(now)),
+            ),
+        )
+        .execute(con)?;
+        diesel::insert_into(schema::mfa_challenge::table)
+            .values(&InsertMfaChallenge {
+                hnonce: hash_code(&nonce),
+                user_id,
+                expires: now + APPRC.mfa.challenge_ttl,
+            })
+            .execute(con)?;
+        Ok(())
+    })?;
+    token::new_mfa_challenge(user_id, &nonce)
+}
+
+/// Checks the challenge is issued and not consumed yet.
+///
+/// Returns id of the user it's issued for.
+pub fn verify_challenge(challenge: &str, con: &mut Con) -> Res<Id> {
+    let claims =
+        token::verify_mfa_challenge(challenge, APPRC.mfa.challenge_ttl)?;
+    let found: i64 = schema::mfa_challenge::table
+        .filter(schema::mfa_challenge::hnonce.eq(hash_code(&claims.nonce)))
+        .filter(schema::mfa_challenge::user_id.eq(claims.user_id))
+        .filter(schema::mfa_challenge::expires.ge(utc()))
+        .count()
+        .get_result(con)?;
+    if found == 0 {
+        return err::res("invalid_token", "challenge is used or expired");
+    }
+    Ok(claims.user_id)
+}
+
+/// Consumes the challenge once the login is completed, so it cannot be
+/// replayed.
+///
+/// Returns id of the user it's issued for.
+pub fn consume_challenge(challenge: &str, con: &mut Con) -> Res<Id> {
+    let claims =
+        token::verify_mfa_challenge(challenge, APPRC.mfa.challenge_ttl)?;
+    let deleted = diesel::delete(
+        schema::mfa_challenge::table
+            .filter(schema::mfa_challenge::hnonce.eq(hash_code(&claims.nonce)))
+            .filter(schema::mfa_challenge::user_id.eq(claims.user_id))
+            .filter(schema::mfa_challenge::expires.ge(utc())),
+    )
+    .execute(con)?;
+    if deleted == 0 {
+        return err::res("invalid_token", "challenge is used or expired");
+    }
+    Ok(claims.user_id)
+}
+
+/// Starts TOTP enrollment, replacing any unconfirmed secret.
+pub fn new_totp(
+    user_id: Id,
+    username: &str,
+    con: &mut Con,
+) -> Res<TotpEnrollment> {
+    if get_status(user_id, con)?.totp {
+        return err::res("mfa_enabled", "totp is already enabled");
+    }
+    let mut key = [0u8; 20];
+    OsRng.fill_bytes(&mut key);
+    let secret = BASE32_NOPAD.encode(&key);
+    ensure_row(user_id, con)?;
+    diesel::update(
+        schema::mfa::table.filter(schema::mfa::user_id.eq(user_id)),
+    )
+    .set(schema::mfa::totp_secret.eq(Some(&secret)))
+    .execute(con)?;
+
+    let cfg = &APPRC.mfa;
+    let issuer = encode_component(&cfg.issuer);
+    let uri = format!(
+        concat!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}",
+            "&algorithm=SHA1&digits={}&period={}"
+        ),
+        issuer,
+        encode_component(username),
+        secret,
+        issuer,
+        cfg.digits,
+        cfg.period
+    );
+    Ok(TotpEnrollment { secret, uri })
+}
+
+/// Finds the step within the allowed skew for which the code matches.
+fn match_step(secret: &str, code: &str) -> Res<Option<i64>> {
+    let now = get_step(utc());
+    let skew = APPRC.mfa.skew;
+    for step in (now - skew)..=(now + skew) {
+        if get_totp_code(secret, step)? == code {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/// Replaces recovery codes of the user with new ones.
+///
+/// Returns the codes, which are only stored hashed.
+pub fn new_recovery_codes(user_id: Id, con: &mut Con) -> Res<Vec<String>> {
+    let codes: Vec<String> = (0..APPRC.mfa.recovery_codes)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            OsRng.fill_bytes(&mut bytes);
+            to_hex(&bytes)
+        })
+        .collect();
+    let rows: Vec<InsertRecoveryCode> = codes
+        .iter()
+        .map(|x| InsertRecoveryCode {
+            user_id,
+            hcode: hash_code(x),
+        })
+        .collect();
+    con.transaction(|con| {
+        diesel::delete(
+            schema::recovery_code::table
+                .filter(schema::recovery_code::user_id.eq(user_id)),
+        )
+        .execute(con)?;
+        diesel::insert_into(schema::recovery_code::table)
+            .values(&rows)
+            .execute(con)?;
+        Ok(codes)
+    })
+}
+
+/// Enables TOTP if the code matches the enrolled secret.
+///
+/// Returns recovery codes, which can be used instead of TOTP codes once
+/// each.
+pub fn confirm_totp(
+    user_id: Id,
+    code: &str,
+    con: &mut Con,
+) -> Res<Vec<String>> {
+    let row = get_row(user_id, con)?;
+    let Some(secret) = row.as_ref().and_then(|x| x.totp_secret.clone()) else {
+        return err::res("no_totp", "totp enrollment is not started");
+    };
+    if row.is_some_and(|x| x.totp_confirmed_at.is_some()) {
+        return err::res("mfa_enabled", "totp is already enabled");
+    }
+    if match_step(&secret, code)?.is_none() {
+        return err::res("invalid_code", "incorrect code");
+    }
+    diesel::update(
+        schema::mfa::table.filter(schema::mfa::user_id.eq(user_id)),
+    )
+    .set(schema::mfa::totp_confirmed_at.eq(Some(utc())))
+    .execute(con)?;
+    new_recovery_codes(user_id, con)
+}
+
+/// Checks the second factor, either a TOTP code or an unused recovery code.
+///
+/// Each TOTP code is accepted only once. After the configured amount of
+/// wrong codes further codes are rejected until the attempts window of the
+/// user ends, new logins with password do not reset it.
+///
+/// The MFA row of the user is locked for the check, so concurrent requests
+/// cannot exceed the attempts or reuse a code.
+pub fn check(user_id: Id, code: &str, con: &mut Con) -> Res<()> {
+    // wrong codes are committed too, so they're reported after the
+    // transaction
+    if con.transaction(|con| check_locked(user_id, code, con))? {
+        Ok(())
+    } else {
+        err::res("invalid_code", "incorrect code")
+    }
+}
+
+fn check_locked(user_id: Id, code: &str, con: &mut Con) -> Res<bool> {
+    let q = schema::mfa::table.filter(schema::mfa::user_id.eq(user_id));
+    let Some(row) = q
+        .select(MfaTable::as_select())
+        .for_update()
+        .first(con)
+        .optional()?
+    else {
+        return err::res("no_totp", "totp is not enabled");
+    };
+    let (Some(secret), Some(_)) = (&row.totp_secret, row.totp_confirmed_at)
+    else {
+        return err::res("no_totp", "totp is not enabled");
+    };
+    let now = utc();
+    let in_window = row
+        .attempts_since
+        .is_some_and(|x| now - x < APPRC.mfa.attempts_window);
+    if in_window && row.attempts >= APPRC.mfa.max_attempts {
+        return err::res("invalid_code", "too many attempts");
+    }
+
+    if let Some(step) = match_step(secret, code)? {
+        if step > row.totp_last_step {
+            diesel::update(q)
+                .set((
+                    schema::mfa::totp_last_step.eq(step),
+                    schema::mfa::attempts.eq(0),
+                    schema::mfa::attempts_since.eq::<Option<Time>>(None),
+                ))
+                .execute(con)?;
+            return Ok(true);
+        }
+    } else {
+        let used = diesel::update(
+            schema::recovery_code::table
+                .filter(schema::recovery_code::user_id.eq(user_id))
+                .filter(schema::recovery_code::hcode.eq(hash_code(code)))
+                .filter(schema::recovery_code::used_at.is_null()),
+        )
+        .set(schema::recovery_code::used_at.eq(Some(utc())))
+        .execute(con)?;
+        if used > 0 {
+            diesel::update(q)
+                .set((
+                    schema::mfa::attempts.eq(0),
+                    schema::mfa::attempts_since.eq::<Option<Time>>(None),
+                ))
+                .execute(con)?;
+            return Ok(true);
+        }
+    }
+
+    if in_window {
+        diesel::update(q)
+            .set(schema::mfa::attempts.eq(schema::mfa::attempts + 1))
+            .execute(con)?;
+    } else {
+        diesel::update(q)
+            .set((
+                schema::mfa::attempts.eq(1),
+                schema::mfa::attempts_since.eq(Some(now)),
+            ))
+            .execute(con)?;
+    }
+    Ok(false)
+}
+
+/// Sets whether the user must pass MFA on login. Users without a second
+/// factor are then asked to enroll before they get a refresh token.
+pub fn set_required(user_id: Id, required: bool, con: &mut Con) -> Res<()> {
+    ensure_row(user_id, con)?;
+    diesel::update(
+        schema::mfa::table.filter(schema::mfa::user_id.eq(user_id)),
+    )
+    .set(schema::mfa::required.eq(required))
+    .execute(con)?;
+    Ok(())
+}
+
+/// Removes TOTP, recovery codes and passkeys of the user, e.g. if the device
+/// is lost.
+///
+/// Whether MFA is required is kept.
+pub fn reset(user_id: Id, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        diesel::update(
+            schema::mfa::table.filter(schema::mfa::user_id.eq(user_id)),
+        )
+        .set((
+            schema::mfa::totp_secret.eq::<Option<String>>(None),
+            schema::mfa::totp_confirmed_at.eq::<Option<Time>>(None),
+            schema::mfa::totp_last_step.eq(0),
+            schema::mfa::attempts.eq(0),
+            schema::mfa::attempts_since.eq::<Option<Time>>(None),
+        ))
+        .execute(con)?;
+        diesel::delete(
+            schema::recovery_code::table
+                .filter(schema::recovery_code::user_id.eq(user_id)),
+        )
+        .execute(con)?;
+        webauthn::del_all(user_id, con)
+    })
+}
@@ -0,0 +1,1029 @@
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use diesel::prelude::*;
+use log::warn;
+use p256::{
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    pkcs8::DecodePrivateKey,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth_event::{self, AuthEventKind, Origin},
+    check_login,
+    db::{self, Con, Id},
+    mfa,
+    quco::Collection,
+    ryz::{
+        err,
+        hex::to_hex,
+        path,
+        res::Res,
+        time::{utc, Time},
+        url::encode_component,
+    },
+    schema,
+    token::{self, Expire},
+    user::{self, User},
+    Login, OidcCfg, APPRC,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OauthClient {
+    pub id: Id,
+    pub created: Time,
+    pub client_id: String,
+    /// Used to authenticate at the token endpoint. It's only returned on
+    /// creation, since it's stored hashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// Server scopes the client can get service tokens for.
+    pub scopes: Vec<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::oauth_client)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OauthClientTable {
+    pub id: Id,
+    pub created: Time,
+    pub client_id: String,
+    pub hsecret: String,
+    pub name: String,
+    pub redirect_uris: Value,
+    pub scopes: Value,
+}
+
+impl Collection<OauthClient> for OauthClientTable {
+    fn to_msg(&self) -> OauthClient {
+        OauthClient {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            client_id: self.client_id.to_owned(),
+            secret: None,
+            name: self.name.to_owned(),
+            redirect_uris: serde_json::from_value(self.redirect_uris.clone())
+                .unwrap(),
+            scopes: serde_json::from_value(self.scopes.clone()).unwrap(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::oauth_client)]
+struct InsertOauthClient {
+    created: Time,
+    client_id: String,
+    hsecret: String,
+    name: String,
+    redirect_uris: Value,
+    scopes: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewOauthClient {
+    pub name: String,
+    /// Exact URIs codes can be sent to.
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    /// Server scopes for the client credentials grant, for clients of
+    /// backend services.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::oauth_code)]
+struct InsertOauthCode {
+    hcode: String,
+    created: Time,
+    client_id: Id,
+    user_id: Id,
+    redirect_uri: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    expires: Time,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::oauth_code)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct OauthCodeTable {
+    client_id: Id,
+    user_id: Id,
+    redirect_uri: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    expires: Time,
+}
+
+/// Parameters of the authorization request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthorizeParams {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+/// Login form submitted to the authorization endpoint.
+#[derive(Deserialize, Debug)]
+pub struct AuthorizeForm {
+    #[serde(flatten)]
+    pub params: AuthorizeParams,
+    pub username: String,
+    pub password: String,
+    /// MFA challenge, given once the password is checked.
+    pub challenge: Option<String>,
+    /// TOTP or recovery code.
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenForm {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    /// Requested server scopes of the client credentials grant, all scopes
+    /// of the client by default.
+    pub scope: Option<String>,
+    /// For clients which don't use HTTP basic auth.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdTokenPayload {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// User claims granted by scopes.
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+impl Expire for IdTokenPayload {
+    fn get_created(&self) -> Res<Time> {
+        Ok(self.iat as Time)
+    }
+}
+
+/// Error in the format of RFC 6749.
+struct OauthErr {
+    status: StatusCode,
+    error: &'static str,
+    description: String,
+}
+
+impl OauthErr {
+    fn new(error: &'static str, description: &str) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error,
+            description: description.to_string(),
+        }
+    }
+
+    /// Failure on corund's side, e.g. of the database.
+    fn server(e: err::Error) -> Self {
+        warn!("oauth request failed: {:?}", e);
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: "server_error",
+            description: e.msg().to_string(),
+        }
+    }
+}
+
+impl IntoResponse for OauthErr {
+    fn into_response(self) -> Response {
+        let mut res = (
+            self.status,
+            Json(json!({
+                "error": self.error,
+                "error_description": self.description
+            })),
+        )
+            .into_response();
+        if self.status == StatusCode::UNAUTHORIZED {
+            res.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                format!("Bearer error=\"{}\"", self.error).parse().unwrap(),
+            );
+        }
+        res
+    }
+}
+
+/// Scopes of service tokens, each granting a group of `/rpc/server/*`
+/// routes.
+///
+/// Managing clients is left to the domain secret, so services cannot grant
+/// themselves more.
+pub const SERVER_SCOPES: [&str; 7] = [
+    "users",
+    "mfa",
+    "roles",
+    "orgs",
+    "webhooks",
+    "impersonate",
+    "audit",
+];
+
+fn cfg() -> Res<&'static OidcCfg> {
+    match &APPRC.oidc {
+        Some(cfg) => Ok(cfg),
+        None => err::res("no_oidc", "oidc provider is not configured"),
+    }
+}
+
+fn hash_code(code: &str) -> String {
+    to_hex(&Sha256::digest(code.as_bytes()))
+}
+
+fn new_secret(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub fn new_client(inp: &NewOauthClient, con: &mut Con) -> Res<OauthClient> {
+    for uri in inp.redirect_uris.iter() {
+        if !uri.starts_with("http://") && !uri.starts_with("https://") {
+            return err::res_msg("redirect uri must be http or https");
+        }
+    }
+    for scope in inp.scopes.iter() {
+        if !SERVER_SCOPES.contains(&scope.as_str()) {
+            return err::res(
+                "invalid_scope",
+                format!("unknown scope {}", scope).as_str(),
+            );
+        }
+    }
+    let secret = new_secret(32);
+    let client: OauthClientTable =
+        diesel::insert_into(schema::oauth_client::table)
+            .values(&InsertOauthClient {
+                created: utc(),
+                client_id: new_secret(16),
+                hsecret: hash_code(&secret),
+                name: inp.name.to_owned(),
+                redirect_uris: serde_json::to_value(&inp.redirect_uris)
+                    .unwrap(),
+                scopes: serde_json::to_value(&inp.scopes).unwrap(),
+            })
+            .returning(OauthClientTable::as_returning())
+            .get_result(con)?;
+    Ok(OauthClient {
+        secret: Some(secret),
+        ..client.to_msg()
+    })
+}
+
+/// Deletes a client together with it's pending codes. It's service tokens
+/// are rejected right away, other issued tokens stay valid until they
+/// expire.
+pub fn del_client(id: Id, con: &mut Con) -> Res<()> {
+    diesel::delete(
+        schema::oauth_client::table.filter(schema::oauth_client::id.eq(id)),
+    )
+    .execute(con)?;
+    Ok(())
+}
+
+pub fn get_clients(con: &mut Con) -> Res<Vec<OauthClient>> {
+    Ok(schema::oauth_client::table
+        .order(schema::oauth_client::id.asc())
+        .select(OauthClientTable::as_select())
+        .load(con)?
+        .iter()
+        .map(|x| x.to_msg())
+        .collect())
+}
+
+fn get_client_row(client_id: &str, con: &mut Con) -> Option<OauthClientTable> {
+    schema::oauth_client::table
+        .filter(schema::oauth_client::client_id.eq(client_id))
+        .select(OauthClientTable::as_select())
+        .first(con)
+        .ok()
+}
+
+fn get_client(client_id: &str, con: &mut Con) -> Option<OauthClient> {
+    get_client_row(client_id, con).map(|x| x.to_msg())
+}
+
+fn has_scope(scope: &str, name: &str) -> bool {
+    scope.split(' ').any(|x| x == name)
+}
+
+/// Standard claims of the user, besides `sub`, granted by the `profile`,
+/// `email` and `phone` scopes.
+pub fn get_claims(user: &User, scope: &str) -> Map<String, Value> {
+    let mut claims = Map::new();
+    if has_scope(scope, "profile") {
+        claims.insert("preferred_username".to_string(), json!(user.username));
+        for (k, v) in [
+            ("given_name", &user.firstname),
+            ("middle_name", &user.patronym),
+            ("family_name", &user.surname),
+        ] {
+            if let Some(v) = v {
+                claims.insert(k.to_string(), json!(v));
+            }
+        }
+        claims.insert("updated_at".to_string(), json!(user.updated as i64));
+    }
+    if has_scope(scope, "email") {
+        if let Some(email) = &user.email {
+            claims.insert("email".to_string(), json!(email));
+            claims.insert(
+                "email_verified".to_string(),
+                json!(user.email_verified_at.is_some()),
+            );
+        }
+    }
+    if has_scope(scope, "phone") {
+        if let Some(phone) = &user.phone {
+            claims.insert("phone_number".to_string(), json!(phone));
+            claims.insert(
+                "phone_number_verified".to_string(),
+                json!(user.phone_verified_at.is_some()),
+            );
+        }
+    }
+    claims
+}
+
+fn with_query(uri: &str, params: &[(&str, &str)]) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, encode_component(v)))
+        .collect();
+    let sep = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, sep, query.join("&"))
+}
+
+/// Sends an error back to the client, with the state of the request.
+fn redirect_err(params: &AuthorizeParams, error: &str) -> Response {
+    let mut query = vec![("error", error)];
+    if let Some(state) = &params.state {
+        query.push(("state", state));
+    }
+    Redirect::to(&with_query(&params.redirect_uri, &query)).into_response()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn render_page(title: &str, content: &str) -> Html<String> {
+    Html(format!(
+        concat!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\">",
+            "<title>{}</title></head><body><h1>{}</h1>{}</body></html>"
+        ),
+        escape(title),
+        escape(title),
+        content
+    ))
+}
+
+fn render_err(msg: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        render_page("Error", &format!("<p>{}</p>", escape(msg))),
+    )
+        .into_response()
+}
+
+/// Renders the login form, or the second factor form if a challenge is
+/// given.
+fn render_form(
+    client: &OauthClient,
+    params: &AuthorizeParams,
+    username: &str,
+    challenge: Option<&str>,
+    msg: Option<&str>,
+) -> Response {
+    let mut hidden = vec![
+        ("response_type", Some(&params.response_type)),
+        ("client_id", Some(&params.client_id)),
+        ("redirect_uri", Some(&params.redirect_uri)),
+        ("scope", Some(&params.scope)),
+        ("state", params.state.as_ref()),
+        ("nonce", params.nonce.as_ref()),
+        ("code_challenge", params.code_challenge.as_ref()),
+        (
+            "code_challenge_method",
+            params.code_challenge_method.as_ref(),
+        ),
+    ];
+    let challenge = challenge.map(|x| x.to_string());
+    let username = username.to_string();
+    let password = String::new();
+    let fields = match &challenge {
+        Some(_) => {
+            hidden.push(("challenge", challenge.as_ref()));
+            hidden.push(("username", Some(&username)));
+            hidden.push(("password", Some(&password)));
+            concat!(
+                "<label>Code <input name=\"code\"",
+                " autocomplete=\"one-time-code\" autofocus></label>"
+            )
+            .to_string()
+        }
+        None => format!(
+            concat!(
+                "<label>Username <input name=\"username\" value=\"{}\"",
+                " autofocus></label>",
+                "<label>Password <input name=\"password\" type=\"password\">",
+                "</label>"
+            ),
+            escape(&username)
+        ),
+    };
+    let hidden: String = hidden
+        .into_iter()
+        .filter_map(|(k, v)| {
+            v.map(|v| {
+                format!(
+                    "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                    k,
+                    escape(v)
+                )
+            })
+        })
+        .collect();
+    let msg = msg
+        .map(|x| format!("<p>{}</p>", escape(x)))
+        .unwrap_or_default();
+    let content = format!(
+        concat!(
+            "{}<form method=\"post\">{}{}",
+            "<button type=\"submit\">Sign in</button></form>"
+        ),
+        msg, hidden, fields
+    );
+    render_page(&format!("Sign in to {}", client.name), &content)
+        .into_response()
+}
+
+/// Validates the request, answering with an error page if the client cannot
+/// be trusted with a redirect, and with an error redirect otherwise.
+fn check_authorize(
+    params: &AuthorizeParams,
+    con: &mut Con,
+) -> Result<OauthClient, Box<Response>> {
+    let Ok(cfg) = cfg() else {
+        return Err(Box::new(render_err("oidc provider is not configured")));
+    };
+    let Some(client) = get_client(&params.client_id, con) else {
+        return Err(Box::new(render_err("unknown client")));
+    };
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return Err(Box::new(render_err("redirect uri is not registered")));
+    }
+    if params.response_type != "code" {
+        return Err(Box::new(redirect_err(
+            params,
+            "unsupported_response_type",
+        )));
+    }
+    if !has_scope(&params.scope, "openid") {
+        return Err(Box::new(redirect_err(params, "invalid_scope")));
+    }
+    match (
+        &params.code_challenge,
+        params.code_challenge_method.as_deref(),
+    ) {
+        (None, _) if cfg.require_pkce => {
+            return Err(Box::new(redirect_err(params, "invalid_request")))
+        }
+        // plain challenges are not supported
+        (Some(_), method) if method != Some("S256") => {
+            return Err(Box::new(redirect_err(params, "invalid_request")))
+        }
+        _ => (),
+    }
+    Ok(client)
+}
+
+/// Issues a code and sends it to the client.
+fn redirect_code(
+    client: &OauthClient,
+    params: &AuthorizeParams,
+    user_id: Id,
+    con: &mut Con,
+) -> Res<Response> {
+    let code = new_secret(32);
+    let now = utc();
+    diesel::insert_into(schema::oauth_code::table)
+        .values(&InsertOauthCode {
+            hcode: hash_code(&code),
+            created: now,
+            client_id: client.id,
+            user_id,
+            redirect_uri: params.redirect_uri.to_owned(),
+            scope: params.scope.to_owned(),
+            nonce: params.nonce.to_owned(),
+            code_challenge: params.code_challenge.to_owned(),
+            expires: now + cfg()?.code_ttl,
+        })
+        .execute(con)?;
+    let mut query = vec![("code", code.as_str())];
+    if let Some(state) = &params.state {
+        query.push(("state", state));
+    }
+    Ok(
+        Redirect::to(&with_query(&params.redirect_uri, &query))
+            .into_response(),
+    )
+}
+
+pub async fn discovery() -> Response {
+    let Ok(cfg) = cfg() else {
+        return OauthErr {
+            status: StatusCode::NOT_FOUND,
+            error: "invalid_request",
+            description: "oidc provider is not configured".to_string(),
+        }
+        .into_response();
+    };
+    let issuer = cfg.issuer.trim_end_matches('/');
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": issuer.to_string() + "/oauth/authorize",
+        "token_endpoint": issuer.to_string() + "/oauth/token",
+        "userinfo_endpoint": issuer.to_string() + "/oauth/userinfo",
+        "jwks_uri": issuer.to_string() + "/oauth/jwks",
+        "response_types_supported": ["code"],
+        "grant_types_supported":
+            ["authorization_code", "client_credentials"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["ES256"],
+        "scopes_supported": ["openid", "profile", "email", "phone"],
+        "token_endpoint_auth_methods_supported":
+            ["client_secret_basic", "client_secret_post"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": [
+            "sub", "preferred_username", "given_name", "middle_name",
+            "family_name", "updated_at", "email", "email_verified",
+            "phone_number", "phone_number_verified"
+        ]
+    }))
+    .into_response()
+}
+
+lazy_static::lazy_static! {
+    static ref SIGNING_KEY: Option<SigningKey> = get_signing_key();
+}
+
+fn get_signing_key() -> Option<SigningKey> {
+    let file = APPRC.oidc.as_ref()?.signing_key_file.as_ref()?;
+    let pem =
+        std::fs::read_to_string(path::cwd().unwrap().join(file)).unwrap();
+    Some(SigningKey::from_pkcs8_pem(&pem).unwrap())
+}
+
+fn signing_key() -> Res<&'static SigningKey> {
+    match &*SIGNING_KEY {
+        Some(key) => Ok(key),
+        None => err::res_msg("oidc signing key is not configured"),
+    }
+}
+
+/// Id of the signing key, the start of it's public key digest.
+fn get_kid(key: &SigningKey) -> String {
+    let point = key.verifying_key().to_encoded_point(false);
+    to_hex(&Sha256::digest(point.as_bytes())[..8])
+}
+
+/// Signs an ID token by ES256 with the configured key, see `jwks`.
+fn new_id_token(payload: &IdTokenPayload) -> Res<String> {
+    let key = signing_key()?;
+    let header = json!({"alg": "ES256", "typ": "JWT", "kid": get_kid(key)});
+    let input = format!(
+        "{}.{}",
+        BASE64URL_NOPAD.encode(header.to_string().as_bytes()),
+        BASE64URL_NOPAD.encode(&serde_json::to_vec(payload).unwrap())
+    );
+    let signature: Signature = key.sign(input.as_bytes());
+    Ok(format!(
+        "{}.{}",
+        input,
+        BASE64URL_NOPAD.encode(&signature.to_bytes())
+    ))
+}
+
+/// Public key ID tokens are verified with, as a JSON Web Key Set.
+pub async fn jwks() -> Json<Value> {
+    let Ok(key) = signing_key() else {
+        return Json(json!({"keys": []}));
+    };
+    let point = key.verifying_key().to_encoded_point(false);
+    Json(json!({"keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "use": "sig",
+        "alg": "ES256",
+        "kid": get_kid(key),
+        "x": BASE64URL_NOPAD.encode(point.x().unwrap()),
+        "y": BASE64URL_NOPAD.encode(point.y().unwrap()),
+    }]}))
+}
+
+pub async fn authorize_page(
+    Query(params): Query<AuthorizeParams>,
+) -> Response {
+    let con = &mut db::con().unwrap();
+    match check_authorize(&params, con) {
+        Ok(client) => render_form(&client, &params, "", None, None),
+        Err(res) => *res,
+    }
+}
+
+/// Writes a login event for the client, failing to write it doesn't fail the
+/// login page.
+fn record_login<T>(
+    client: &OauthClient,
+    origin: &Origin,
+    user_id: Option<Id>,
+    res: &Res<T>,
+    con: &mut Con,
+) {
+    if let Err(e) = auth_event::record(
+        AuthEventKind::Login,
+        user_id,
+        Some(client.client_id.to_owned()),
+        origin,
+        res,
+        con,
+    ) {
+        warn!("cannot record oauth login: {:?}", e);
+    }
+}
+
+/// Checks the login form and redirects back to the client with a code.
+///
+/// Users with TOTP enter a code on the second step, users which only have
+/// passkeys or must enroll first cannot sign in here.
+pub async fn authorize(
+    origin: Origin,
+    Form(form): Form<AuthorizeForm>,
+) -> Response {
+    let con = &mut db::con().unwrap();
+    let params = &form.params;
+    let client = match check_authorize(params, con) {
+        Ok(client) => client,
+        Err(res) => return *res,
+    };
+    let form_err = |challenge: Option<&str>, msg: &str| {
+        render_form(&client, params, &form.username, challenge, Some(msg))
+    };
+    let record = |user_id: Option<Id>, res: &Res<()>, con: &mut Con| {
+        record_login(&client, &origin, user_id, res, con)
+    };
+
+    let user_id = match (&form.challenge, &form.code) {
+        (Some(challenge), Some(code)) => {
+            let user_id = match mfa::verify_challenge(challenge, con) {
+                Ok(user_id) => user_id,
+                Err(e) => {
+                    record(None, &Err(e), con);
+                    return form_err(None, "Sign in again");
+                }
+            };
+            if let Err(e) = mfa::check(user_id, code, con) {
+                record(Some(user_id), &Err(e), con);
+                return form_err(Some(challenge), "Incorrect code");
+            }
+            if let Err(e) = mfa::consume_challenge(challenge, con) {
+                record(Some(user_id), &Err(e), con);
+                return form_err(None, "Sign in again");
+            }
+            user_id
+        }
+        _ => {
+            let login = Login {
+                username: form.username.to_owned(),
+                password: form.password.to_owned(),
+            };
+            let user = match check_login(&login, con) {
+                Ok(user) => user,
+                Err(e) => {
+                    // failed attempts of existing users are attributed to
+                    // them
+                    let user_id = user::get_by_login(&login.username, con)
+                        .ok()
+                        .map(|x| x.0.id);
+                    record(user_id, &Err(e), con);
+                    return form_err(None, "Incorrect username or password");
+                }
+            };
+            let Ok(status) = mfa::get_status(user.id, con) else {
+                return render_err("Cannot sign in, try again later");
+            };
+            if status.enabled || status.required {
+                record(
+                    Some(user.id),
+                    &err::res("mfa_required", "second factor is required"),
+                    con,
+                );
+            }
+            if status.required && !status.enabled {
+                return render_err("Set up two-factor authentication first");
+            }
+            if status.enabled {
+                if !status.totp {
+                    return render_err("Sign in with a passkey instead");
+                }
+                let challenge = match mfa::new_challenge(user.id, con) {
+                    Ok(challenge) => challenge,
+                    Err(_) => {
+                        return render_err("Cannot sign in, try again later")
+                    }
+                };
+                return render_form(
+                    &client,
+                    params,
+                    &form.username,
+                    Some(&challenge),
+                    None,
+                );
+            }
+            user.id
+        }
+    };
+    let res = redirect_code(&client, params, user_id, con);
+    record_login(&client, &origin, Some(user_id), &res, con);
+    match res {
+        Ok(res) => res,
+        Err(_) => render_err("Cannot sign in, try again later"),
+    }
+}
+
+/// Compares secrets in constant time, so timing doesn't tell how much of the
+/// secret is guessed.
+fn secrets_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes())
+        .iter()
+        .zip(Sha256::digest(b.as_bytes()).iter())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Authenticates the client by HTTP basic auth or by form fields.
+fn auth_client(
+    headers: &HeaderMap,
+    form: &TokenForm,
+    con: &mut Con,
+) -> Result<OauthClient, OauthErr> {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Basic "))
+        .and_then(|x| BASE64.decode(x.as_bytes()).ok())
+        .and_then(|x| String::from_utf8(x).ok());
+    let (client_id, secret) = match (&basic, &form.client_id) {
+        (Some(basic), _) => match basic.split_once(':') {
+            Some((id, secret)) => (id.to_string(), secret.to_string()),
+            None => (basic.to_owned(), String::new()),
+        },
+        (None, Some(client_id)) => (
+            client_id.to_owned(),
+            form.client_secret.to_owned().unwrap_or_default(),
+        ),
+        (None, None) => ("".to_string(), "".to_string()),
+    };
+    match get_client_row(&client_id, con) {
+        Some(client) if secrets_eq(&client.hsecret, &hash_code(&secret)) => {
+            Ok(client.to_msg())
+        }
+        _ => Err(OauthErr {
+            status: StatusCode::UNAUTHORIZED,
+            error: "invalid_client",
+            description: "invalid client credentials".to_string(),
+        }),
+    }
+}
+
+fn exchange_code(
+    cfg: &OidcCfg,
+    client: &OauthClient,
+    form: &TokenForm,
+    con: &mut Con,
+) -> Result<Value, OauthErr> {
+    let (Some(code), Some(redirect_uri)) = (&form.code, &form.redirect_uri)
+    else {
+        return Err(OauthErr::new(
+            "invalid_request",
+            "missing code or redirect uri",
+        ));
+    };
+    // codes are single-use, so they're taken even if the exchange fails
+    let Some(code) = diesel::delete(
+        schema::oauth_code::table
+            .filter(schema::oauth_code::hcode.eq(hash_code(code))),
+    )
+    .returning(OauthCodeTable::as_returning())
+    .get_result(con)
+    .optional()
+    .map_err(|x| OauthErr::server(x.into()))?
+    else {
+        return Err(OauthErr::new("invalid_grant", "no such code"));
+    };
+    if code.expires < utc()
+        || code.client_id != client.id
+        || &code.redirect_uri != redirect_uri
+    {
+        return Err(OauthErr::new("invalid_grant", "invalid code"));
+    }
+    if let Some(challenge) = &code.code_challenge {
+        let Some(verifier) = &form.code_verifier else {
+            return Err(OauthErr::new("invalid_grant", "missing verifier"));
+        };
+        let hash = BASE64URL_NOPAD.encode(&Sha256::digest(verifier));
+        if &hash != challenge {
+            return Err(OauthErr::new("invalid_grant", "invalid verifier"));
+        }
+    }
+    let Ok(user) = user::get_by_id(code.user_id, con) else {
+        return Err(OauthErr::new("invalid_grant", "no such user"));
+    };
+
+    let now = utc();
+    let id_token = IdTokenPayload {
+        iss: cfg.issuer.trim_end_matches('/').to_string(),
+        sub: user.id.to_string(),
+        aud: client.client_id.to_owned(),
+        exp: (now + cfg.at_ttl) as i64,
+        iat: now as i64,
+        nonce: code.nonce,
+        claims: get_claims(&user, &code.scope),
+    };
+    let id_token = new_id_token(&id_token).map_err(OauthErr::server)?;
+    let at = token::new_oauth_at(user.id, &client.client_id, &code.scope)
+        .map_err(OauthErr::server)?;
+    Ok(json!({
+        "access_token": at,
+        "token_type": "Bearer",
+        "expires_in": cfg.at_ttl as i64,
+        "scope": code.scope,
+        "id_token": id_token
+    }))
+}
+
+/// Issues a service token for the requested scopes, which must all be
+/// granted to the client.
+fn grant_client_credentials(
+    cfg: &OidcCfg,
+    client: &OauthClient,
+    form: &TokenForm,
+) -> Result<Value, OauthErr> {
+    if client.scopes.is_empty() {
+        return Err(OauthErr::new(
+            "unauthorized_client",
+            "client has no server scopes",
+        ));
+    }
+    let scope = match &form.scope {
+        Some(scope) => {
+            if scope
+                .split(' ')
+                .any(|x| !client.scopes.iter().any(|y| x == y))
+            {
+                return Err(OauthErr::new(
+                    "invalid_scope",
+                    "scope is not granted to the client",
+                ));
+            }
+            scope.to_owned()
+        }
+        None => client.scopes.join(" "),
+    };
+    Ok(json!({
+        "access_token":
+            token::new_service_at(&client.client_id, &scope).unwrap(),
+        "token_type": "Bearer",
+        "expires_in": cfg.service_at_ttl as i64,
+        "scope": scope,
+    }))
+}
+
+fn exchange(
+    headers: &HeaderMap,
+    form: &TokenForm,
+    con: &mut Con,
+) -> Result<Value, OauthErr> {
+    let Ok(cfg) = cfg() else {
+        return Err(OauthErr::new(
+            "invalid_request",
+            "oidc is not configured",
+        ));
+    };
+    let client = auth_client(headers, form, con)?;
+    match form.grant_type.as_str() {
+        "authorization_code" => exchange_code(cfg, &client, form, con),
+        "client_credentials" => grant_client_credentials(cfg, &client, form),
+        _ => Err(OauthErr::new(
+            "unsupported_grant_type",
+            "only authorization_code and client_credentials are supported",
+        )),
+    }
+}
+
+/// Exchanges a code for an access token and an ID token, or client
+/// credentials for a service token.
+pub async fn token(
+    headers: HeaderMap,
+    Form(form): Form<TokenForm>,
+) -> Response {
+    let con = &mut db::con().unwrap();
+    match exchange(&headers, &form, con) {
+        Ok(res) => {
+            ([(header::CACHE_CONTROL, "no-store")], Json(res)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn userinfo(headers: HeaderMap) -> Response {
+    let invalid = || {
+        OauthErr {
+            status: StatusCode::UNAUTHORIZED,
+            error: "invalid_token",
+            description: "invalid access token".to_string(),
+        }
+        .into_response()
+    };
+    let Ok(cfg) = cfg() else {
+        return invalid();
+    };
+    let Some(at) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+    else {
+        return invalid();
+    };
+    let Ok(claims) = token::verify_oauth_at(at, cfg.at_ttl) else {
+        return invalid();
+    };
+    let con = &mut db::con().unwrap();
+    let Ok(user) = user::get_by_id(claims.user_id, con) else {
+        return invalid();
+    };
+    let mut info = get_claims(&user, &claims.scope);
+    info.insert("sub".to_string(), json!(user.id.to_string()));
+    Json(info).into_response()
+}
+
+/// Verifies a service token and checks the scope is granted both by the
+/// token and by the client at the moment, so deleted clients and revoked
+/// scopes take effect before the token expires.
+pub fn verify_service_token(
+    at: &str,
+    scope: &str,
+    con: &mut Con,
+) -> Res<token::ServiceTokenPayload> {
+    let claims = token::verify_service_at(at, cfg()?.service_at_ttl)?;
+    let Some(client) = get_client(&claims.client_id, con) else {
+        return err::res("invalid_token", "client no longer exists");
+    };
+    if !has_scope(&claims.scope, scope)
+        || !client.scopes.iter().any(|x| x == scope)
+    {
+        return err::res(
+            "insufficient_scope",
+            format!("token lacks scope {}", scope).as_str(),
+        );
+    }
+    Ok(claims)
+}
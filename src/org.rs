@@ -0,0 +1,329 @@
+use diesel::{dsl::not, pg::Pg, prelude::*};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::{Con, Id},
+    quco::{col_cond, BoxedCond, Collection, Op},
+    ryz::{
+        enm::StrEnum,
+        err,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema,
+    user_change::{self, ChangeAction, NewUserChange},
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MemberRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl StrEnum for MemberRole {
+    fn to_str(&self) -> &str {
+        match self {
+            MemberRole::Owner => "owner",
+            MemberRole::Admin => "admin",
+            MemberRole::Member => "member",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "owner" => Ok(MemberRole::Owner),
+            "admin" => Ok(MemberRole::Admin),
+            "member" => Ok(MemberRole::Member),
+            _ => err::res_default(),
+        }
+    }
+}
+
+/// Organisation, or tenant, grouping users.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Org {
+    pub id: Id,
+    pub created: Time,
+    pub name: String,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::organisation)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrgTable {
+    pub id: Id,
+    pub created: Time,
+    pub name: String,
+}
+
+impl Collection<Org> for OrgTable {
+    fn to_msg(&self) -> Org {
+        Org {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            name: self.name.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Membership {
+    pub org_id: Id,
+    pub user_id: Id,
+    pub role: MemberRole,
+    pub created: Time,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::membership)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MembershipTable {
+    pub org_id: Id,
+    pub user_id: Id,
+    pub role: String,
+    pub created: Time,
+}
+
+impl Collection<Membership> for MembershipTable {
+    fn to_msg(&self) -> Membership {
+        Membership {
+            org_id: self.org_id.to_owned(),
+            user_id: self.user_id.to_owned(),
+            role: MemberRole::from_str(self.role.as_str()).unwrap(),
+            created: self.created.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::organisation)]
+struct InsertOrg {
+    created: Time,
+    name: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::membership)]
+struct InsertMembership {
+    org_id: Id,
+    user_id: Id,
+    role: String,
+    created: Time,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewOrg {
+    pub name: String,
+    /// User to become the first owner.
+    #[serde(default)]
+    pub owner_id: Option<Id>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetMember {
+    pub org_id: Id,
+    pub user_id: Id,
+    pub role: MemberRole,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DelMember {
+    pub org_id: Id,
+    pub user_id: Id,
+}
+
+/// Writes the `Membership` change, with the new role or null if the user
+/// left.
+fn new_change(
+    org_id: Id,
+    user_id: Id,
+    role: Option<MemberRole>,
+    con: &mut Con,
+) -> Res<()> {
+    user_change::new(
+        &NewUserChange {
+            user_id,
+            action: ChangeAction::Membership,
+            data: Some(json!({"org_id": org_id, "role": role})),
+        },
+        con,
+    )?;
+    Ok(())
+}
+
+pub fn new(inp: &NewOrg, con: &mut Con) -> Res<Org> {
+    if inp.name.is_empty() {
+        return err::res_msg("organisation name cannot be empty");
+    }
+    con.transaction(|con| {
+        let org: OrgTable = diesel::insert_into(schema::organisation::table)
+            .values(&InsertOrg {
+                created: utc(),
+                name: inp.name.to_owned(),
+            })
+            .returning(OrgTable::as_returning())
+            .get_result(con)?;
+        if let Some(owner_id) = inp.owner_id {
+            set_member(
+                &SetMember {
+                    org_id: org.id,
+                    user_id: owner_id,
+                    role: MemberRole::Owner,
+                },
+                con,
+            )?;
+        }
+        Ok(org.to_msg())
+    })
+}
+
+/// Deletes the organisation, each member gets a `Membership` change.
+pub fn del(id: Id, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        let user_ids: Vec<Id> = schema::membership::table
+            .filter(schema::membership::org_id.eq(id))
+            .select(schema::membership::user_id)
+            .load(con)?;
+        diesel::delete(
+            schema::organisation::table
+                .filter(schema::organisation::id.eq(id)),
+        )
+        .execute(con)?;
+        for user_id in user_ids {
+            new_change(id, user_id, None, con)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn get_many(con: &mut Con) -> Res<Vec<Org>> {
+    Ok(schema::organisation::table
+        .order(schema::organisation::id.asc())
+        .select(OrgTable::as_select())
+        .load(con)?
+        .iter()
+        .map(|x| x.to_msg())
+        .collect())
+}
+
+pub fn get_members(org_id: Id, con: &mut Con) -> Res<Vec<Membership>> {
+    Ok(schema::membership::table
+        .filter(schema::membership::org_id.eq(org_id))
+        .order(schema::membership::user_id.asc())
+        .select(MembershipTable::as_select())
+        .load(con)?
+        .iter()
+        .map(|x| x.to_msg())
+        .collect())
+}
+
+pub fn get_membership(
+    org_id: Id,
+    user_id: Id,
+    con: &mut Con,
+) -> Res<Option<Membership>> {
+    Ok(schema::membership::table
+        .filter(schema::membership::org_id.eq(org_id))
+        .filter(schema::membership::user_id.eq(user_id))
+        .select(MembershipTable::as_select())
+        .first(con)
+        .optional()?
+        .map(|x| x.to_msg()))
+}
+
+/// Fails if the user is the only owner of the organisation, which would be
+/// left without one.
+fn check_not_last_owner(org_id: Id, user_id: Id, con: &mut Con) -> Res<()> {
+    let owners: Vec<Id> = schema::membership::table
+        .filter(schema::membership::org_id.eq(org_id))
+        .filter(schema::membership::role.eq(MemberRole::Owner.to_str()))
+        .select(schema::membership::user_id)
+        .for_update()
+        .load(con)?;
+    if owners == vec![user_id] {
+        return err::res("last_owner", "organisation must keep an owner");
+    }
+    Ok(())
+}
+
+/// Adds the user to the organisation or changes their role.
+pub fn set_member(inp: &SetMember, con: &mut Con) -> Res<Membership> {
+    con.transaction(|con| {
+        if let Some(current) = get_membership(inp.org_id, inp.user_id, con)? {
+            if current.role == inp.role {
+                return Ok(current);
+            }
+            if current.role == MemberRole::Owner {
+                check_not_last_owner(inp.org_id, inp.user_id, con)?;
+            }
+        }
+        let membership: MembershipTable =
+            diesel::insert_into(schema::membership::table)
+                .values(&InsertMembership {
+                    org_id: inp.org_id,
+                    user_id: inp.user_id,
+                    role: inp.role.to_str().to_string(),
+                    created: utc(),
+                })
+                .on_conflict((
+                    schema::membership::org_id,
+                    schema::membership::user_id,
+                ))
+                .do_update()
+                .set(schema::membership::role.eq(inp.role.to_str()))
+                .returning(MembershipTable::as_returning())
+                .get_result(con)?;
+        new_change(inp.org_id, inp.user_id, Some(inp.role), con)?;
+        Ok(membership.to_msg())
+    })
+}
+
+pub fn del_member(inp: &DelMember, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        check_not_last_owner(inp.org_id, inp.user_id, con)?;
+        let deleted = diesel::delete(
+            schema::membership::table
+                .filter(schema::membership::org_id.eq(inp.org_id))
+                .filter(schema::membership::user_id.eq(inp.user_id)),
+        )
+        .execute(con)?;
+        if deleted > 0 {
+            new_change(inp.org_id, inp.user_id, None, con)?;
+        }
+        Ok(())
+    })
+}
+
+/// Matches users by organisations they're members of, e.g. `{"org_id": 1}`
+/// finds members of the organisation.
+///
+/// Negative operators find users who aren't members of the organisations,
+/// and `$exists` tells whether the user is a member of any.
+pub fn member_cond(op: &Op) -> BoxedCond<schema::appuser::table> {
+    let members = |op: &Op| {
+        schema::membership::table
+            .into_boxed::<Pg>()
+            .filter(col_cond!(schema::membership::org_id, op, as_int))
+            .select(schema::membership::user_id)
+    };
+    let all = || schema::membership::table.select(schema::membership::user_id);
+    match op {
+        Op::Ne(v) => Box::new(
+            not(schema::appuser::id.eq_any(members(&Op::Eq(v.clone()))))
+                .nullable(),
+        ),
+        Op::Nin(vs) => Box::new(
+            not(schema::appuser::id.eq_any(members(&Op::In(vs.clone()))))
+                .nullable(),
+        ),
+        Op::Exists(true) => {
+            Box::new(schema::appuser::id.eq_any(all()).nullable())
+        }
+        Op::Exists(false) => {
+            Box::new(not(schema::appuser::id.eq_any(all())).nullable())
+        }
+        op => Box::new(schema::appuser::id.eq_any(members(op)).nullable()),
+    }
+}
@@ -1,23 +1,314 @@
+use std::{
+    collections::HashSet,
+    fs, panic,
+    time::{Duration, Instant},
+};
+
 use argon2::{
     password_hash::{PasswordHasher, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version,
 };
+use pbkdf2::Pbkdf2;
 use rand_core::OsRng;
+use scrypt::Scrypt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::ryz::res::Res;
+use crate::{
+    ryz::{err, path, res::Res},
+    PasswordCfg, APPRC,
+};
+
+lazy_static::lazy_static! {
+    static ref BREACHED: HashSet<String> = get_breached();
+}
+
+fn get_breached() -> HashSet<String> {
+    let Some(file) = &APPRC.password_policy.breached_file else {
+        return HashSet::new();
+    };
+    fs::read_to_string(path::cwd().unwrap().join(file))
+        .unwrap()
+        .lines()
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn algorithm(cfg: &PasswordCfg) -> Algorithm {
+    Algorithm::new(cfg.algorithm.as_str()).unwrap()
+}
+
+fn params(cfg: &PasswordCfg) -> Params {
+    Params::new(cfg.m_cost, cfg.t_cost, cfg.p_cost, None).unwrap()
+}
+
+/// Argon2 hasher with the configured parameters and pepper.
+fn argon2() -> Argon2<'static> {
+    argon2_with(params(&APPRC.password))
+}
+
+/// Argon2 hasher with the configured algorithm and pepper, but the given
+/// parameters.
+fn argon2_with(params: Params) -> Argon2<'static> {
+    let cfg = &APPRC.password;
+    match &cfg.pepper {
+        Some(pepper) => Argon2::new_with_secret(
+            pepper.as_bytes(),
+            algorithm(cfg),
+            Version::V0x13,
+            params,
+        )
+        .unwrap(),
+        None => Argon2::new(algorithm(cfg), Version::V0x13, params),
+    }
+}
 
 pub fn hash_password(password: &String) -> Res<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    Ok(argon2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string())
+    match argon2().hash_password(password.as_bytes(), &salt) {
+        Ok(x) => Ok(x.to_string()),
+        Err(_) => err::res_msg("cannot hash password"),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Check {
+    Mismatch,
+    Match,
+    /// Password matches, but the hash should be replaced by a fresh one from
+    /// `hash_password`.
+    Outdated,
+}
+
+/// Checks a password against a hash of any supported algorithm, see
+/// `detect_hash`.
+///
+/// Hashes of legacy algorithms, argon2 hashes with weaker than configured
+/// parameters and argon2 hashes made before the pepper was configured are
+/// reported as outdated. Unsupported or malformed hashes never match, even
+/// if their verification panics, since imported hashes are not trusted.
+pub fn check_password(password: &str, hpassword: &str) -> Check {
+    let Some(kind) = detect_hash(hpassword) else {
+        return Check::Mismatch;
+    };
+    panic::catch_unwind(|| verify(password.as_bytes(), hpassword, kind))
+        .unwrap_or(Check::Mismatch)
+}
+
+fn verify(password: &[u8], hpassword: &str, kind: HashKind) -> Check {
+    let ok = if kind == HashKind::Bcrypt {
+        bcrypt::verify(password, hpassword).unwrap_or(false)
+    } else {
+        let Ok(parsed_hash) = PasswordHash::new(hpassword) else {
+            return Check::Mismatch;
+        };
+        match kind {
+            HashKind::Argon2 => {
+                if argon2().verify_password(password, &parsed_hash).is_ok() {
+                    return if is_weak(&parsed_hash) {
+                        Check::Outdated
+                    } else {
+                        Check::Match
+                    };
+                }
+                // hashes made before the pepper was configured
+                APPRC.password.pepper.is_some()
+                    && Argon2::default()
+                        .verify_password(password, &parsed_hash)
+                        .is_ok()
+            }
+            HashKind::Pbkdf2Sha256 => {
+                Pbkdf2.verify_password(password, &parsed_hash).is_ok()
+            }
+            HashKind::Scrypt => {
+                Scrypt.verify_password(password, &parsed_hash).is_ok()
+            }
+            HashKind::Bcrypt => unreachable!(),
+        }
+    };
+    if ok {
+        Check::Outdated
+    } else {
+        Check::Mismatch
+    }
+}
+
+/// Whether an argon2 hash is made by other variant or with lower costs than
+/// configured.
+fn is_weak(parsed_hash: &PasswordHash) -> bool {
+    let cfg = &APPRC.password;
+    let Ok(hash_params) = Params::try_from(parsed_hash) else {
+        return true;
+    };
+    parsed_hash.algorithm != algorithm(cfg).ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || hash_params.m_cost() < cfg.m_cost
+        || hash_params.t_cost() < cfg.t_cost
+        || hash_params.p_cost() < cfg.p_cost
+}
+
+/// Suggests argon2 parameters for which hashing with the configured
+/// algorithm and pepper on this host takes about the target time.
+///
+/// Memory cost is doubled first, up to 1 GiB, then time cost is increased.
+/// Parallelism is set to the amount of available cores. The benchmarked
+/// algorithm is returned along with the parameters.
+pub fn bench(target: Duration) -> (Algorithm, Params) {
+    let p_cost =
+        std::thread::available_parallelism().map_or(1, |x| x.get() as u32);
+    let salt = SaltString::generate(&mut OsRng);
+    let measure = |params: &Params| {
+        let argon2 = argon2_with(params.clone());
+        let start = Instant::now();
+        argon2.hash_password(b"benchmark", &salt).unwrap();
+        start.elapsed()
+    };
+
+    let mut m_cost = Params::DEFAULT_M_COST;
+    let mut t_cost = Params::DEFAULT_T_COST;
+    loop {
+        let params = Params::new(m_cost, t_cost, p_cost, None).unwrap();
+        if measure(&params) >= target {
+            return (algorithm(&APPRC.password), params);
+        }
+        if m_cost < 1024 * 1024 {
+            m_cost *= 2;
+        } else {
+            t_cost += 1;
+        }
+    }
+}
+
+/// Algorithm of a stored password hash.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HashKind {
+    Argon2,
+    Bcrypt,
+    Pbkdf2Sha256,
+    Scrypt,
+}
+
+fn is_bcrypt(hpassword: &str) -> bool {
+    // $2b$<cost>$<22 chars of salt><31 chars of hash>
+    let parts: Vec<&str> = hpassword.split('$').collect();
+    parts.len() == 4
+        && parts[0].is_empty()
+        && matches!(parts[1], "2a" | "2b" | "2y")
+        && parts[2].len() == 2
+        && parts[2].chars().all(|x| x.is_ascii_digit())
+        && parts[3].len() == 53
+        && parts[3]
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '.' || x == '/')
+}
+
+/// Detects the algorithm of a hash, if it is supported.
+///
+/// Argon2, PBKDF2 and scrypt hashes are expected in PHC string format, e.g.
+/// `$pbkdf2-sha256$i=600000,l=32$<salt>$<hash>`, bcrypt hashes in their
+/// modular crypt format.
+pub fn detect_hash(hpassword: &str) -> Option<HashKind> {
+    if is_bcrypt(hpassword) {
+        return Some(HashKind::Bcrypt);
+    }
+    let parsed = PasswordHash::new(hpassword).ok()?;
+    parsed.hash?;
+    match parsed.algorithm.as_str() {
+        "argon2id" | "argon2i" | "argon2d" => Some(HashKind::Argon2),
+        "pbkdf2-sha256" => Some(HashKind::Pbkdf2Sha256),
+        "scrypt" => Some(HashKind::Scrypt),
+        _ => None,
+    }
+}
+
+/// Violated rule of the password policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Violation {
+    pub rule: String,
+    pub msg: String,
+}
+
+/// Lists every rule of the configured password policy the password violates.
+pub fn get_violations(username: &str, password: &str) -> Vec<Violation> {
+    let policy = &APPRC.password_policy;
+    let mut violations = vec![];
+    let mut violate = |rule: &str, msg: String| {
+        violations.push(Violation {
+            rule: rule.to_string(),
+            msg,
+        })
+    };
+
+    let len = password.chars().count();
+    if len < policy.min_len {
+        violate(
+            "min_len",
+            format!("must be at least {} characters", policy.min_len),
+        );
+    }
+    if len > policy.max_len {
+        violate(
+            "max_len",
+            format!("must be at most {} characters", policy.max_len),
+        );
+    }
+    let has =
+        |is_class: fn(&char) -> bool| password.chars().any(|x| is_class(&x));
+    if policy.require_lower && !has(|x| x.is_lowercase()) {
+        violate(
+            "require_lower",
+            "must contain a lowercase letter".to_string(),
+        );
+    }
+    if policy.require_upper && !has(|x| x.is_uppercase()) {
+        violate(
+            "require_upper",
+            "must contain an uppercase letter".to_string(),
+        );
+    }
+    if policy.require_digit && !has(|x| x.is_numeric()) {
+        violate("require_digit", "must contain a digit".to_string());
+    }
+    if policy.require_symbol && !has(|x| !x.is_alphanumeric()) {
+        violate("require_symbol", "must contain a symbol".to_string());
+    }
+    if policy.forbid_username
+        && !username.is_empty()
+        && password.to_lowercase().contains(&username.to_lowercase())
+    {
+        violate("forbid_username", "must not contain username".to_string());
+    }
+    if policy.min_score > 0 {
+        let score: u8 = zxcvbn::zxcvbn(password, &[username]).score().into();
+        if score < policy.min_score {
+            violate(
+                "min_score",
+                format!(
+                    "must have strength score at least {}, got {}",
+                    policy.min_score, score
+                ),
+            );
+        }
+    }
+    if BREACHED.contains(&password.to_lowercase()) {
+        violate("breached", "is known to be breached".to_string());
+    }
+    violations
 }
 
-pub fn check_password(password: &String, hpassword: &String) -> bool {
-    let parsed_hash = PasswordHash::new(&hpassword).unwrap();
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+/// Checks a password against the configured policy.
+///
+/// The error lists all violated rules in `data.violations`.
+pub fn check_policy(username: &str, password: &str) -> Res<()> {
+    let violations = get_violations(username, password);
+    if violations.is_empty() {
+        return Ok(());
+    }
+    err::res_data(
+        "password_policy",
+        "password violates policy",
+        json!({ "violations": violations }),
+    )
 }
@@ -1,6 +1,979 @@
-use crate::ryz::dict;
+use diesel::{
+    dsl::sql,
+    expression::{expression_types::NotSelectable, SqlLiteral},
+    pg::Pg,
+    query_dsl::methods::{FilterDsl, LimitDsl, OffsetDsl, ThenOrderDsl},
+    sql_types::{Bool, Jsonb, Nullable},
+    BoolExpressionMethods, BoxableExpression,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    db::Con,
+    ryz::{dict, err, res::Res},
+    APPRC,
+};
 
 pub trait Collection<T> {
     fn to_msg(&self) -> T;
 }
 pub type Query = dict::dict<String, serde_json::Value>;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FieldType {
+    Int,
+    Float,
+    Str,
+    /// String restricted to the given values.
+    Enum(&'static [&'static str]),
+    /// JSON object, queried by paths into it, e.g. `"field.key.nested"`.
+    /// Values at paths are scalars.
+    Json,
+}
+
+/// Queryable field of a collection.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub typ: FieldType,
+    pub nullable: bool,
+    /// Derived fields are computed from other columns and are not a part of
+    /// collection messages, so they cannot be projected.
+    pub derived: bool,
+    /// Whether rows can be ordered by the field.
+    pub sortable: bool,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, typ: FieldType) -> Self {
+        Self {
+            name,
+            typ,
+            nullable: false,
+            derived: false,
+            sortable: true,
+        }
+    }
+
+    pub const fn nullable(self) -> Self {
+        Self {
+            nullable: true,
+            ..self
+        }
+    }
+
+    pub const fn derived(self) -> Self {
+        Self {
+            derived: true,
+            ..self
+        }
+    }
+
+    pub const fn unsortable(self) -> Self {
+        Self {
+            sortable: false,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Val {
+    Int(i32),
+    Float(f64),
+    Str(String),
+    Json(Value),
+}
+
+impl Val {
+    /// Panics if the value is not an int, which is impossible for a value
+    /// parsed for an int field.
+    pub fn as_int(&self) -> i32 {
+        match self {
+            Val::Int(v) => *v,
+            _ => panic!("expected int query value"),
+        }
+    }
+
+    pub fn as_float(&self) -> f64 {
+        match self {
+            Val::Float(v) => *v,
+            _ => panic!("expected float query value"),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Val::Str(v) => v.as_str(),
+            _ => panic!("expected string query value"),
+        }
+    }
+
+    pub fn as_json(&self) -> &Value {
+        match self {
+            Val::Json(v) => v,
+            _ => panic!("expected json query value"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    Eq(Val),
+    /// Also matches nulls.
+    Ne(Val),
+    In(Vec<Val>),
+    /// Also matches nulls.
+    Nin(Vec<Val>),
+    Gt(Val),
+    Gte(Val),
+    Lt(Val),
+    Lte(Val),
+    Like(String),
+    Ilike(String),
+    Exists(bool),
+}
+
+/// Validated query.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Cond {
+    Field(&'static str, Op),
+    /// Operator over the value at the path of a JSON field.
+    Path(&'static str, Vec<String>, Op),
+    And(Vec<Cond>),
+    Or(Vec<Cond>),
+}
+
+fn query_err<T>(msg: String) -> Res<T> {
+    err::res("query_err", msg.as_str())
+}
+
+/// Parses a search query into a validated condition tree.
+///
+/// Each key of the query is either a field name or one of `$and`/`$or`
+/// operators, which accept an array of nested queries. A field is matched
+/// either directly `{"field": value}` or by operators
+/// `{"field": {"$in": [...], "$ne": value}}`. Supported field operators
+/// are `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`, `$like`,
+/// `$ilike` and `$exists`.
+///
+/// JSON fields are matched by paths only, `{"field.key": value}`, where
+/// missing keys are treated as nulls.
+///
+/// All keys of a query are joined by `and`.
+pub fn parse(sq: &Query, fields: &[Field]) -> Res<Cond> {
+    let mut conds = vec![];
+    for (k, v) in sq {
+        conds.push(parse_entry(k, v, fields)?);
+    }
+    Ok(Cond::And(conds))
+}
+
+fn parse_entry(k: &str, v: &Value, fields: &[Field]) -> Res<Cond> {
+    match k {
+        "$and" | "$or" => {
+            let Some(items) = v.as_array() else {
+                return query_err(format!(
+                    "{} expects an array of queries",
+                    k
+                ));
+            };
+            let mut conds = vec![];
+            for item in items {
+                let Ok(sq) = serde_json::from_value::<Query>(item.clone())
+                else {
+                    return query_err(format!(
+                        "{} expects an array of queries",
+                        k
+                    ));
+                };
+                conds.push(parse(&sq, fields)?);
+            }
+            if k == "$and" {
+                Ok(Cond::And(conds))
+            } else {
+                Ok(Cond::Or(conds))
+            }
+        }
+        _ => {
+            let (name, path) = match k.split_once('.') {
+                Some((name, path)) => (name, Some(path)),
+                None => (k, None),
+            };
+            let Some(field) = fields.iter().find(|x| x.name == name) else {
+                return query_err(format!("unknown field {}", k));
+            };
+            let new_cond: Box<dyn Fn(Op) -> Cond> = match (field.typ, path) {
+                (FieldType::Json, Some(path)) => {
+                    let path: Vec<String> =
+                        path.split('.').map(|x| x.to_string()).collect();
+                    if path.iter().any(|x| x.is_empty()) {
+                        return query_err(format!("invalid path {}", k));
+                    }
+                    Box::new(move |op| {
+                        Cond::Path(field.name, path.clone(), op)
+                    })
+                }
+                (FieldType::Json, None) => {
+                    return query_err(format!(
+                        "field {} is queried by paths",
+                        k
+                    ));
+                }
+                (_, Some(_)) => {
+                    return query_err(format!("unknown field {}", k));
+                }
+                (_, None) => Box::new(|op| Cond::Field(field.name, op)),
+            };
+            match v {
+                Value::Object(ops) => {
+                    if ops.is_empty() {
+                        return query_err(format!(
+                            "no operators for field {}",
+                            k
+                        ));
+                    }
+                    let mut conds = vec![];
+                    for (op, arg) in ops {
+                        conds.push(new_cond(parse_op(field, op, arg)?));
+                    }
+                    Ok(Cond::And(conds))
+                }
+                _ => Ok(new_cond(Op::Eq(parse_val(field, v)?))),
+            }
+        }
+    }
+}
+
+fn parse_op(field: &Field, op: &str, arg: &Value) -> Res<Op> {
+    match op {
+        "$eq" => Ok(Op::Eq(parse_val(field, arg)?)),
+        "$ne" => Ok(Op::Ne(parse_val(field, arg)?)),
+        "$gt" | "$gte" | "$lt" | "$lte" => {
+            if let FieldType::Enum(_) = field.typ {
+                return query_err(format!(
+                    "{} is not supported for field {}",
+                    op, field.name
+                ));
+            }
+            let val = parse_val(field, arg)?;
+            Ok(match op {
+                "$gt" => Op::Gt(val),
+                "$gte" => Op::Gte(val),
+                "$lt" => Op::Lt(val),
+                _ => Op::Lte(val),
+            })
+        }
+        "$in" | "$nin" => {
+            let Some(items) = arg.as_array() else {
+                return query_err(format!(
+                    "{} of field {} expects an array",
+                    op, field.name
+                ));
+            };
+            let vals = items
+                .iter()
+                .map(|x| parse_val(field, x))
+                .collect::<Res<Vec<Val>>>()?;
+            if op == "$in" {
+                Ok(Op::In(vals))
+            } else {
+                Ok(Op::Nin(vals))
+            }
+        }
+        "$like" | "$ilike" => {
+            if field.typ != FieldType::Str && field.typ != FieldType::Json {
+                return query_err(format!(
+                    "{} is not supported for field {}",
+                    op, field.name
+                ));
+            }
+            let Some(pattern) = arg.as_str() else {
+                return query_err(format!(
+                    "{} of field {} expects a string",
+                    op, field.name
+                ));
+            };
+            if op == "$like" {
+                Ok(Op::Like(pattern.to_string()))
+            } else {
+                Ok(Op::Ilike(pattern.to_string()))
+            }
+        }
+        "$exists" => {
+            if !field.nullable && field.typ != FieldType::Json {
+                return query_err(format!(
+                    "$exists is not supported for non-nullable field {}",
+                    field.name
+                ));
+            }
+            let Some(exists) = arg.as_bool() else {
+                return query_err(format!(
+                    "$exists of field {} expects a bool",
+                    field.name
+                ));
+            };
+            Ok(Op::Exists(exists))
+        }
+        _ => query_err(format!("unknown operator {}", op)),
+    }
+}
+
+fn parse_val(field: &Field, v: &Value) -> Res<Val> {
+    let val = match field.typ {
+        FieldType::Int => {
+            v.as_i64().and_then(|x| i32::try_from(x).ok()).map(Val::Int)
+        }
+        FieldType::Float => v.as_f64().map(Val::Float),
+        FieldType::Str => v.as_str().map(|x| Val::Str(x.to_string())),
+        FieldType::Enum(variants) => v
+            .as_str()
+            .filter(|x| variants.contains(x))
+            .map(|x| Val::Str(x.to_string())),
+        FieldType::Json => Some(v)
+            .filter(|x| x.is_string() || x.is_number() || x.is_boolean())
+            .map(|x| Val::Json(x.clone())),
+    };
+    match val {
+        Some(val) => Ok(val),
+        None => query_err(format!(
+            "invalid value {} for field {} of type {:?}",
+            v, field.name, field.typ
+        )),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sort {
+    pub field: &'static str,
+    pub desc: bool,
+}
+
+fn find_field<'a>(name: &str, fields: &'a [Field]) -> Res<&'a Field> {
+    match fields.iter().find(|x| x.name == name) {
+        Some(field) => Ok(field),
+        None => query_err(format!("unknown field {}", name)),
+    }
+}
+
+/// Parses sort fields, each optionally prefixed with `-` for descending
+/// order.
+///
+/// `id` is appended if missing, so the order is always total.
+pub fn parse_sort(sort: &[String], fields: &[Field]) -> Res<Vec<Sort>> {
+    let mut res = vec![];
+    for k in sort {
+        let (name, desc) = match k.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (k.as_str(), false),
+        };
+        let field = find_field(name, fields)?;
+        if !field.sortable {
+            return query_err(format!("cannot sort by field {}", name));
+        }
+        res.push(Sort {
+            field: field.name,
+            desc,
+        });
+    }
+    if !res.iter().any(|x| x.field == "id") {
+        res.push(Sort {
+            field: find_field("id", fields)?.name,
+            desc: false,
+        });
+    }
+    Ok(res)
+}
+
+fn check_keyset(sort: &[Sort], fields: &[Field]) -> Res<()> {
+    for x in sort {
+        let field = find_field(x.field, fields)?;
+        if field.nullable || matches!(field.typ, FieldType::Enum(_)) {
+            return query_err(format!(
+                "cursor is not supported for sort field {}",
+                field.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Makes a condition matching rows after the cursor for the given sort.
+///
+/// The cursor holds values of the sort fields of the last fetched row.
+pub fn keyset(sort: &[Sort], after: &[Value], fields: &[Field]) -> Res<Cond> {
+    check_keyset(sort, fields)?;
+    if sort.len() != after.len() {
+        return query_err(format!(
+            "cursor must have {} values, got {}",
+            sort.len(),
+            after.len()
+        ));
+    }
+    let mut vals = vec![];
+    for (x, v) in sort.iter().zip(after) {
+        vals.push(parse_val(find_field(x.field, fields)?, v)?);
+    }
+    let mut conds = vec![];
+    for i in 0..sort.len() {
+        let mut and: Vec<Cond> = (0..i)
+            .map(|j| Cond::Field(sort[j].field, Op::Eq(vals[j].clone())))
+            .collect();
+        let val = vals[i].clone();
+        and.push(Cond::Field(
+            sort[i].field,
+            if sort[i].desc {
+                Op::Lt(val)
+            } else {
+                Op::Gt(val)
+            },
+        ));
+        conds.push(Cond::And(and));
+    }
+    Ok(Cond::Or(conds))
+}
+
+/// Takes a cursor for the given sort from a serialized row.
+///
+/// Returns `None` if the sort doesn't support cursors.
+pub fn cursor(
+    sort: &[Sort],
+    fields: &[Field],
+    msg: &Value,
+) -> Option<Vec<Value>> {
+    check_keyset(sort, fields).ok()?;
+    sort.iter().map(|x| msg.get(x.field).cloned()).collect()
+}
+
+/// Checks that all projected fields are known and not derived.
+pub fn check_projection(projection: &[String], fields: &[Field]) -> Res<()> {
+    for name in projection {
+        if !fields.iter().any(|x| x.name == name && !x.derived) {
+            return query_err(format!("unknown projected field {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// Retains only projected fields of a serialized row.
+pub fn project(msg: Value, projection: &[String]) -> Value {
+    match msg {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(k, _)| projection.contains(k))
+                .collect(),
+        ),
+        _ => msg,
+    }
+}
+
+/// Selects fields of rows as JSON objects shaped like collection messages,
+/// for `QueryCollection::load_fields`.
+///
+/// Fields are selected by the columns of the same name. Enums are stored
+/// lowercase and serialized capitalized, see `StrEnum`.
+pub fn select_fields(fields: &[&Field]) -> SqlLiteral<Jsonb> {
+    let pairs: Vec<String> = fields
+        .iter()
+        .map(|x| match x.typ {
+            FieldType::Enum(_) => format!("'{0}', initcap(\"{0}\")", x.name),
+            _ => format!("'{0}', \"{0}\"", x.name),
+        })
+        .collect();
+    sql::<Jsonb>(&format!("jsonb_build_object({})", pairs.join(", ")))
+}
+
+pub type BoxedCond<T> =
+    Box<dyn BoxableExpression<T, Pg, SqlType = Nullable<Bool>>>;
+pub type BoxedOrder<T> =
+    Box<dyn BoxableExpression<T, Pg, SqlType = NotSelectable>>;
+
+/// Table which can be searched by `quco` queries.
+///
+/// Implementors declare their fields and map them to diesel columns, the
+/// rest is done by `find`.
+pub trait QueryCollection {
+    type Table: 'static;
+    /// Boxed query over the table.
+    type Query: FilterDsl<BoxedCond<Self::Table>, Output = Self::Query>
+        + ThenOrderDsl<BoxedOrder<Self::Table>, Output = Self::Query>
+        + LimitDsl<Output = Self::Query>
+        + OffsetDsl<Output = Self::Query>;
+
+    /// Fields available for filtering, sorting and projection. Must include
+    /// `id`.
+    const FIELDS: &'static [Field];
+
+    fn query() -> Self::Query;
+
+    /// Translates an operator over a field into a diesel expression, see
+    /// `col_cond!`.
+    ///
+    /// Called only with fields and operators validated against `FIELDS`.
+    fn cond(field: &str, op: &Op) -> BoxedCond<Self::Table>;
+
+    /// Translates an operator over a path of a JSON field into a diesel
+    /// expression, see `json_cond!`.
+    ///
+    /// Only called for collections with JSON fields.
+    fn path_cond(
+        _field: &str,
+        _path: &[String],
+        _op: &Op,
+    ) -> BoxedCond<Self::Table> {
+        unreachable!()
+    }
+
+    /// Translates a sort into a diesel expression, see `col_order!`.
+    fn order(sort: &Sort) -> BoxedOrder<Self::Table>;
+
+    /// Loads rows serialized as collection messages.
+    fn load(q: Self::Query, con: &mut Con) -> Res<Vec<Value>>;
+
+    /// Loads only the given fields of rows, which are never derived, see
+    /// `select_fields`.
+    fn load_fields(
+        q: Self::Query,
+        fields: &[&Field],
+        con: &mut Con,
+    ) -> Res<Vec<Value>>;
+
+    fn count(q: Self::Query, con: &mut Con) -> Res<i64>;
+}
+
+/// Translates an operator over a column into a diesel expression.
+///
+/// Pass `text` to enable pattern operators for text columns.
+macro_rules! col_cond {
+    ($col:expr, $op:expr, $as:ident) => {{
+        use diesel::prelude::*;
+        use $crate::quco::{BoxedCond, Op};
+        match $op {
+            Op::Eq(v) => Box::new($col.eq(v.$as().to_owned()).nullable())
+                as BoxedCond<_>,
+            Op::Ne(v) => {
+                Box::new($col.is_distinct_from(v.$as().to_owned()).nullable())
+            }
+            Op::In(vs) => Box::new(
+                $col.eq_any(
+                    vs.iter().map(|x| x.$as().to_owned()).collect::<Vec<_>>(),
+                )
+                .nullable(),
+            ),
+            Op::Nin(vs) => Box::new(
+                $col.is_null()
+                    .or($col.ne_all(
+                        vs.iter()
+                            .map(|x| x.$as().to_owned())
+                            .collect::<Vec<_>>(),
+                    ))
+                    .nullable(),
+            ),
+            Op::Gt(v) => Box::new($col.gt(v.$as().to_owned()).nullable()),
+            Op::Gte(v) => Box::new($col.ge(v.$as().to_owned()).nullable()),
+            Op::Lt(v) => Box::new($col.lt(v.$as().to_owned()).nullable()),
+            Op::Lte(v) => Box::new($col.le(v.$as().to_owned()).nullable()),
+            Op::Exists(true) => Box::new($col.is_not_null().nullable()),
+            Op::Exists(false) => Box::new($col.is_null().nullable()),
+            // pattern operators are validated to only reach text columns
+            Op::Like(_) | Op::Ilike(_) => unreachable!(),
+        }
+    }};
+    ($col:expr, $op:expr, $as:ident, text) => {{
+        use diesel::prelude::*;
+        use $crate::quco::{BoxedCond, Op};
+        match $op {
+            Op::Like(v) => {
+                Box::new($col.like(v.to_owned()).nullable()) as BoxedCond<_>
+            }
+            Op::Ilike(v) => Box::new($col.ilike(v.to_owned()).nullable()),
+            op => $crate::quco::col_cond!($col, op, $as),
+        }
+    }};
+}
+pub(crate) use col_cond;
+
+/// Translates an operator over a path of a JSONB column into a diesel
+/// expression.
+///
+/// Values are compared as JSON, so `"1"` doesn't match `1`, while patterns
+/// are matched against the text of the value.
+macro_rules! json_cond {
+    ($col:expr, $path:expr, $op:expr) => {{
+        use diesel::prelude::*;
+        use $crate::quco::{BoxedCond, Op};
+        let path: Vec<String> = $path.to_vec();
+        let val = || $col.retrieve_by_path_as_object(path.clone());
+        match $op {
+            Op::Eq(v) => Box::new(val().eq(v.as_json().clone()).nullable())
+                as BoxedCond<_>,
+            Op::Ne(v) => Box::new(
+                val().is_distinct_from(v.as_json().clone()).nullable(),
+            ),
+            Op::In(vs) => Box::new(
+                val()
+                    .eq_any(
+                        vs.iter()
+                            .map(|x| x.as_json().clone())
+                            .collect::<Vec<_>>(),
+                    )
+                    .nullable(),
+            ),
+            Op::Nin(vs) => Box::new(
+                val()
+                    .is_null()
+                    .or(val().ne_all(
+                        vs.iter()
+                            .map(|x| x.as_json().clone())
+                            .collect::<Vec<_>>(),
+                    ))
+                    .nullable(),
+            ),
+            Op::Gt(v) => Box::new(val().gt(v.as_json().clone()).nullable()),
+            Op::Gte(v) => Box::new(val().ge(v.as_json().clone()).nullable()),
+            Op::Lt(v) => Box::new(val().lt(v.as_json().clone()).nullable()),
+            Op::Lte(v) => Box::new(val().le(v.as_json().clone()).nullable()),
+            Op::Exists(true) => Box::new(val().is_not_null().nullable()),
+            Op::Exists(false) => Box::new(val().is_null().nullable()),
+            Op::Like(v) => Box::new(
+                $col.retrieve_by_path_as_text(path)
+                    .like(v.to_owned())
+                    .nullable(),
+            ),
+            Op::Ilike(v) => Box::new(
+                $col.retrieve_by_path_as_text(path)
+                    .ilike(v.to_owned())
+                    .nullable(),
+            ),
+        }
+    }};
+}
+pub(crate) use json_cond;
+
+/// Translates a sort over a column into a diesel expression.
+macro_rules! col_order {
+    ($col:expr, $sort:expr) => {{
+        use diesel::prelude::*;
+        if $sort.desc {
+            Box::new($col.desc()) as $crate::quco::BoxedOrder<_>
+        } else {
+            Box::new($col.asc())
+        }
+    }};
+}
+pub(crate) use col_order;
+
+pub fn to_expr<C: QueryCollection>(cond: &Cond) -> BoxedCond<C::Table> {
+    match cond {
+        Cond::Field(field, op) => C::cond(field, op),
+        Cond::Path(field, path, op) => C::path_cond(field, path, op),
+        Cond::And(conds) => conds
+            .iter()
+            .map(to_expr::<C>)
+            .reduce(|a, b| Box::new(a.and(b)))
+            .unwrap_or_else(|| Box::new(sql::<Nullable<Bool>>("TRUE"))),
+        Cond::Or(conds) => conds
+            .iter()
+            .map(to_expr::<C>)
+            .reduce(|a, b| Box::new(a.or(b)))
+            .unwrap_or_else(|| Box::new(sql::<Nullable<Bool>>("FALSE"))),
+    }
+}
+
+/// Search over a collection.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Search {
+    #[serde(default)]
+    pub sq: Query,
+    /// Fields to sort by, prefixed with `-` for descending order.
+    #[serde(default)]
+    pub sort: Vec<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// Cursor returned as `next` by the previous page.
+    pub after: Option<Vec<Value>>,
+    /// Whether to count all rows matching the query.
+    #[serde(default)]
+    pub count: bool,
+    /// Fields to return for each row, all by default.
+    pub fields: Option<Vec<String>>,
+}
+
+pub struct Found {
+    pub items: Vec<Value>,
+    /// Amount of all rows matching the query, if requested.
+    pub total: Option<i64>,
+    /// Cursor for the next page, if the page is full and the sort supports
+    /// cursors.
+    pub next: Option<Vec<Value>>,
+}
+
+/// Searches a collection.
+///
+/// See `parse` for the query syntax. Rows are sorted by id, unless other
+/// sort is given.
+///
+/// Paging is opt-in: without a `limit` all matching rows are returned, a
+/// given limit is lowered to `query.max_limit`. Pages are fetched either by
+/// `offset` or by `after` cursor, the latter only supports sorting by
+/// non-nullable fields.
+pub fn find<C: QueryCollection>(s: &Search, con: &mut Con) -> Res<Found> {
+    let limit = s.limit.map(|x| x.min(APPRC.query.max_limit));
+    find_limited::<C>(s, limit, con)
+}
+
+/// Searches like `find`, but never returns more than `max_limit` rows, even
+/// without a limit, e.g. for exports.
+pub fn find_up_to<C: QueryCollection>(
+    s: &Search,
+    max_limit: i64,
+    con: &mut Con,
+) -> Res<Found> {
+    let limit = s.limit.unwrap_or(max_limit).min(max_limit);
+    find_limited::<C>(s, Some(limit), con)
+}
+
+fn find_limited<C: QueryCollection>(
+    s: &Search,
+    limit: Option<i64>,
+    con: &mut Con,
+) -> Res<Found> {
+    let cond = parse(&s.sq, C::FIELDS)?;
+    let sort = parse_sort(&s.sort, C::FIELDS)?;
+    if let Some(projection) = &s.fields {
+        check_projection(projection, C::FIELDS)?;
+    }
+
+    let total = if s.count {
+        Some(C::count(C::query().filter(to_expr::<C>(&cond)), con)?)
+    } else {
+        None
+    };
+
+    let mut q = C::query().filter(to_expr::<C>(&cond));
+    if let Some(after) = &s.after {
+        q = q.filter(to_expr::<C>(&keyset(&sort, after, C::FIELDS)?));
+    }
+    for x in sort.iter() {
+        q = q.then_order_by(C::order(x));
+    }
+    if let Some(limit) = limit {
+        q = q.limit(limit);
+    }
+    if let Some(offset) = s.offset {
+        q = q.offset(offset);
+    }
+    let items = match &s.fields {
+        Some(projection) => {
+            // sort fields are loaded too, so the cursor can be made of them
+            let fields: Vec<&Field> = C::FIELDS
+                .iter()
+                .filter(|x| !x.derived)
+                .filter(|x| {
+                    projection.iter().any(|p| p == x.name)
+                        || sort.iter().any(|y| y.field == x.name)
+                })
+                .collect();
+            C::load_fields(q, &fields, con)?
+        }
+        None => C::load(q, con)?,
+    };
+
+    let next = match items.last() {
+        Some(last) if limit == Some(items.len() as i64) => {
+            cursor(&sort, C::FIELDS, last)
+        }
+        _ => None,
+    };
+    let items = match &s.fields {
+        Some(projection) => {
+            items.into_iter().map(|x| project(x, projection)).collect()
+        }
+        None => items,
+    };
+    Ok(Found { items, total, next })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const FIELDS: &[Field] = &[
+        Field::new("id", FieldType::Int),
+        Field::new("name", FieldType::Str).nullable(),
+        Field::new("kind", FieldType::Enum(&["a", "b"])),
+        Field::new("score", FieldType::Float),
+        Field::new("rank", FieldType::Int).derived(),
+        Field::new("group", FieldType::Int).derived().unsortable(),
+        Field::new("meta", FieldType::Json).unsortable(),
+    ];
+
+    fn sq(v: Value) -> Query {
+        serde_json::from_value(v).unwrap()
+    }
+
+    fn sort(keys: &[&str]) -> Vec<Sort> {
+        let keys: Vec<String> = keys.iter().map(|x| x.to_string()).collect();
+        parse_sort(&keys, FIELDS).unwrap()
+    }
+
+    #[test]
+    fn parse_std_ok() {
+        assert_eq!(
+            parse(&sq(json!({"id": 1})), FIELDS).unwrap(),
+            Cond::And(vec![Cond::Field("id", Op::Eq(Val::Int(1)))])
+        );
+        assert_eq!(
+            parse(
+                &sq(json!({"$or": [
+                    {"kind": {"$in": ["a"]}},
+                    {"name": {"$exists": false}}
+                ]})),
+                FIELDS
+            )
+            .unwrap(),
+            Cond::And(vec![Cond::Or(vec![
+                Cond::And(vec![Cond::And(vec![Cond::Field(
+                    "kind",
+                    Op::In(vec![Val::Str("a".to_string())])
+                )])]),
+                Cond::And(vec![Cond::And(vec![Cond::Field(
+                    "name",
+                    Op::Exists(false)
+                )])]),
+            ])])
+        );
+        assert_eq!(
+            parse(&sq(json!({"score": {"$gte": 1.5}})), FIELDS).unwrap(),
+            Cond::And(vec![Cond::And(vec![Cond::Field(
+                "score",
+                Op::Gte(Val::Float(1.5))
+            )])])
+        );
+    }
+
+    #[test]
+    fn parse_invalid_err() {
+        for v in [
+            json!({"unknown": 1}),
+            json!({"id": "1"}),
+            json!({"id": {"$regex": "1"}}),
+            json!({"id": {}}),
+            json!({"id": {"$like": "1%"}}),
+            json!({"id": {"$exists": true}}),
+            json!({"kind": "c"}),
+            json!({"kind": {"$gt": "a"}}),
+            json!({"$or": {"id": 1}}),
+            json!({"meta": "x"}),
+            json!({"meta.": "x"}),
+            json!({"meta.a": {"b": 1}}),
+            json!({"meta.a": null}),
+            json!({"id.a": 1}),
+        ] {
+            let e = parse(&sq(v.clone()), FIELDS).unwrap_err();
+            assert_eq!(
+                serde_json::to_value(e).unwrap()["code"],
+                "query_err",
+                "{}",
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn parse_path_ok() {
+        let path = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            parse(&sq(json!({"meta.a.b": "x"})), FIELDS).unwrap(),
+            Cond::And(vec![Cond::Path(
+                "meta",
+                path.clone(),
+                Op::Eq(Val::Json(json!("x")))
+            )])
+        );
+        assert_eq!(
+            parse(
+                &sq(json!({"meta.a.b": {"$exists": true, "$like": "x%"}})),
+                FIELDS
+            )
+            .unwrap(),
+            Cond::And(vec![Cond::And(vec![
+                Cond::Path("meta", path.clone(), Op::Exists(true)),
+                Cond::Path("meta", path, Op::Like("x%".to_string())),
+            ])])
+        );
+    }
+
+    #[test]
+    fn parse_sort_std_ok() {
+        assert_eq!(
+            sort(&["-score"]),
+            vec![
+                Sort {
+                    field: "score",
+                    desc: true
+                },
+                Sort {
+                    field: "id",
+                    desc: false
+                }
+            ]
+        );
+        assert_eq!(
+            sort(&["-id"]),
+            vec![Sort {
+                field: "id",
+                desc: true
+            }]
+        );
+        assert!(parse_sort(&["unknown".to_string()], FIELDS).is_err());
+        assert!(parse_sort(&["group".to_string()], FIELDS).is_err());
+    }
+
+    #[test]
+    fn keyset_std_ok() {
+        assert_eq!(
+            keyset(&sort(&["-score"]), &[json!(2.0), json!(5)], FIELDS)
+                .unwrap(),
+            Cond::Or(vec![
+                Cond::And(vec![Cond::Field("score", Op::Lt(Val::Float(2.0)))]),
+                Cond::And(vec![
+                    Cond::Field("score", Op::Eq(Val::Float(2.0))),
+                    Cond::Field("id", Op::Gt(Val::Int(5))),
+                ]),
+            ])
+        );
+        assert!(keyset(&sort(&["-score"]), &[json!(2.0)], FIELDS).is_err());
+        assert!(
+            keyset(&sort(&["name"]), &[json!("x"), json!(1)], FIELDS).is_err()
+        );
+        assert!(
+            keyset(&sort(&["kind"]), &[json!("a"), json!(1)], FIELDS).is_err()
+        );
+    }
+
+    #[test]
+    fn cursor_std_ok() {
+        let msg = json!({"id": 5, "name": "x", "kind": "a", "score": 2.0});
+        assert_eq!(
+            cursor(&sort(&["-score"]), FIELDS, &msg),
+            Some(vec![json!(2.0), json!(5)])
+        );
+        assert_eq!(cursor(&sort(&["name"]), FIELDS, &msg), None);
+    }
+
+    #[test]
+    fn projection_std_ok() {
+        let projection = vec!["id".to_string(), "kind".to_string()];
+        assert!(check_projection(&projection, FIELDS).is_ok());
+        assert!(check_projection(&["rank".to_string()], FIELDS).is_err());
+        assert!(check_projection(&["unknown".to_string()], FIELDS).is_err());
+        assert_eq!(
+            project(json!({"id": 5, "name": "x", "kind": "a"}), &projection),
+            json!({"id": 5, "kind": "a"})
+        );
+    }
+}
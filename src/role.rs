@@ -0,0 +1,287 @@
+use std::collections::BTreeSet;
+
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::{Con, Id},
+    ryz::{
+        err,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema,
+    user_change::{self, ChangeAction, NewUserChange},
+};
+
+/// Named set of permissions, assigned to users.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Role {
+    pub id: Id,
+    pub created: Time,
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetRole {
+    pub name: String,
+    /// Replace the current permissions of the role.
+    pub permissions: Vec<String>,
+}
+
+/// Role of an user, as assigned or revoked.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AssignRole {
+    pub user_id: Id,
+    pub role: String,
+    /// Domain the role is granted in, all domains if omitted.
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UserRole {
+    pub role: String,
+    pub domain: Option<String>,
+}
+
+/// Roles and permissions of an user within a domain.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::role)]
+struct InsertRole {
+    created: Time,
+    name: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::role_permission)]
+struct InsertRolePermission {
+    role_id: Id,
+    permission: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::user_role)]
+struct InsertUserRole {
+    user_id: Id,
+    role_id: Id,
+    domain: String,
+    created: Time,
+}
+
+/// Global roles are stored with an empty domain, to be part of the key.
+fn to_column(domain: &Option<String>) -> String {
+    domain.to_owned().unwrap_or_default()
+}
+
+fn from_column(domain: String) -> Option<String> {
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+fn get_id(name: &str, con: &mut Con) -> Res<Id> {
+    match schema::role::table
+        .filter(schema::role::name.eq(name))
+        .select(schema::role::id)
+        .first(con)
+        .optional()?
+    {
+        Some(id) => Ok(id),
+        None => err::res("no_role", format!("no role {}", name).as_str()),
+    }
+}
+
+fn get_permissions(role_id: Id, con: &mut Con) -> Res<Vec<String>> {
+    Ok(schema::role_permission::table
+        .filter(schema::role_permission::role_id.eq(role_id))
+        .order(schema::role_permission::permission.asc())
+        .select(schema::role_permission::permission)
+        .load(con)?)
+}
+
+/// Creates the role or replaces it's permissions.
+///
+/// Access tokens issued before keep the old permissions until they expire.
+pub fn set(inp: &SetRole, con: &mut Con) -> Res<Role> {
+    if inp.name.is_empty() || inp.permissions.iter().any(|x| x.is_empty()) {
+        return err::res_msg("role and permission names cannot be empty");
+    }
+    con.transaction(|con| {
+        diesel::insert_into(schema::role::table)
+            .values(&InsertRole {
+                created: utc(),
+                name: inp.name.to_owned(),
+            })
+            .on_conflict_do_nothing()
+            .execute(con)?;
+        let (id, created): (Id, Time) = schema::role::table
+            .filter(schema::role::name.eq(&inp.name))
+            .select((schema::role::id, schema::role::created))
+            .first(con)?;
+        diesel::delete(
+            schema::role_permission::table
+                .filter(schema::role_permission::role_id.eq(id)),
+        )
+        .execute(con)?;
+        let permissions: BTreeSet<&String> = inp.permissions.iter().collect();
+        diesel::insert_into(schema::role_permission::table)
+            .values(
+                permissions
+                    .iter()
+                    .map(|x| InsertRolePermission {
+                        role_id: id,
+                        permission: x.to_string(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(con)?;
+        Ok(Role {
+            id,
+            created,
+            name: inp.name.to_owned(),
+            permissions: get_permissions(id, con)?,
+        })
+    })
+}
+
+pub fn get_many(con: &mut Con) -> Res<Vec<Role>> {
+    let roles: Vec<(Id, Time, String)> = schema::role::table
+        .order(schema::role::name.asc())
+        .select((schema::role::id, schema::role::created, schema::role::name))
+        .load(con)?;
+    roles
+        .into_iter()
+        .map(|(id, created, name)| {
+            Ok(Role {
+                id,
+                created,
+                name,
+                permissions: get_permissions(id, con)?,
+            })
+        })
+        .collect()
+}
+
+pub fn get_user_roles(user_id: Id, con: &mut Con) -> Res<Vec<UserRole>> {
+    let rows: Vec<(String, String)> = schema::user_role::table
+        .inner_join(schema::role::table)
+        .filter(schema::user_role::user_id.eq(user_id))
+        .order((schema::role::name.asc(), schema::user_role::domain.asc()))
+        .select((schema::role::name, schema::user_role::domain))
+        .load(con)?;
+    Ok(rows
+        .into_iter()
+        .map(|(role, domain)| UserRole {
+            role,
+            domain: from_column(domain),
+        })
+        .collect())
+}
+
+/// Writes the `Roles` change with the current roles of each user.
+fn new_changes(user_ids: &[Id], con: &mut Con) -> Res<()> {
+    let mut changes = vec![];
+    for user_id in user_ids {
+        changes.push(NewUserChange {
+            user_id: *user_id,
+            action: ChangeAction::Roles,
+            data: Some(json!({"roles": get_user_roles(*user_id, con)?})),
+        });
+    }
+    user_change::new_many(&changes, con)?;
+    Ok(())
+}
+
+/// Deletes the role, revoking it from all users.
+pub fn del(name: &str, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        let id = get_id(name, con)?;
+        let mut user_ids: Vec<Id> = schema::user_role::table
+            .filter(schema::user_role::role_id.eq(id))
+            .select(schema::user_role::user_id)
+            .load(con)?;
+        user_ids.sort();
+        user_ids.dedup();
+        diesel::delete(schema::role::table.filter(schema::role::id.eq(id)))
+            .execute(con)?;
+        if !user_ids.is_empty() {
+            new_changes(&user_ids, con)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn assign(inp: &AssignRole, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        let role_id = get_id(&inp.role, con)?;
+        let inserted = diesel::insert_into(schema::user_role::table)
+            .values(&InsertUserRole {
+                user_id: inp.user_id,
+                role_id,
+                domain: to_column(&inp.domain),
+                created: utc(),
+            })
+            .on_conflict_do_nothing()
+            .execute(con)?;
+        if inserted > 0 {
+            new_changes(&[inp.user_id], con)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn revoke(inp: &AssignRole, con: &mut Con) -> Res<()> {
+    con.transaction(|con| {
+        let role_id = get_id(&inp.role, con)?;
+        let deleted = diesel::delete(
+            schema::user_role::table
+                .filter(schema::user_role::user_id.eq(inp.user_id))
+                .filter(schema::user_role::role_id.eq(role_id))
+                .filter(schema::user_role::domain.eq(to_column(&inp.domain))),
+        )
+        .execute(con)?;
+        if deleted > 0 {
+            new_changes(&[inp.user_id], con)?;
+        }
+        Ok(())
+    })
+}
+
+/// Collects global roles of the user together with the ones granted in the
+/// domain, and their permissions.
+pub fn get_grants(
+    user_id: Id,
+    domain: Option<&str>,
+    con: &mut Con,
+) -> Res<Grants> {
+    let domains = vec!["", domain.unwrap_or_default()];
+    let role_ids: Vec<(Id, String)> = schema::user_role::table
+        .inner_join(schema::role::table)
+        .filter(schema::user_role::user_id.eq(user_id))
+        .filter(schema::user_role::domain.eq_any(domains))
+        .select((schema::role::id, schema::role::name))
+        .load(con)?;
+    let ids: Vec<Id> = role_ids.iter().map(|x| x.0).collect();
+    let permissions: BTreeSet<String> = schema::role_permission::table
+        .filter(schema::role_permission::role_id.eq_any(ids))
+        .select(schema::role_permission::permission)
+        .load::<String>(con)?
+        .into_iter()
+        .collect();
+    let roles: BTreeSet<String> = role_ids.into_iter().map(|x| x.1).collect();
+    Ok(Grants {
+        roles: roles.into_iter().collect(),
+        permissions: permissions.into_iter().collect(),
+    })
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::res::Res;
 
@@ -6,6 +7,9 @@ use super::res::Res;
 pub struct Error {
     code: String,
     msg: String,
+    /// Structured details of the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
 }
 
 impl Default for Error {
@@ -13,6 +17,7 @@ impl Default for Error {
         Self {
             code: "err".to_string(),
             msg: "".to_string(),
+            data: None,
         }
     }
 }
@@ -22,6 +27,15 @@ impl Error {
         Self {
             code: code.to_string(),
             msg: msg.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn new_data(code: &str, msg: &str, data: Value) -> Self {
+        Self {
+            code: code.to_string(),
+            msg: msg.to_string(),
+            data: Some(data),
         }
     }
 
@@ -38,6 +52,14 @@ impl Error {
             ..Default::default()
         }
     }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
 }
 
 pub fn res_default<T>() -> Res<T> {
@@ -55,3 +77,7 @@ pub fn res_msg<T>(msg: &str) -> Res<T> {
 pub fn res<T>(code: &str, msg: &str) -> Res<T> {
     Err(Error::new(code, msg))
 }
+
+pub fn res_data<T>(code: &str, msg: &str, data: Value) -> Res<T> {
+    Err(Error::new_data(code, msg, data))
+}
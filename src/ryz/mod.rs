@@ -1,6 +1,8 @@
 pub mod dict;
 pub mod enm;
 pub mod err;
+pub mod hex;
 pub mod path;
 pub mod res;
 pub mod time;
+pub mod url;
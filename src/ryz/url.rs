@@ -0,0 +1,15 @@
+/// Percent-encodes everything except unreserved characters of RFC 3986.
+pub fn encode_component(s: &str) -> String {
+    s.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => (x as char).to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
@@ -8,7 +8,153 @@ diesel::table! {
         firstname -> Nullable<Varchar>,
         patronym -> Nullable<Varchar>,
         surname -> Nullable<Varchar>,
-        rt -> Nullable<Varchar>,
+        hrt -> Nullable<Varchar>,
+        created -> Float8,
+        updated -> Float8,
+        nusername -> Varchar,
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Float8>,
+        phone -> Nullable<Varchar>,
+        phone_verified_at -> Nullable<Float8>,
+        metadata -> Jsonb,
+    }
+}
+
+diesel::table! {
+    auth_event (id) {
+        id -> Int4,
+        created -> Float8,
+        kind -> Varchar,
+        user_id -> Nullable<Int4>,
+        domain -> Nullable<Varchar>,
+        rpc -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        outcome -> Varchar,
+        reason -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    identity (id) {
+        id -> Int4,
+        created -> Float8,
+        provider -> Varchar,
+        subject -> Varchar,
+        user_id -> Int4,
+    }
+}
+
+diesel::table! {
+    mail (id) {
+        id -> Int4,
+        created -> Float8,
+        template -> Varchar,
+        recipient -> Varchar,
+        subject -> Varchar,
+        body -> Varchar,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt -> Float8,
+        err -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    membership (org_id, user_id) {
+        org_id -> Int4,
+        user_id -> Int4,
+        role -> Varchar,
+        created -> Float8,
+    }
+}
+
+diesel::table! {
+    mfa (user_id) {
+        user_id -> Int4,
+        required -> Bool,
+        totp_secret -> Nullable<Varchar>,
+        totp_confirmed_at -> Nullable<Float8>,
+        totp_last_step -> Int8,
+        attempts -> Int4,
+        attempts_since -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    mfa_challenge (hnonce) {
+        hnonce -> Varchar,
+        user_id -> Int4,
+        expires -> Float8,
+    }
+}
+
+diesel::table! {
+    oauth_client (id) {
+        id -> Int4,
+        created -> Float8,
+        client_id -> Varchar,
+        hsecret -> Varchar,
+        name -> Varchar,
+        redirect_uris -> Jsonb,
+        scopes -> Jsonb,
+    }
+}
+
+diesel::table! {
+    oauth_code (hcode) {
+        hcode -> Varchar,
+        created -> Float8,
+        client_id -> Int4,
+        user_id -> Int4,
+        redirect_uri -> Varchar,
+        scope -> Varchar,
+        nonce -> Nullable<Varchar>,
+        code_challenge -> Nullable<Varchar>,
+        expires -> Float8,
+    }
+}
+
+diesel::table! {
+    organisation (id) {
+        id -> Int4,
+        created -> Float8,
+        name -> Varchar,
+    }
+}
+
+diesel::table! {
+    recovery_code (id) {
+        id -> Int4,
+        user_id -> Int4,
+        hcode -> Varchar,
+        used_at -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    role (id) {
+        id -> Int4,
+        created -> Float8,
+        name -> Varchar,
+    }
+}
+
+diesel::table! {
+    role_permission (role_id, permission) {
+        role_id -> Int4,
+        permission -> Varchar,
+    }
+}
+
+diesel::table! {
+    upstream_login (hstate) {
+        hstate -> Varchar,
+        created -> Float8,
+        provider -> Varchar,
+        nonce -> Varchar,
+        code_verifier -> Varchar,
+        expires -> Float8,
     }
 }
 
@@ -18,9 +164,116 @@ diesel::table! {
         created -> Float8,
         action -> Varchar,
         user_id -> Int4,
+        data -> Nullable<Jsonb>,
+    }
+}
+
+diesel::table! {
+    user_role (user_id, role_id, domain) {
+        user_id -> Int4,
+        role_id -> Int4,
+        domain -> Varchar,
+        created -> Float8,
+    }
+}
+
+diesel::table! {
+    verification (id) {
+        id -> Int4,
+        created -> Float8,
+        user_id -> Int4,
+        kind -> Varchar,
+        target -> Varchar,
+        hcode -> Varchar,
+        expires -> Float8,
+        attempts -> Int4,
+    }
+}
+
+diesel::table! {
+    webauthn_challenge (id) {
+        id -> Int4,
+        created -> Float8,
+        challenge -> Varchar,
+        kind -> Varchar,
+        user_id -> Nullable<Int4>,
+        expires -> Float8,
+    }
+}
+
+diesel::table! {
+    webauthn_credential (id) {
+        id -> Int4,
+        created -> Float8,
+        user_id -> Int4,
+        credential_id -> Varchar,
+        public_key -> Varchar,
+        sign_count -> Int8,
+        name -> Nullable<Varchar>,
+        last_used -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    webhook (id) {
+        id -> Int4,
+        created -> Float8,
+        event -> Varchar,
+        url -> Varchar,
+        secret -> Varchar,
+    }
+}
+
+diesel::table! {
+    webhook_delivery (id) {
+        id -> Int4,
+        created -> Float8,
+        webhook_id -> Int4,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt -> Float8,
+        err -> Nullable<Varchar>,
     }
 }
 
+diesel::joinable!(identity -> appuser (user_id));
+diesel::joinable!(membership -> appuser (user_id));
+diesel::joinable!(membership -> organisation (org_id));
+diesel::joinable!(mfa -> appuser (user_id));
+diesel::joinable!(mfa_challenge -> appuser (user_id));
+diesel::joinable!(oauth_code -> appuser (user_id));
+diesel::joinable!(oauth_code -> oauth_client (client_id));
+diesel::joinable!(recovery_code -> appuser (user_id));
+diesel::joinable!(role_permission -> role (role_id));
 diesel::joinable!(user_change -> appuser (user_id));
+diesel::joinable!(user_role -> appuser (user_id));
+diesel::joinable!(user_role -> role (role_id));
+diesel::joinable!(verification -> appuser (user_id));
+diesel::joinable!(webauthn_challenge -> appuser (user_id));
+diesel::joinable!(webauthn_credential -> appuser (user_id));
+diesel::joinable!(webhook_delivery -> webhook (webhook_id));
 
-diesel::allow_tables_to_appear_in_same_query!(appuser, user_change,);
+diesel::allow_tables_to_appear_in_same_query!(
+    appuser,
+    auth_event,
+    identity,
+    mail,
+    membership,
+    mfa,
+    mfa_challenge,
+    oauth_client,
+    oauth_code,
+    organisation,
+    recovery_code,
+    role,
+    role_permission,
+    upstream_login,
+    user_change,
+    user_role,
+    verification,
+    webauthn_challenge,
+    webauthn_credential,
+    webhook,
+    webhook_delivery,
+);
@@ -1,14 +1,16 @@
 use crate::ryz::err::res;
+use crate::ryz::hex::to_hex;
 use crate::ryz::res::Res;
 use crate::ryz::time::{utc, Time};
+use crate::APPRC;
 use hmac::{Hmac, Mac};
 use jwt::VerifyWithKey;
 use jwt::{SignWithKey, ToBase64};
 use serde::Deserialize;
 use serde::Serialize;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct UserTokenPayload {
     pub user_id: i32,
     /// When the token was created.
@@ -16,14 +18,59 @@ pub struct UserTokenPayload {
     /// We store this field instead of `exp` to allow receiver to freely
     /// interpret using known exp delta.
     pub created: Time,
+    /// Domain the access token is issued for, roles granted in it are
+    /// included besides the global ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Roles of the user, only set for access tokens.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Permissions of the roles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    /// Organisation the access token is issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<i32>,
+    /// Role of the user in the organisation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_role: Option<String>,
+    /// Metadata keys of the user selected by `metadata.claims` config, by
+    /// namespace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// When the token expires at the latest, set for impersonation tokens
+    /// which live shorter than regular ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<Time>,
+    /// Who acts on behalf of the user, set for impersonation tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
 }
 
 impl Expire for UserTokenPayload {
     fn get_created(&self) -> Res<Time> {
-        Ok(*&self.created)
+        Ok(self.created)
+    }
+
+    fn check_exp(&self, delta: Time) -> Res<Time> {
+        let mut exp = self.created + delta;
+        if let Some(max_exp) = self.exp {
+            exp = exp.min(max_exp);
+        }
+        if exp < utc() {
+            return res("exp_err", "expired token");
+        }
+        Ok(exp)
     }
 }
 
+/// Impersonator of the user, as in `act` claim of RFC 8693.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Actor {
+    /// Identifier of the impersonator given by the domain.
+    pub sub: String,
+}
+
 pub trait Expire {
     fn get_created(&self) -> Res<Time>;
 
@@ -31,7 +78,7 @@ pub trait Expire {
     ///
     /// Returns exp time with relation to the given delta.
     fn check_exp(&self, delta: Time) -> Res<Time> {
-        let created = *&self.get_created().unwrap();
+        let created = self.get_created().unwrap();
         let exp = created + delta;
         if exp < utc() {
             return res("exp_err", "expired token");
@@ -40,41 +87,206 @@ pub trait Expire {
     }
 }
 
+/// Signing key of a kind of tokens, derived from the configured
+/// `token.secret`, so tokens of one kind are never accepted as another.
+fn derive_key(kind: &[u8]) -> Vec<u8> {
+    let mut mac: Hmac<Sha256> =
+        Hmac::new_from_slice(APPRC.token.secret.as_bytes()).unwrap();
+    mac.update(kind);
+    mac.finalize().into_bytes().to_vec()
+}
+
 pub fn new_token(
     payload: &(impl ToBase64 + Expire + Serialize),
     secret: &[u8],
 ) -> Res<String> {
-    let encoded_secret: Hmac<Sha256> =
-        Hmac::new_from_slice(secret.into()).unwrap();
+    let encoded_secret: Hmac<Sha256> = Hmac::new_from_slice(secret).unwrap();
     Ok(payload.sign_with_key(&encoded_secret).unwrap())
 }
 
-pub fn verify_token<T>(token: &String, secret: &[u8]) -> Res<T>
+pub fn verify_token<T>(token: &str, secret: &[u8]) -> Res<T>
 where
     T: ToBase64 + Expire + for<'a> Deserialize<'a>,
 {
     let encoded_secret: Hmac<Sha256> = Hmac::new_from_slice(secret).unwrap();
-    let payload: T = token.verify_with_key(&encoded_secret).unwrap();
+    let Ok(payload): Result<T, _> = token.verify_with_key(&encoded_secret)
+    else {
+        return res("invalid_token", "invalid token");
+    };
     payload.check_exp(Time::from(60 * 60 * 24 * 365))?;
     Ok(payload)
 }
 
 pub fn new_rt(user_id: i32) -> Res<String> {
     let payload = UserTokenPayload {
-        user_id: user_id,
+        user_id,
         created: utc(),
+        ..Default::default()
     };
     new_token(&payload, b"weloveauth")
 }
 
-pub fn new_at(user_id: i32) -> Res<String> {
+/// Access token carrying the user's roles, permissions and organisation, so
+/// services can authorise requests without asking corund.
+///
+/// `created` of the payload is set to now.
+pub fn new_at(payload: UserTokenPayload) -> Res<String> {
     let payload = UserTokenPayload {
-        user_id: user_id,
         created: utc(),
+        ..payload
     };
     new_token(&payload, b"helloworld")
 }
 
-pub fn verify_rt(rt: &String) -> Res<UserTokenPayload> {
+/// Verifies an access token made no earlier than `ttl` ago.
+///
+/// Services which disallow impersonation pass `allow_act` as false to reject
+/// tokens with the `act` claim.
+pub fn verify_at(
+    at: &str,
+    ttl: Time,
+    allow_act: bool,
+) -> Res<UserTokenPayload> {
+    let payload: UserTokenPayload = verify_token(at, b"helloworld")?;
+    payload.check_exp(ttl)?;
+    if payload.act.is_some() && !allow_act {
+        return res("impersonation_denied", "impersonation is not allowed");
+    }
+    Ok(payload)
+}
+
+pub fn verify_rt(rt: &str) -> Res<UserTokenPayload> {
     verify_token(rt, b"weloveauth")
 }
+
+/// Hashes a refresh token for storage, so leaked hashes cannot be used as
+/// tokens.
+///
+/// Refresh tokens are random enough to not require salting.
+pub fn hash_rt(rt: &str) -> String {
+    to_hex(&Sha256::digest(rt.as_bytes()))
+}
+
+/// Token proving the password was correct, which is exchanged for a refresh
+/// token once the second factor is passed too.
+///
+/// It's only accepted while it's nonce is stored, see `mfa::new_challenge`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MfaChallengePayload {
+    pub user_id: i32,
+    pub created: Time,
+    pub nonce: String,
+}
+
+impl Expire for MfaChallengePayload {
+    fn get_created(&self) -> Res<Time> {
+        Ok(self.created)
+    }
+}
+
+pub fn new_mfa_challenge(user_id: i32, nonce: &str) -> Res<String> {
+    let payload = MfaChallengePayload {
+        user_id,
+        created: utc(),
+        nonce: nonce.to_string(),
+    };
+    new_token(&payload, &derive_key(b"twofactors"))
+}
+
+/// Verifies a challenge made no earlier than `ttl` ago.
+pub fn verify_mfa_challenge(
+    challenge: &str,
+    ttl: Time,
+) -> Res<MfaChallengePayload> {
+    let payload: MfaChallengePayload =
+        verify_token(challenge, &derive_key(b"twofactors"))?;
+    payload.check_exp(ttl)?;
+    Ok(payload)
+}
+
+/// Access token issued to OAuth clients.
+///
+/// It's signed with it's own key derived from `token.secret`, so third-party
+/// clients cannot pass it's tokens as access tokens of `/rpc/access`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OauthTokenPayload {
+    pub user_id: i32,
+    pub created: Time,
+    pub client_id: String,
+    /// Space-separated granted scopes.
+    pub scope: String,
+}
+
+impl Expire for OauthTokenPayload {
+    fn get_created(&self) -> Res<Time> {
+        Ok(self.created)
+    }
+}
+
+pub fn new_oauth_at(
+    user_id: i32,
+    client_id: &str,
+    scope: &str,
+) -> Res<String> {
+    let payload = OauthTokenPayload {
+        user_id,
+        created: utc(),
+        client_id: client_id.to_string(),
+        scope: scope.to_string(),
+    };
+    new_token(&payload, &derive_key(b"oauthgrant"))
+}
+
+/// Verifies an access token made no earlier than `ttl` ago.
+pub fn verify_oauth_at(at: &str, ttl: Time) -> Res<OauthTokenPayload> {
+    let payload: OauthTokenPayload =
+        verify_token(at, &derive_key(b"oauthgrant"))?;
+    payload.check_exp(ttl)?;
+    Ok(payload)
+}
+
+/// Access token of a service client, accepted by `/rpc/server/*` routes
+/// within the granted scopes.
+///
+/// It's signed with it's own key derived from `token.secret`, so user tokens
+/// cannot be used instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceTokenPayload {
+    pub client_id: String,
+    pub created: Time,
+    /// Space-separated granted scopes.
+    pub scope: String,
+}
+
+impl Expire for ServiceTokenPayload {
+    fn get_created(&self) -> Res<Time> {
+        Ok(self.created)
+    }
+}
+
+pub fn new_service_at(client_id: &str, scope: &str) -> Res<String> {
+    let payload = ServiceTokenPayload {
+        client_id: client_id.to_string(),
+        created: utc(),
+        scope: scope.to_string(),
+    };
+    new_token(&payload, &derive_key(b"backendsvc"))
+}
+
+/// Client id of a service token with a valid signature, even if it's
+/// expired.
+pub fn get_service_client_id(at: &str) -> Option<String> {
+    let encoded_secret: Hmac<Sha256> =
+        Hmac::new_from_slice(&derive_key(b"backendsvc")).unwrap();
+    let payload: ServiceTokenPayload =
+        at.verify_with_key(&encoded_secret).ok()?;
+    Some(payload.client_id)
+}
+
+/// Verifies a service token made no earlier than `ttl` ago.
+pub fn verify_service_at(at: &str, ttl: Time) -> Res<ServiceTokenPayload> {
+    let payload: ServiceTokenPayload =
+        verify_token(at, &derive_key(b"backendsvc"))?;
+    payload.check_exp(ttl)?;
+    Ok(payload)
+}
@@ -0,0 +1,384 @@
+use std::time::Duration;
+
+use data_encoding::BASE64URL_NOPAD;
+use diesel::prelude::*;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{Con, Id},
+    quco::Collection,
+    ryz::{
+        err,
+        hex::to_hex,
+        res::Res,
+        time::{utc, Time},
+        url::encode_component,
+    },
+    schema,
+    user::{self, User},
+    verification::normalize_email,
+    Reg, UpstreamCfg, APPRC,
+};
+
+/// How long the provider's token endpoint is awaited.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Link of an user to their account at an external provider.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Identity {
+    pub id: Id,
+    pub created: Time,
+    /// Name of the provider in `upstream` config.
+    pub provider: String,
+    /// `sub` claim of the provider.
+    pub subject: String,
+    pub user_id: Id,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::identity)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct IdentityTable {
+    pub id: Id,
+    pub created: Time,
+    pub provider: String,
+    pub subject: String,
+    pub user_id: Id,
+}
+
+impl Collection<Identity> for IdentityTable {
+    fn to_msg(&self) -> Identity {
+        Identity {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            provider: self.provider.to_owned(),
+            subject: self.subject.to_owned(),
+            user_id: self.user_id.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::identity)]
+struct InsertIdentity {
+    created: Time,
+    provider: String,
+    subject: String,
+    user_id: Id,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::upstream_login)]
+struct InsertUpstreamLogin {
+    hstate: String,
+    created: Time,
+    provider: String,
+    nonce: String,
+    code_verifier: String,
+    expires: Time,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::upstream_login)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct UpstreamLoginTable {
+    provider: String,
+    nonce: String,
+    code_verifier: String,
+    expires: Time,
+}
+
+/// Where the client app sends the user to sign in at the provider.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpstreamRedirect {
+    pub url: String,
+}
+
+/// Claims of the provider's ID token.
+#[derive(Deserialize, Debug)]
+pub struct UpstreamClaims {
+    pub iss: String,
+    pub sub: String,
+    /// Either a single client id or a list of them.
+    pub aud: Value,
+    pub exp: f64,
+    pub nonce: Option<String>,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+    pub given_name: Option<String>,
+    pub middle_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenRes {
+    id_token: String,
+}
+
+fn cfg(provider: &str) -> Res<&'static UpstreamCfg> {
+    match APPRC.upstream.get(provider) {
+        Some(cfg) => Ok(cfg),
+        None => err::res(
+            "no_upstream",
+            format!("no upstream provider {}", provider).as_str(),
+        ),
+    }
+}
+
+fn hash_state(state: &str) -> String {
+    to_hex(&Sha256::digest(state.as_bytes()))
+}
+
+fn new_secret(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Starts a login at the provider, which redirects the user back to the
+/// configured page with a code and the state.
+pub fn begin(provider: &str, con: &mut Con) -> Res<UpstreamRedirect> {
+    let cfg = cfg(provider)?;
+    let state = new_secret(16);
+    let nonce = new_secret(16);
+    let code_verifier = new_secret(32);
+    let now = utc();
+    diesel::insert_into(schema::upstream_login::table)
+        .values(&InsertUpstreamLogin {
+            hstate: hash_state(&state),
+            created: now,
+            provider: provider.to_string(),
+            nonce: nonce.to_owned(),
+            code_verifier: code_verifier.to_owned(),
+            expires: now + cfg.login_ttl,
+        })
+        .execute(con)?;
+    let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(&code_verifier));
+    let query = [
+        ("response_type", "code"),
+        ("client_id", cfg.client_id.as_str()),
+        ("redirect_uri", cfg.redirect_uri.as_str()),
+        ("scope", cfg.scope.as_str()),
+        ("state", state.as_str()),
+        ("nonce", nonce.as_str()),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ]
+    .iter()
+    .map(|(k, v)| format!("{}={}", k, encode_component(v)))
+    .collect::<Vec<String>>()
+    .join("&");
+    let sep = if cfg.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    Ok(UpstreamRedirect {
+        url: format!("{}{}{}", cfg.authorization_endpoint, sep, query),
+    })
+}
+
+/// Decodes the payload of an ID token.
+///
+/// The signature is not checked, since the token is received directly from
+/// the provider's token endpoint, as allowed by OpenID Connect for the code
+/// flow.
+fn decode_id_token(id_token: &str) -> Option<UpstreamClaims> {
+    let payload = id_token.split('.').nth(1)?;
+    let payload = BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+fn check_claims(
+    cfg: &UpstreamCfg,
+    claims: &UpstreamClaims,
+    nonce: &str,
+) -> Res<()> {
+    let aud_ok = match &claims.aud {
+        Value::String(aud) => aud == &cfg.client_id,
+        Value::Array(aud) => aud.iter().any(|x| x == &cfg.client_id),
+        _ => false,
+    };
+    if claims.iss != cfg.issuer
+        || !aud_ok
+        || claims.exp < utc()
+        || claims.nonce.as_deref() != Some(nonce)
+    {
+        return err::res("upstream_err", "invalid id token");
+    }
+    Ok(())
+}
+
+/// Exchanges the code received by the redirect page for the provider's
+/// claims of the user.
+///
+/// Returns the provider name and the claims.
+pub async fn finish(
+    state: &str,
+    code: &str,
+    con: &mut Con,
+) -> Res<(String, UpstreamClaims)> {
+    // states are single-use, so they're taken even if the exchange fails
+    let Some(login) = diesel::delete(
+        schema::upstream_login::table
+            .filter(schema::upstream_login::hstate.eq(hash_state(state))),
+    )
+    .returning(UpstreamLoginTable::as_returning())
+    .get_result(con)
+    .optional()?
+    else {
+        return err::res("invalid_state", "no such login");
+    };
+    if login.expires < utc() {
+        return err::res("invalid_state", "expired login");
+    }
+    let cfg = cfg(&login.provider)?;
+
+    let res = reqwest::Client::new()
+        .post(&cfg.token_endpoint)
+        .timeout(EXCHANGE_TIMEOUT)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", cfg.redirect_uri.as_str()),
+            ("code_verifier", login.code_verifier.as_str()),
+            ("client_id", cfg.client_id.as_str()),
+            ("client_secret", cfg.client_secret.as_str()),
+        ])
+        .send()
+        .await;
+    let Ok(res) = res else {
+        return err::res("upstream_err", "provider is unavailable");
+    };
+    if !res.status().is_success() {
+        return err::res(
+            "upstream_err",
+            format!("provider responded with {}", res.status()).as_str(),
+        );
+    }
+    let Ok(res) = res.json::<TokenRes>().await else {
+        return err::res("upstream_err", "no id token");
+    };
+    let Some(claims) = decode_id_token(&res.id_token) else {
+        return err::res("upstream_err", "malformed id token");
+    };
+    check_claims(cfg, &claims, &login.nonce)?;
+    Ok((login.provider, claims))
+}
+
+fn get_identity(
+    provider: &str,
+    subject: &str,
+    con: &mut Con,
+) -> Res<Option<Identity>> {
+    Ok(schema::identity::table
+        .filter(schema::identity::provider.eq(provider))
+        .filter(schema::identity::subject.eq(subject))
+        .select(IdentityTable::as_select())
+        .first(con)
+        .optional()?
+        .map(|x| x.to_msg()))
+}
+
+pub fn get_many(user_id: Id, con: &mut Con) -> Res<Vec<Identity>> {
+    Ok(schema::identity::table
+        .filter(schema::identity::user_id.eq(user_id))
+        .order(schema::identity::id.asc())
+        .select(IdentityTable::as_select())
+        .load(con)?
+        .iter()
+        .map(|x| x.to_msg())
+        .collect())
+}
+
+/// Links the provider's account to the user.
+pub fn link(
+    user_id: Id,
+    provider: &str,
+    claims: &UpstreamClaims,
+    con: &mut Con,
+) -> Res<Identity> {
+    if let Some(identity) = get_identity(provider, &claims.sub, con)? {
+        if identity.user_id != user_id {
+            return err::res(
+                "identity_taken",
+                "identity is linked to another user",
+            );
+        }
+        return Ok(identity);
+    }
+    let identity: IdentityTable = diesel::insert_into(schema::identity::table)
+        .values(&InsertIdentity {
+            created: utc(),
+            provider: provider.to_string(),
+            subject: claims.sub.to_owned(),
+            user_id,
+        })
+        .returning(IdentityTable::as_returning())
+        .get_result(con)?;
+    Ok(identity.to_msg())
+}
+
+/// Creates an user for the provider's account.
+///
+/// The username is taken from the claims if it's valid and free, otherwise
+/// it's random. The password is random and never shown, so the user signs
+/// in at the provider until they reset it. The email stays unverified.
+fn provision(claims: &UpstreamClaims, con: &mut Con) -> Res<User> {
+    let email = claims
+        .email
+        .as_deref()
+        .filter(|x| normalize_email(x).is_ok());
+    let mut usernames: Vec<String> = vec![];
+    if let Some(username) = &claims.preferred_username {
+        usernames.push(username.to_owned());
+    }
+    if let Some((local, _)) = email.and_then(|x| x.split_once('@')) {
+        usernames.push(local.to_string());
+    }
+    usernames.push("user".to_string() + &new_secret(4));
+    let password = new_secret(16) + "Aa1!";
+    let mut last = err::res_msg("no username");
+    for username in usernames {
+        let reg = Reg {
+            username,
+            password: password.to_owned(),
+            firstname: claims.given_name.to_owned(),
+            patronym: claims.middle_name.to_owned(),
+            surname: claims.family_name.to_owned(),
+            email: email.map(|x| x.to_string()),
+            phone: None,
+            metadata: Default::default(),
+        };
+        last = user::new(&reg, con);
+        if last.is_ok() {
+            break;
+        }
+    }
+    last
+}
+
+/// Finds the user linked to the provider's account, creating one if the
+/// provider allows it.
+pub fn get_or_provision(
+    provider: &str,
+    claims: &UpstreamClaims,
+    con: &mut Con,
+) -> Res<User> {
+    if let Some(identity) = get_identity(provider, &claims.sub, con)? {
+        return user::get_by_id(identity.user_id, con);
+    }
+    if !cfg(provider)?.auto_provision {
+        return err::res("no_identity", "identity is not linked to any user");
+    }
+    con.transaction(|con| {
+        let user = provision(claims, con)?;
+        link(user.id, provider, claims, con)?;
+        Ok(user)
+    })
+}
@@ -1,20 +1,66 @@
-use diesel::prelude::*;
+use diesel::{
+    dsl::sql,
+    pg::Pg,
+    prelude::*,
+    sql_types::{Bool, Nullable},
+};
 use serde::{Deserialize, Serialize};
-use serde_json;
+use serde_json::{self, json, Map, Value};
 
 use crate::{
     db::{Con, Id},
-    password::hash_password,
-    quco::{Collection, Query},
-    ryz::{dict, err, res::Res},
+    org,
+    password::{check_policy, hash_password},
+    quco::{
+        self, col_cond, col_order, json_cond, BoxedCond, BoxedOrder,
+        Collection, Field, FieldType, Op, Query, QueryCollection, Search,
+        Sort,
+    },
+    ryz::{
+        err,
+        res::Res,
+        time::{utc, Time},
+    },
     schema,
+    token::hash_rt,
     user_change::{self, ChangeAction, NewUserChange},
-    InsertReg, Reg,
+    username,
+    verification::{normalize_email, normalize_phone},
+    webauthn, InsertReg, Reg, APPRC,
 };
 
+pub type GetUsers = Search;
+
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GetUsers {
-    pub sq: Query,
+pub struct GetUsersRes<T> {
+    pub users: Vec<T>,
+    /// Amount of all users matching the query, if requested.
+    pub total: Option<i64>,
+    /// Cursor for the next page, if the page is full and the sort supports
+    /// cursors.
+    pub next: Option<Vec<Value>>,
+}
+
+/// Custom data of an user, split by who may write it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Metadata {
+    /// Written by domains only, e.g. a billing plan.
+    #[serde(default)]
+    pub domain: Map<String, Value>,
+    /// Written by the user too, e.g. preferences.
+    #[serde(default)]
+    pub user: Map<String, Value>,
+}
+
+/// Keys to set in metadata namespaces, other keys are kept. Null values
+/// remove the keys.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateMetadata {
+    pub user_id: Id,
+    #[serde(default)]
+    pub domain: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub user: Option<Map<String, Value>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,7 +70,13 @@ pub struct User {
     pub firstname: Option<String>,
     pub patronym: Option<String>,
     pub surname: Option<String>,
-    pub rt: Option<String>,
+    pub email: Option<String>,
+    pub email_verified_at: Option<Time>,
+    pub phone: Option<String>,
+    pub phone_verified_at: Option<Time>,
+    pub metadata: Metadata,
+    pub created: Time,
+    pub updated: Time,
 }
 
 impl PartialEq for User {
@@ -34,7 +86,13 @@ impl PartialEq for User {
             && self.firstname == other.firstname
             && self.patronym == other.patronym
             && self.surname == other.surname
-            && self.rt == other.rt
+            && self.email == other.email
+            && self.email_verified_at == other.email_verified_at
+            && self.phone == other.phone
+            && self.phone_verified_at == other.phone_verified_at
+            && self.metadata == other.metadata
+            && self.created == other.created
+            && self.updated == other.updated
     }
 }
 
@@ -48,9 +106,20 @@ pub struct UserTable {
     pub firstname: Option<String>,
     pub patronym: Option<String>,
     pub surname: Option<String>,
-    pub rt: Option<String>,
+    /// Hash of the refresh token, see `token::hash_rt`.
+    pub hrt: Option<String>,
+    pub created: Time,
+    pub updated: Time,
+    /// See `username::fold`.
+    pub nusername: String,
+    pub email: Option<String>,
+    pub email_verified_at: Option<Time>,
+    pub phone: Option<String>,
+    pub phone_verified_at: Option<Time>,
+    pub metadata: Value,
 }
 
+/// Public shape of a user, which never includes password or refresh token.
 impl Collection<User> for UserTable {
     fn to_msg(&self) -> User {
         User {
@@ -59,85 +128,205 @@ impl Collection<User> for UserTable {
             firstname: self.firstname.to_owned(),
             patronym: self.patronym.to_owned(),
             surname: self.surname.to_owned(),
-            rt: self.rt.to_owned(),
+            email: self.email.to_owned(),
+            email_verified_at: self.email_verified_at.to_owned(),
+            phone: self.phone.to_owned(),
+            phone_verified_at: self.phone_verified_at.to_owned(),
+            metadata: serde_json::from_value(self.metadata.to_owned())
+                .unwrap_or_default(),
+            created: self.created.to_owned(),
+            updated: self.updated.to_owned(),
         }
     }
 }
 
+/// Registers a new user.
+///
+/// The username must be valid, see `username::check`, and is unique
+/// case-insensitively. The password must satisfy the password policy, see
+/// `password::check_policy`.
+///
+/// The user and it's change are written in one transaction, so the user
+/// always appears in the change feed.
 pub fn new(reg: &Reg, con: &mut Con) -> Res<User> {
-    if reg.username.starts_with("archive::") {
-        return err::res_msg("cannot accept archived usernames");
-    }
+    username::check(&reg.username)?;
+    check_policy(&reg.username, &reg.password)?;
+    let email = reg.email.as_deref().map(normalize_email).transpose()?;
+    let phone = reg.phone.as_deref().map(normalize_phone).transpose()?;
+    let metadata = check_metadata(&reg.metadata)?;
     let hpassword = hash_password(&reg.password).unwrap();
-    let user: UserTable = diesel::insert_into(schema::appuser::table)
-        .values(&InsertReg {
-            username: reg.username.to_owned(),
-            hpassword: hpassword.to_owned(),
-            firstname: reg.firstname.to_owned(),
-            patronym: reg.patronym.to_owned(),
-            surname: reg.surname.to_owned(),
-        })
+    let now = utc();
+    con.transaction(|con| {
+        let user: UserTable = diesel::insert_into(schema::appuser::table)
+            .values(&InsertReg {
+                username: username::normalize(&reg.username),
+                nusername: username::fold(&reg.username),
+                hpassword: hpassword.to_owned(),
+                firstname: reg.firstname.to_owned(),
+                patronym: reg.patronym.to_owned(),
+                surname: reg.surname.to_owned(),
+                email,
+                phone,
+                metadata,
+                created: now,
+                updated: now,
+            })
+            .returning(UserTable::as_returning())
+            .get_result(con)?;
+        let user = user.to_msg();
+
+        user_change::new(
+            &NewUserChange {
+                user_id: user.id,
+                action: ChangeAction::New,
+                data: Some(serde_json::to_value(&user).unwrap()),
+            },
+            con,
+        )?;
+
+        Ok(user)
+    })
+}
+
+/// Serializes metadata for storage, failing if it's larger than allowed by
+/// `metadata.max_size` config.
+fn check_metadata(metadata: &Metadata) -> Res<Value> {
+    let value = serde_json::to_value(metadata).unwrap();
+    if value.to_string().len() > APPRC.metadata.max_size {
+        return err::res("metadata_too_large", "metadata is too large");
+    }
+    Ok(value)
+}
+
+fn merge(into: &mut Map<String, Value>, keys: &Map<String, Value>) {
+    for (k, v) in keys {
+        if v.is_null() {
+            into.remove(k);
+        } else {
+            into.insert(k.to_owned(), v.to_owned());
+        }
+    }
+}
+
+/// Sets metadata keys of an active user, see `UpdateMetadata`.
+///
+/// The new metadata is written to an `Update` change.
+pub fn update_metadata(inp: &UpdateMetadata, con: &mut Con) -> Res<User> {
+    con.transaction(|con| {
+        let user: UserTable = schema::appuser::table
+            .filter(schema::appuser::id.eq(inp.user_id))
+            .filter(schema::appuser::username.not_like("archived::%"))
+            .select(UserTable::as_select())
+            .for_update()
+            .first(con)?;
+        let mut metadata = user.to_msg().metadata;
+        if let Some(domain) = &inp.domain {
+            merge(&mut metadata.domain, domain);
+        }
+        if let Some(user) = &inp.user {
+            merge(&mut metadata.user, user);
+        }
+        let user: UserTable = diesel::update(
+            schema::appuser::table.filter(schema::appuser::id.eq(inp.user_id)),
+        )
+        .set((
+            schema::appuser::metadata.eq(check_metadata(&metadata)?),
+            schema::appuser::updated.eq(utc()),
+        ))
         .returning(UserTable::as_returning())
-        .get_result(con)
-        .unwrap();
+        .get_result(con)?;
 
-    user_change::new(
-        &NewUserChange {
-            user_id: user.id,
-            action: ChangeAction::New,
-        },
-        con,
-    )
-    .unwrap();
+        user_change::new(
+            &NewUserChange {
+                user_id: user.id,
+                action: ChangeAction::Update,
+                data: Some(json!({"metadata": metadata})),
+            },
+            con,
+        )?;
+        Ok(user.to_msg())
+    })
+}
 
-    Ok(user.to_msg())
+/// Picks metadata keys listed by `metadata.claims` config, e.g.
+/// `domain.plan`, keeping their namespaces.
+///
+/// Returns `None` if the user has none of the keys.
+pub fn get_metadata_claims(metadata: &Metadata) -> Option<Metadata> {
+    let mut claims = Metadata::default();
+    for key in APPRC.metadata.claims.iter() {
+        let (from, into, k) = match key.split_once('.') {
+            Some(("domain", k)) => (&metadata.domain, &mut claims.domain, k),
+            Some(("user", k)) => (&metadata.user, &mut claims.user, k),
+            _ => continue,
+        };
+        if let Some(v) = from.get(k) {
+            into.insert(k.to_string(), v.to_owned());
+        }
+    }
+    if claims == Metadata::default() {
+        return None;
+    }
+    Some(claims)
 }
 
 /// Instead of deletion, users are archived, their usernames are changed to
 /// be `archive::<username>` and they are no more accessible. This needs to be
 /// done due to user_change synchronization needs, the changes will still point
 /// to the archived user.
+///
+/// The archivation and it's change are written in one transaction.
 pub fn del(sq: &Query, con: &mut Con) -> Res<()> {
     let id = sq.get("id");
     let username = sq.get("username");
     let mut q = schema::appuser::table.into_boxed();
-    if id.is_some() {
-        let val = serde_json::from_value::<Id>(id.unwrap().clone()).unwrap();
+    if let Some(id) = id {
+        let val = serde_json::from_value::<Id>(id.clone()).unwrap();
         q = q.filter(schema::appuser::id.eq(val));
     }
-    if username.is_some() {
+    if let Some(username) = username {
         let username =
-            serde_json::from_value::<String>(username.unwrap().clone())
-                .unwrap();
+            serde_json::from_value::<String>(username.clone()).unwrap();
         if username.starts_with("archive::") {
             return err::res_msg("cannot accept archived usernames");
         }
-        q = q.filter(schema::appuser::username.eq(username));
+        q = q
+            .filter(schema::appuser::nusername.eq(username::fold(&username)))
+            .filter(schema::appuser::username.not_like("archived::%"));
     }
 
-    let username = q
-        .select(schema::appuser::username)
-        .get_result::<String>(con)
-        .unwrap();
-    let archived_username = "archived::".to_string() + username.as_str();
+    con.transaction(|con| {
+        // snapshot is taken before the archivation, so the change retains
+        // the original username
+        let user = q
+            .select(UserTable::as_select())
+            .get_result::<UserTable>(con)?
+            .to_msg();
+        let archived_username =
+            "archived::".to_string() + user.username.as_str();
 
-    let id = diesel::update(schema::appuser::table)
-        .filter(schema::appuser::username.eq(username))
-        .set(schema::appuser::username.eq(archived_username))
-        .returning(schema::appuser::id)
-        .get_result::<Id>(con)
-        .unwrap();
+        let id = diesel::update(schema::appuser::table)
+            .filter(schema::appuser::username.eq(&user.username))
+            .set((
+                schema::appuser::username.eq(archived_username),
+                schema::appuser::updated.eq(utc()),
+            ))
+            .returning(schema::appuser::id)
+            .get_result::<Id>(con)?;
+        // passkeys are bound to the user only, so they would keep working
+        webauthn::del_all(id, con)?;
 
-    user_change::new(
-        &NewUserChange {
-            user_id: id,
-            action: ChangeAction::Del,
-        },
-        con,
-    )
-    .unwrap();
+        user_change::new(
+            &NewUserChange {
+                user_id: id,
+                action: ChangeAction::Del,
+                data: Some(serde_json::to_value(&user).unwrap()),
+            },
+            con,
+        )?;
 
-    Ok(())
+        Ok(())
+    })
 }
 
 pub fn get_by_id(id: i32, con: &mut Con) -> Res<User> {
@@ -145,59 +334,83 @@ pub fn get_by_id(id: i32, con: &mut Con) -> Res<User> {
         .filter(schema::appuser::id.eq(id))
         .filter(schema::appuser::username.not_like("archived::%"))
         .select(UserTable::as_select())
-        .first(con)
-        .unwrap()
+        .first(con)?
         .to_msg())
 }
 
-pub fn get_by_username(
-    username: &String,
-    con: &mut Con,
-) -> Res<(User, String)> {
+/// Finds an active user by username, compared case-insensitively, see
+/// `username::fold`.
+pub fn get_by_username(username: &str, con: &mut Con) -> Res<(User, String)> {
     if username.starts_with("archive::") {
         return err::res_msg("cannot accept archived usernames");
     }
-    let user: UserTable = schema::appuser::table
-        .filter(schema::appuser::username.eq(username))
+    let Some(user) = schema::appuser::table
+        .filter(schema::appuser::nusername.eq(username::fold(username)))
+        .filter(schema::appuser::username.not_like("archived::%"))
         .select(UserTable::as_select())
         .first(con)
-        .unwrap();
+        .optional()?
+    else {
+        return err::res_msg("no such user");
+    };
+    Ok((user.to_msg(), user.hpassword))
+}
+
+/// Finds an active user by login, which is either a verified email, a
+/// verified phone or a username.
+///
+/// Logins with `@` are treated as emails and logins starting with `+` as
+/// phones.
+pub fn get_by_login(login: &str, con: &mut Con) -> Res<(User, String)> {
+    let q = schema::appuser::table
+        .filter(schema::appuser::username.not_like("archived::%"))
+        .select(UserTable::as_select());
+    let user: UserTable = if login.contains('@') {
+        q.filter(schema::appuser::email.eq(normalize_email(login)?))
+            .filter(schema::appuser::email_verified_at.is_not_null())
+            .first(con)?
+    } else if login.starts_with('+') {
+        q.filter(schema::appuser::phone.eq(normalize_phone(login)?))
+            .filter(schema::appuser::phone_verified_at.is_not_null())
+            .first(con)?
+    } else {
+        q.filter(schema::appuser::nusername.eq(username::fold(login)))
+            .first(con)?
+    };
     Ok((user.to_msg(), user.hpassword))
 }
 
-pub fn get_by_rt(rt: &String, con: &mut Con) -> Res<(User, String)> {
+pub fn get_by_rt(rt: &str, con: &mut Con) -> Res<(User, String)> {
     let user: UserTable = schema::appuser::table
-        .filter(schema::appuser::rt.eq(rt))
+        .filter(schema::appuser::hrt.eq(hash_rt(rt)))
         .filter(schema::appuser::username.not_like("archived::%"))
         .select(UserTable::as_select())
-        .first(con)
-        .unwrap();
+        .first(con)?;
     Ok((user.to_msg(), user.hpassword))
 }
 
-pub fn del_rt(rt: &String, con: &mut Con) -> Res<()> {
+pub fn del_rt(rt: &str, con: &mut Con) -> Res<()> {
     // here we can delete rt even for archived users
-    diesel::update(schema::appuser::table.filter(schema::appuser::rt.eq(rt)))
-        .set(schema::appuser::rt.eq::<Option<String>>(None))
-        .execute(con)
-        .unwrap();
+    diesel::update(
+        schema::appuser::table.filter(schema::appuser::hrt.eq(hash_rt(rt))),
+    )
+    .set(schema::appuser::hrt.eq::<Option<String>>(None))
+    .execute(con)?;
     Ok(())
 }
 
-pub fn set_rt_for_username(
-    username: &String,
-    rt: &String,
-    con: &mut Con,
-) -> Res<()> {
-    if username.starts_with("archive::") {
-        return err::res_msg("cannot accept archived usernames");
-    }
-    diesel::update(
-        schema::appuser::table.filter(schema::appuser::username.eq(username)),
-    )
-    .set(schema::appuser::rt.eq::<Option<String>>(Some(rt.to_owned())))
-    .execute(con)
-    .unwrap();
+pub fn set_rt(id: Id, rt: &str, con: &mut Con) -> Res<()> {
+    diesel::update(schema::appuser::table.filter(schema::appuser::id.eq(id)))
+        .set(schema::appuser::hrt.eq::<Option<String>>(Some(hash_rt(rt))))
+        .execute(con)?;
+    Ok(())
+}
+
+/// Replaces the password hash of a user.
+pub fn set_hpassword(id: Id, hpassword: &str, con: &mut Con) -> Res<()> {
+    diesel::update(schema::appuser::table.filter(schema::appuser::id.eq(id)))
+        .set(schema::appuser::hpassword.eq(hpassword))
+        .execute(con)?;
     Ok(())
 }
 
@@ -210,47 +423,165 @@ pub fn get_many_as_ids(con: &mut Con) -> Res<Vec<Id>> {
     Ok(ids)
 }
 
-pub fn get_many(sq: Query, con: &mut PgConnection) -> Res<Vec<User>> {
-    let mut q = schema::appuser::table.into_boxed();
-    for (k, v) in sq {
-        match k.as_str() {
-            // we only support either direct `{"field": value}` or `$in` search
-            // `{"field": {"$in": [...]}}`
-            //
-            // for now `in` also supported only for id
-            "id" => {
-                let parsed = serde_json::from_value::<
-                    dict::dict<String, Vec<Id>>,
-                >(v.clone());
-                if parsed.is_err() {
-                    let parsed = serde_json::from_value::<Id>(v).unwrap();
-                    q = q.filter(schema::appuser::id.eq(parsed));
-                } else {
-                    let parsed = parsed.unwrap().get("$in").unwrap().clone();
-                    q = q.filter(schema::appuser::id.eq_any(parsed));
-                }
-            }
+/// Queryable user fields.
+///
+/// `status` is derived from the archivation of a user and is either
+/// `active` or `archived`. `org_id` matches organisations the user is a
+/// member of, see `org::member_cond`. `metadata` is queried by paths, e.g.
+/// `metadata.user.locale`.
+pub const FIELDS: &[Field] = &[
+    Field::new("id", FieldType::Int),
+    Field::new("username", FieldType::Str),
+    Field::new("firstname", FieldType::Str).nullable(),
+    Field::new("patronym", FieldType::Str).nullable(),
+    Field::new("surname", FieldType::Str).nullable(),
+    Field::new("email", FieldType::Str).nullable(),
+    Field::new("email_verified_at", FieldType::Float).nullable(),
+    Field::new("phone", FieldType::Str).nullable(),
+    Field::new("phone_verified_at", FieldType::Float).nullable(),
+    Field::new("status", FieldType::Enum(&["active", "archived"])).derived(),
+    Field::new("org_id", FieldType::Int)
+        .nullable()
+        .derived()
+        .unsortable(),
+    Field::new("metadata", FieldType::Json).unsortable(),
+    Field::new("created", FieldType::Float),
+    Field::new("updated", FieldType::Float),
+];
+
+fn status_cond(op: &Op) -> BoxedCond<schema::appuser::table> {
+    let all = ["active", "archived"];
+    let statuses: Vec<&str> = match op {
+        Op::Eq(v) => vec![v.as_str()],
+        Op::Ne(v) => all.into_iter().filter(|x| *x != v.as_str()).collect(),
+        Op::In(vs) => vs.iter().map(|x| x.as_str()).collect(),
+        Op::Nin(vs) => all
+            .into_iter()
+            .filter(|x| !vs.iter().any(|v| v.as_str() == *x))
+            .collect(),
+        _ => unreachable!(),
+    };
+    let archived = statuses.contains(&"archived");
+    let active = statuses.contains(&"active");
+    match (active, archived) {
+        (true, true) => Box::new(sql::<Nullable<Bool>>("TRUE")),
+        (false, false) => Box::new(sql::<Nullable<Bool>>("FALSE")),
+        (true, false) => Box::new(
+            schema::appuser::username.not_like("archived::%").nullable(),
+        ),
+        (false, true) => {
+            Box::new(schema::appuser::username.like("archived::%").nullable())
+        }
+    }
+}
+
+impl QueryCollection for UserTable {
+    type Table = schema::appuser::table;
+    type Query = schema::appuser::BoxedQuery<'static, Pg>;
+
+    const FIELDS: &'static [Field] = FIELDS;
+
+    fn query() -> Self::Query {
+        schema::appuser::table.into_boxed()
+    }
+
+    fn cond(field: &str, op: &Op) -> BoxedCond<Self::Table> {
+        match field {
+            "id" => col_cond!(schema::appuser::id, op, as_int),
             "username" => {
-                let v = serde_json::from_value::<String>(v).unwrap();
-                q = q.filter(schema::appuser::username.eq(v));
-                // if username.starts_with("archive::") {
-                //     return err::res_msg("cannot accept archived usernames");
-                // }
+                col_cond!(schema::appuser::username, op, as_str, text)
             }
             "firstname" => {
-                let v = serde_json::from_value::<String>(v).unwrap();
-                q = q.filter(schema::appuser::firstname.eq(v));
+                col_cond!(schema::appuser::firstname, op, as_str, text)
             }
-            _ => return err::res_msg("unrecognized field"),
+            "patronym" => {
+                col_cond!(schema::appuser::patronym, op, as_str, text)
+            }
+            "surname" => {
+                col_cond!(schema::appuser::surname, op, as_str, text)
+            }
+            "email" => col_cond!(schema::appuser::email, op, as_str, text),
+            "email_verified_at" => {
+                col_cond!(schema::appuser::email_verified_at, op, as_float)
+            }
+            "phone" => col_cond!(schema::appuser::phone, op, as_str, text),
+            "phone_verified_at" => {
+                col_cond!(schema::appuser::phone_verified_at, op, as_float)
+            }
+            "status" => status_cond(op),
+            "org_id" => org::member_cond(op),
+            "created" => col_cond!(schema::appuser::created, op, as_float),
+            "updated" => col_cond!(schema::appuser::updated, op, as_float),
+            _ => unreachable!(),
         }
     }
 
-    let users: Vec<User> = q
-        .select(UserTable::as_select())
-        .get_results(con)
-        .unwrap()
-        .iter()
-        .map(|x| x.to_msg())
-        .collect();
-    Ok(users)
+    fn path_cond(
+        field: &str,
+        path: &[String],
+        op: &Op,
+    ) -> BoxedCond<Self::Table> {
+        match field {
+            "metadata" => json_cond!(schema::appuser::metadata, path, op),
+            _ => unreachable!(),
+        }
+    }
+
+    fn order(sort: &Sort) -> BoxedOrder<Self::Table> {
+        match sort.field {
+            "id" => col_order!(schema::appuser::id, sort),
+            "username" => col_order!(schema::appuser::username, sort),
+            "firstname" => col_order!(schema::appuser::firstname, sort),
+            "patronym" => col_order!(schema::appuser::patronym, sort),
+            "surname" => col_order!(schema::appuser::surname, sort),
+            "email" => col_order!(schema::appuser::email, sort),
+            "email_verified_at" => {
+                col_order!(schema::appuser::email_verified_at, sort)
+            }
+            "phone" => col_order!(schema::appuser::phone, sort),
+            "phone_verified_at" => {
+                col_order!(schema::appuser::phone_verified_at, sort)
+            }
+            // active go before archived, same as by name
+            "status" => {
+                col_order!(schema::appuser::username.like("archived::%"), sort)
+            }
+            "created" => col_order!(schema::appuser::created, sort),
+            "updated" => col_order!(schema::appuser::updated, sort),
+            _ => unreachable!(),
+        }
+    }
+
+    fn load(q: Self::Query, con: &mut Con) -> Res<Vec<Value>> {
+        Ok(q.select(UserTable::as_select())
+            .get_results(con)?
+            .iter()
+            .map(|x| serde_json::to_value(x.to_msg()).unwrap())
+            .collect())
+    }
+
+    fn load_fields(
+        q: Self::Query,
+        fields: &[&Field],
+        con: &mut Con,
+    ) -> Res<Vec<Value>> {
+        Ok(q.select(quco::select_fields(fields)).get_results(con)?)
+    }
+
+    fn count(q: Self::Query, con: &mut Con) -> Res<i64> {
+        Ok(q.count().get_result(con)?)
+    }
+}
+
+/// Fetches users matching the search query.
+///
+/// See `quco::find` for the search semantics and `FIELDS` for the queryable
+/// fields.
+pub fn get_many(inp: &GetUsers, con: &mut Con) -> Res<GetUsersRes<Value>> {
+    let found = quco::find::<UserTable>(inp, con)?;
+    Ok(GetUsersRes {
+        users: found.items,
+        total: found.total,
+        next: found.next,
+    })
 }
@@ -1,9 +1,20 @@
-use diesel::prelude::*;
+use std::{slice, sync::Mutex, thread, time::Duration};
+
+use diesel::{pg::Pg, prelude::*};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{
+    broadcast::{self, error::RecvError, error::TryRecvError},
+    mpsc,
+};
 
 use crate::{
-    db::{Con, Id},
-    quco::Collection,
+    db::{self, Con, Id},
+    quco::{
+        self, col_cond, col_order, BoxedCond, BoxedOrder, Collection, Cond,
+        Field, FieldType, Op, Query, QueryCollection, Search, Sort,
+    },
     ryz::{
         enm::StrEnum,
         err,
@@ -11,37 +22,106 @@ use crate::{
         time::{utc, Time},
     },
     schema,
+    webhook::{self, WebhookEvent},
 };
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ChangeAction {
     New,
     Del,
+    /// Fields of the user were changed, the data holds their new values.
+    Update,
+    /// Roles of the user were assigned or revoked.
+    Roles,
+    /// The user joined, left or changed role in an organisation.
+    Membership,
+    /// An access token of the user was issued to an impersonator.
+    Impersonation,
 }
 
-impl StrEnum for ChangeAction {
-    fn to_str(&self) -> &str {
+impl ChangeAction {
+    pub const ALL: [ChangeAction; 6] = [
+        ChangeAction::New,
+        ChangeAction::Del,
+        ChangeAction::Update,
+        ChangeAction::Roles,
+        ChangeAction::Membership,
+        ChangeAction::Impersonation,
+    ];
+
+    const fn as_str(&self) -> &'static str {
         match self {
             ChangeAction::New => "new",
             ChangeAction::Del => "del",
+            ChangeAction::Update => "update",
+            ChangeAction::Roles => "roles",
+            ChangeAction::Membership => "membership",
+            ChangeAction::Impersonation => "impersonation",
         }
     }
+}
+
+impl StrEnum for ChangeAction {
+    fn to_str(&self) -> &str {
+        self.as_str()
+    }
 
     fn from_str(s: &str) -> Res<Self> {
-        match s {
-            "new" => Ok(ChangeAction::New),
-            "del" => Ok(ChangeAction::Del),
-            _ => err::res_default(),
+        match ChangeAction::ALL.iter().find(|x| x.as_str() == s) {
+            Some(x) => Ok(*x),
+            None => err::res_default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Names of all actions, as they're stored and queried.
+const ACTIONS: [&str; ChangeAction::ALL.len()] = {
+    let mut names = [""; ChangeAction::ALL.len()];
+    let mut i = 0;
+    while i < names.len() {
+        names[i] = ChangeAction::ALL[i].as_str();
+        i += 1;
+    }
+    names
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserChange {
     pub id: Id,
     pub created: Time,
     pub action: ChangeAction,
     pub user_id: Id,
+    /// Snapshot of the user for `New`/`Del` changes, or the changed fields
+    /// for updates. Only sent if requested via `GetUserChanges::with_data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetUserChanges {
+    /// From which time to fetch changes.
+    pub from: Time,
+    /// Only fetch changes with these actions.
+    pub actions: Option<Vec<ChangeAction>>,
+    /// Only fetch changes of these users.
+    pub user_ids: Option<Vec<Id>>,
+    /// Whether to embed change data into each change.
+    #[serde(default)]
+    pub with_data: bool,
+    /// Additional search over `FIELDS`, see `quco::find`. Streams only use
+    /// it's query.
+    #[serde(flatten)]
+    pub search: Search,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetUserChangesRes<T> {
+    pub changes: Vec<T>,
+    /// Amount of all changes matching the query, if requested.
+    pub total: Option<i64>,
+    /// Cursor for the next page, if the page is full and the sort supports
+    /// cursors.
+    pub next: Option<Vec<Value>>,
 }
 
 #[derive(Queryable, Selectable)]
@@ -52,6 +132,7 @@ pub struct UserChangeTable {
     pub created: Time,
     pub action: String,
     pub user_id: Id,
+    pub data: Option<Value>,
 }
 
 impl Collection<UserChange> for UserChangeTable {
@@ -61,6 +142,7 @@ impl Collection<UserChange> for UserChangeTable {
             created: self.created.to_owned(),
             action: ChangeAction::from_str(self.action.as_str()).unwrap(),
             user_id: self.user_id.to_owned(),
+            data: self.data.to_owned(),
         }
     }
 }
@@ -68,6 +150,7 @@ impl Collection<UserChange> for UserChangeTable {
 pub struct NewUserChange {
     pub user_id: Id,
     pub action: ChangeAction,
+    pub data: Option<Value>,
 }
 
 #[derive(Insertable)]
@@ -76,32 +159,330 @@ struct InsertNewUserChange {
     pub user_id: Id,
     pub created: Time,
     pub action: String,
+    pub data: Option<Value>,
+}
+
+/// Postgres channel notified with an id of every inserted user change.
+pub const NOTIFY_CHANNEL: &str = "user_change";
+
+/// How often the stream listener checks for new notifications.
+const LISTEN_INTERVAL: Duration = Duration::from_millis(100);
+/// How many notified ids a stream may fall behind before it's closed.
+const LISTEN_CAPACITY: usize = 1024;
+
+lazy_static::lazy_static! {
+    /// Sender of ids notified to the listener shared by all streams, while
+    /// any stream is open.
+    static ref LISTENER: Mutex<Option<broadcast::Sender<Id>>> =
+        Mutex::new(None);
+}
+
+/// Queryable user change fields.
+pub const FIELDS: &[Field] = &[
+    Field::new("id", FieldType::Int),
+    Field::new("created", FieldType::Float),
+    Field::new("action", FieldType::Enum(&ACTIONS)),
+    Field::new("user_id", FieldType::Int),
+];
+
+impl QueryCollection for UserChangeTable {
+    type Table = schema::user_change::table;
+    type Query = schema::user_change::BoxedQuery<'static, Pg>;
+
+    const FIELDS: &'static [Field] = FIELDS;
+
+    fn query() -> Self::Query {
+        schema::user_change::table.into_boxed()
+    }
+
+    fn cond(field: &str, op: &Op) -> BoxedCond<Self::Table> {
+        match field {
+            "id" => col_cond!(schema::user_change::id, op, as_int),
+            "created" => col_cond!(schema::user_change::created, op, as_float),
+            "action" => col_cond!(schema::user_change::action, op, as_str),
+            "user_id" => col_cond!(schema::user_change::user_id, op, as_int),
+            _ => unreachable!(),
+        }
+    }
+
+    fn order(sort: &Sort) -> BoxedOrder<Self::Table> {
+        match sort.field {
+            "id" => col_order!(schema::user_change::id, sort),
+            "created" => col_order!(schema::user_change::created, sort),
+            "action" => col_order!(schema::user_change::action, sort),
+            "user_id" => col_order!(schema::user_change::user_id, sort),
+            _ => unreachable!(),
+        }
+    }
+
+    fn load(q: Self::Query, con: &mut Con) -> Res<Vec<Value>> {
+        Ok(q.select(UserChangeTable::as_select())
+            .get_results(con)?
+            .iter()
+            .map(|x| serde_json::to_value(x.to_msg()).unwrap())
+            .collect())
+    }
+
+    fn load_fields(
+        q: Self::Query,
+        fields: &[&Field],
+        con: &mut Con,
+    ) -> Res<Vec<Value>> {
+        Ok(q.select(quco::select_fields(fields)).get_results(con)?)
+    }
+
+    fn count(q: Self::Query, con: &mut Con) -> Res<i64> {
+        Ok(q.count().get_result(con)?)
+    }
 }
 
-/// Fetches all user changes for a domain.
+fn filter<'a>(
+    inp: &'a GetUserChanges,
+    cond: &Cond,
+) -> schema::user_change::BoxedQuery<'a, Pg> {
+    let mut q = schema::user_change::table
+        .filter(schema::user_change::created.ge(inp.from))
+        .filter(quco::to_expr::<UserChangeTable>(cond))
+        .into_boxed();
+    if let Some(actions) = &inp.actions {
+        let actions: Vec<&str> = actions.iter().map(|x| x.to_str()).collect();
+        q = q.filter(schema::user_change::action.eq_any(actions));
+    }
+    if let Some(user_ids) = &inp.user_ids {
+        q = q.filter(schema::user_change::user_id.eq_any(user_ids));
+    }
+    q
+}
+
+fn to_msgs(
+    inp: &GetUserChanges,
+    user_changes: Vec<UserChangeTable>,
+) -> Vec<UserChange> {
+    user_changes
+        .iter()
+        .map(|x| {
+            let mut change = x.to_msg();
+            if !inp.with_data {
+                change.data = None;
+            }
+            change
+        })
+        .collect()
+}
+
+/// Fetches user changes for a domain, ordered by their creation unless
+/// other sort is given.
+///
+/// The filters of `inp` are joined with it's search query, see `quco::find`
+/// for the search semantics.
+pub fn get_many(
+    inp: &GetUserChanges,
+    con: &mut Con,
+) -> Res<GetUserChangesRes<Value>> {
+    let mut conds =
+        vec![json!(inp.search.sq), json!({"created": {"$gte": inp.from}})];
+    if let Some(actions) = &inp.actions {
+        let actions: Vec<&str> = actions.iter().map(|x| x.to_str()).collect();
+        conds.push(json!({"action": {"$in": actions}}));
+    }
+    if let Some(user_ids) = &inp.user_ids {
+        conds.push(json!({"user_id": {"$in": user_ids}}));
+    }
+    let search = Search {
+        sq: Query::from([("$and".to_string(), Value::Array(conds))]),
+        ..inp.search.clone()
+    };
+    let mut found = quco::find::<UserChangeTable>(&search, con)?;
+    if !inp.with_data {
+        for change in found.items.iter_mut() {
+            if let Some(change) = change.as_object_mut() {
+                change.remove("data");
+            }
+        }
+    }
+    Ok(GetUserChangesRes {
+        changes: found.items,
+        total: found.total,
+        next: found.next,
+    })
+}
+
+/// Subscribes to ids of new user changes, starting the shared listener if
+/// it's not running.
 ///
-/// # Args
+/// The listener is listening once this returns, so no change committed
+/// after it is missed.
+fn subscribe() -> Res<broadcast::Receiver<Id>> {
+    let mut listener = LISTENER.lock().unwrap();
+    if let Some(tx) = listener.as_ref() {
+        return Ok(tx.subscribe());
+    }
+    let mut con = db::con()?;
+    diesel::sql_query(format!("LISTEN {}", NOTIFY_CHANNEL))
+        .execute(&mut con)?;
+    let (tx, rx) = broadcast::channel(LISTEN_CAPACITY);
+    *listener = Some(tx.clone());
+    thread::spawn(move || listen(con, tx));
+    Ok(rx)
+}
+
+/// Broadcasts notified ids until there are no subscribers left or the
+/// connection fails, in which case all subscribers are closed.
+fn listen(mut con: Con, tx: broadcast::Sender<Id>) {
+    loop {
+        for notification in con.notifications_iter() {
+            match notification {
+                Ok(x) => {
+                    if let Ok(id) = x.payload.parse::<Id>() {
+                        // no subscribers is checked below
+                        let _ = tx.send(id);
+                    }
+                }
+                Err(e) => {
+                    warn!("user change listener failed: {}", e);
+                    stop_listener(&tx, true);
+                    return;
+                }
+            }
+        }
+        if stop_listener(&tx, false) {
+            return;
+        }
+        thread::sleep(LISTEN_INTERVAL);
+    }
+}
+
+/// Unregisters the listener, if forced or no one is subscribed to it.
 ///
-/// * `from` - from which time to fetch changes
-pub fn get_many(from: Time, con: &mut Con) -> Res<Vec<UserChange>> {
-    let user_changes = schema::user_change::table
-        .filter(schema::user_change::created.ge(from))
+/// Returns whether the listener is unregistered.
+fn stop_listener(tx: &broadcast::Sender<Id>, force: bool) -> bool {
+    let mut listener = LISTENER.lock().unwrap();
+    if !force && tx.receiver_count() > 0 {
+        return false;
+    }
+    if listener.as_ref().is_some_and(|x| x.same_channel(tx)) {
+        *listener = None;
+    }
+    true
+}
+
+/// Waits for the next notified ids, taking all which are already pending.
+async fn recv_ids(notified: &mut broadcast::Receiver<Id>) -> Res<Vec<Id>> {
+    let mut ids = vec![match notified.recv().await {
+        Ok(id) => id,
+        Err(RecvError::Lagged(_)) => {
+            return err::res_msg("stream fell behind new changes")
+        }
+        Err(RecvError::Closed) => {
+            return err::res_msg("user change listener stopped")
+        }
+    }];
+    loop {
+        match notified.try_recv() {
+            Ok(id) => ids.push(id),
+            Err(TryRecvError::Empty) => return Ok(ids),
+            Err(TryRecvError::Lagged(_)) => {
+                return err::res_msg("stream fell behind new changes")
+            }
+            Err(TryRecvError::Closed) => {
+                return err::res_msg("user change listener stopped")
+            }
+        }
+    }
+}
+
+/// Streams user changes into `tx` until the receiving side is closed.
+///
+/// First all changes matching `inp` are replayed, then every new change is
+/// sent as soon as Postgres notifies about it, so the stream works across
+/// multiple corund instances writing to the same database. Notifications
+/// are received by a single listener shared by all streams.
+///
+/// Changes are sent in the order they're committed, which may differ from
+/// the order of their ids.
+pub async fn stream(
+    inp: GetUserChanges,
+    tx: mpsc::Sender<UserChange>,
+) -> Res<()> {
+    let cond = quco::parse(&inp.search.sq, FIELDS)?;
+    // subscribe before the replay, so no change is lost between them
+    let mut notified = subscribe()?;
+
+    // changes committed during the replay are notified too, they are
+    // skipped as at or below the last replayed id
+    let user_changes = filter(&inp, &cond)
+        .order(schema::user_change::id.asc())
         .select(UserChangeTable::as_select())
-        .load(con)
-        .unwrap();
-    Ok(user_changes.iter().map(|x| x.to_msg()).collect())
+        .load(&mut db::con()?)?;
+    let last_replayed = user_changes.last().map(|x| x.id);
+    for change in to_msgs(&inp, user_changes) {
+        if tx.send(change).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    loop {
+        let ids = tokio::select! {
+            ids = recv_ids(&mut notified) => ids?,
+            _ = tx.closed() => return Ok(()),
+        };
+        let ids: Vec<Id> = ids
+            .into_iter()
+            .filter(|x| last_replayed.is_none_or(|last| *x > last))
+            .collect();
+        if ids.is_empty() {
+            continue;
+        }
+        let user_changes = filter(&inp, &cond)
+            .filter(schema::user_change::id.eq_any(ids))
+            .order(schema::user_change::id.asc())
+            .select(UserChangeTable::as_select())
+            .load(&mut db::con()?)?;
+        for change in to_msgs(&inp, user_changes) {
+            if tx.send(change).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
 }
 
+/// Writes a new user change and queues webhooks for it.
+///
+/// Should be called within the transaction of the user mutation, so the
+/// change and the notifications only exist if the mutation is committed.
 pub fn new(data: &NewUserChange, con: &mut Con) -> Res<UserChange> {
-    let change: UserChangeTable =
+    Ok(new_many(slice::from_ref(data), con)?.pop().unwrap())
+}
+
+/// Writes many user changes at once, see `new`.
+pub fn new_many(
+    data: &[NewUserChange],
+    con: &mut Con,
+) -> Res<Vec<UserChange>> {
+    let now = utc();
+    let inserts: Vec<InsertNewUserChange> = data
+        .iter()
+        .map(|x| InsertNewUserChange {
+            user_id: x.user_id,
+            created: now,
+            action: x.action.to_str().to_string(),
+            data: x.data.to_owned(),
+        })
+        .collect();
+    let changes: Vec<UserChangeTable> =
         diesel::insert_into(schema::user_change::table)
-            .values(&InsertNewUserChange {
-                user_id: data.user_id,
-                created: utc(),
-                action: data.action.to_str().to_string(),
-            })
+            .values(&inserts)
             .returning(UserChangeTable::as_returning())
-            .get_result(con)
-            .unwrap();
-    Ok(change.to_msg())
+            .get_results(con)?;
+    let changes: Vec<UserChange> =
+        changes.iter().map(|x| x.to_msg()).collect();
+    for change in changes.iter() {
+        webhook::emit(
+            WebhookEvent::from(&change.action),
+            change.user_id,
+            Some(change),
+            con,
+        )?;
+    }
+    Ok(changes)
 }
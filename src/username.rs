@@ -0,0 +1,143 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    ryz::{err, res::Res},
+    APPRC,
+};
+
+/// Normalises a username to NFKC, so compatibility lookalikes, e.g. fullwidth
+/// letters, are stored as their canonical characters.
+pub fn normalize(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// Normalises and case-folds a username, the result is unique among active
+/// users and is used for lookups.
+pub fn fold(username: &str) -> String {
+    normalize(username).to_lowercase()
+}
+
+/// Writing systems told apart to reject usernames mixing lookalike letters,
+/// e.g. Cyrillic `а` within a Latin name.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Georgian,
+    Hangul,
+    Hiragana,
+    Katakana,
+    Bopomofo,
+    Han,
+    /// Letters of any other script, all assumed to be of the same one.
+    Other,
+}
+
+/// Script of a character, none for characters shared by scripts, such as
+/// ASCII digits and symbols.
+fn get_script(c: char) -> Option<Script> {
+    if c.is_ascii_alphabetic() {
+        return Some(Script::Latin);
+    }
+    if c.is_ascii() || !c.is_alphanumeric() {
+        return None;
+    }
+    Some(match c as u32 {
+        0x00c0..=0x024f
+        | 0x1e00..=0x1eff
+        | 0x2c60..=0x2c7f
+        | 0xa720..=0xa7ff
+        | 0xab30..=0xab6f => Script::Latin,
+        0x0370..=0x03ff | 0x1f00..=0x1fff => Script::Greek,
+        0x0400..=0x052f
+        | 0x1c80..=0x1c8f
+        | 0x2de0..=0x2dff
+        | 0xa640..=0xa69f => Script::Cyrillic,
+        0x0530..=0x058f => Script::Armenian,
+        0x0590..=0x05ff => Script::Hebrew,
+        0x0600..=0x06ff | 0x0750..=0x077f | 0x08a0..=0x08ff => Script::Arabic,
+        0x0900..=0x097f => Script::Devanagari,
+        0x0e00..=0x0e7f => Script::Thai,
+        0x10a0..=0x10ff | 0x2d00..=0x2d2f => Script::Georgian,
+        0x1100..=0x11ff | 0x3130..=0x318f | 0xac00..=0xd7af => Script::Hangul,
+        0x3040..=0x309f => Script::Hiragana,
+        0x30a0..=0x30ff | 0x31f0..=0x31ff => Script::Katakana,
+        0x3100..=0x312f | 0x31a0..=0x31bf => Script::Bopomofo,
+        0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff => Script::Han,
+        _ => Script::Other,
+    })
+}
+
+/// Whether the scripts may be used together in one username.
+///
+/// Follows the highly restrictive level of Unicode TS #39: a single script,
+/// or Latin with the scripts of Japanese, Chinese or Korean writing.
+fn is_script_mix_allowed(scripts: &[Script]) -> bool {
+    const MIXES: [&[Script]; 3] = [
+        &[
+            Script::Latin,
+            Script::Han,
+            Script::Hiragana,
+            Script::Katakana,
+        ],
+        &[Script::Latin, Script::Han, Script::Bopomofo],
+        &[Script::Latin, Script::Han, Script::Hangul],
+    ];
+    scripts.len() <= 1
+        || MIXES
+            .iter()
+            .any(|mix| scripts.iter().all(|x| mix.contains(x)))
+}
+
+/// Describes why a username violates the configured rules, if it does.
+///
+/// Usernames may consist only of letters, digits and the configured
+/// symbols, must not mix letters of different scripts, and their folded
+/// form must not be reserved.
+pub fn get_violation(username: &str) -> Option<String> {
+    let cfg = &APPRC.username;
+    let username = normalize(username);
+    let len = username.chars().count();
+    if len < cfg.min_len || len > cfg.max_len {
+        return Some(format!(
+            "username must be from {} to {} characters",
+            cfg.min_len, cfg.max_len
+        ));
+    }
+    if let Some(c) = username
+        .chars()
+        .find(|x| !x.is_alphanumeric() && !cfg.symbols.contains(*x))
+    {
+        return Some(format!("username cannot contain {:?}", c));
+    }
+    let mut scripts: Vec<Script> = vec![];
+    for script in username.chars().filter_map(get_script) {
+        if !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+    if !is_script_mix_allowed(&scripts) {
+        return Some(
+            "username cannot mix letters of different scripts".to_string(),
+        );
+    }
+    let folded = fold(&username);
+    if cfg.reserved.iter().any(|x| fold(x) == folded) {
+        return Some(format!("username {} is reserved", username));
+    }
+    None
+}
+
+/// Validates a username, see `get_violation`.
+pub fn check(username: &str) -> Res<()> {
+    match get_violation(username) {
+        Some(msg) => err::res("invalid_username", msg.as_str()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use diesel::prelude::*;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{Con, Id},
+    ryz::{
+        enm::StrEnum,
+        err,
+        hex::to_hex,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema, APPRC,
+};
+
+/// Identifier of a user, besides username, which can be used for login once
+/// verified.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IdentifierKind {
+    Email,
+    Phone,
+}
+
+impl StrEnum for IdentifierKind {
+    fn to_str(&self) -> &str {
+        match self {
+            IdentifierKind::Email => "email",
+            IdentifierKind::Phone => "phone",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "email" => Ok(IdentifierKind::Email),
+            "phone" => Ok(IdentifierKind::Phone),
+            _ => err::res_default(),
+        }
+    }
+}
+
+/// Lowercases an email and checks it has a local part and a domain.
+pub fn normalize_email(email: &str) -> Res<String> {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace) =>
+        {
+            Ok(email)
+        }
+        _ => err::res(
+            "invalid_email",
+            format!("invalid email {}", email).as_str(),
+        ),
+    }
+}
+
+/// Strips formatting from a phone and checks it's in E.164 format, e.g.
+/// `+79001234567`.
+pub fn normalize_phone(phone: &str) -> Res<String> {
+    let phone: String = phone
+        .chars()
+        .filter(|x| !matches!(x, ' ' | '-' | '(' | ')'))
+        .collect();
+    let digits = phone.strip_prefix('+').unwrap_or("");
+    if (8..=15).contains(&digits.len())
+        && digits.chars().all(|x| x.is_ascii_digit())
+    {
+        return Ok(phone);
+    }
+    err::res("invalid_phone", format!("invalid phone {}", phone).as_str())
+}
+
+/// Pending verification of an identifier.
+///
+/// `code` is only given to the domain and to the sender, it's never sent to
+/// users via client RPCs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Verification {
+    pub id: Id,
+    pub user_id: Id,
+    pub kind: IdentifierKind,
+    /// Email or phone being verified.
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub expires: Time,
+}
+
+/// Delivers verification codes to users.
+pub trait Sender: Send + Sync {
+    fn send(&self, verification: &Verification) -> Res<()>;
+}
+
+lazy_static::lazy_static! {
+    static ref SENDER: RwLock<Option<Arc<dyn Sender>>> = RwLock::new(None);
+}
+
+/// Sets the sender for new verifications.
+///
+/// Without a sender, codes are only handed to the domain, which is then
+/// responsible for the delivery.
+pub fn set_sender(sender: Option<Arc<dyn Sender>>) {
+    *SENDER.write().unwrap() = sender;
+}
+
+pub fn has_sender() -> bool {
+    SENDER.read().unwrap().is_some()
+}
+
+/// Sender which keeps verifications in memory, for tests.
+#[derive(Default, Clone)]
+pub struct CaptureSender {
+    pub sent: Arc<Mutex<Vec<Verification>>>,
+}
+
+impl Sender for CaptureSender {
+    fn send(&self, verification: &Verification) -> Res<()> {
+        self.sent.lock().unwrap().push(verification.clone());
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewVerification {
+    pub user_id: Id,
+    pub kind: IdentifierKind,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Verify {
+    pub id: Id,
+    pub code: String,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::verification)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct VerificationTable {
+    pub id: Id,
+    pub created: Time,
+    pub user_id: Id,
+    pub kind: String,
+    pub target: String,
+    /// Hash of the code, see `hash_code`.
+    pub hcode: String,
+    pub expires: Time,
+    pub attempts: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::verification)]
+struct InsertVerification {
+    created: Time,
+    user_id: Id,
+    kind: String,
+    target: String,
+    hcode: String,
+    expires: Time,
+    attempts: i32,
+}
+
+fn hash_code(code: &str) -> String {
+    to_hex(&Sha256::digest(code.as_bytes()))
+}
+
+/// Emails are verified by a token, which is usually put into a link, and
+/// phones by a short code, which is typed in by the user.
+fn new_code(kind: IdentifierKind) -> String {
+    match kind {
+        IdentifierKind::Email => {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            to_hex(&bytes)
+        }
+        IdentifierKind::Phone => format!("{:06}", OsRng.next_u32() % 1000000),
+    }
+}
+
+/// Starts verification of the current user's email or phone.
+///
+/// The code is passed to the sender, if any, and is returned for the
+/// domain. Earlier codes of the kind are invalidated, and a new code can be
+/// requested once per `verification.resend_interval`.
+pub fn new(inp: &NewVerification, con: &mut Con) -> Res<Verification> {
+    let verification = con.transaction(|con| new_locked(inp, con))?;
+    let sender = SENDER.read().unwrap().clone();
+    if let Some(sender) = sender {
+        sender.send(&verification)?;
+    }
+    Ok(verification)
+}
+
+/// Writes a new verification, replacing earlier ones of the user and kind,
+/// within a transaction locking the user.
+fn new_locked(inp: &NewVerification, con: &mut Con) -> Res<Verification> {
+    let (email, phone) = schema::appuser::table
+        .filter(schema::appuser::id.eq(inp.user_id))
+        .filter(schema::appuser::username.not_like("archived::%"))
+        .select((schema::appuser::email, schema::appuser::phone))
+        .for_update()
+        .first::<(Option<String>, Option<String>)>(con)?;
+    let target = match inp.kind {
+        IdentifierKind::Email => email,
+        IdentifierKind::Phone => phone,
+    };
+    let Some(target) = target else {
+        return err::res(
+            "no_identifier",
+            format!("user has no {}", inp.kind.to_str()).as_str(),
+        );
+    };
+
+    let now = utc();
+    let earlier = schema::verification::table
+        .filter(schema::verification::user_id.eq(inp.user_id))
+        .filter(schema::verification::kind.eq(inp.kind.to_str()));
+    let last_created = earlier
+        .select(diesel::dsl::max(schema::verification::created))
+        .get_result::<Option<Time>>(con)?;
+    if last_created
+        .is_some_and(|x| now - x < APPRC.verification.resend_interval)
+    {
+        return err::res(
+            "rate_limited",
+            format!("{} code is requested too often", inp.kind.to_str())
+                .as_str(),
+        );
+    }
+    // only the last code is valid, so it's attempts cannot be multiplied
+    diesel::delete(earlier).execute(con)?;
+
+    let code = new_code(inp.kind);
+    let id = diesel::insert_into(schema::verification::table)
+        .values(&InsertVerification {
+            created: now,
+            user_id: inp.user_id,
+            kind: inp.kind.to_str().to_string(),
+            target: target.to_owned(),
+            hcode: hash_code(&code),
+            expires: now + APPRC.verification.ttl,
+            attempts: 0,
+        })
+        .returning(schema::verification::id)
+        .get_result::<Id>(con)?;
+    Ok(Verification {
+        id,
+        user_id: inp.user_id,
+        kind: inp.kind,
+        target,
+        code: Some(code),
+        expires: now + APPRC.verification.ttl,
+    })
+}
+
+/// Marks the identifier as verified if the code matches.
+///
+/// Each wrong code counts as an attempt, after the configured amount of
+/// attempts the verification can no longer be completed.
+pub fn verify(inp: &Verify, con: &mut Con) -> Res<()> {
+    // wrong attempts are committed before the error
+    if !con.transaction(|con| verify_locked(inp, con))? {
+        return err::res("invalid_code", "incorrect code");
+    }
+    Ok(())
+}
+
+/// Checks the code within a transaction locking the verification, so
+/// concurrent attempts are counted one after another.
+///
+/// Returns whether the code is correct.
+fn verify_locked(inp: &Verify, con: &mut Con) -> Res<bool> {
+    let q = schema::verification::table
+        .filter(schema::verification::id.eq(inp.id));
+    let Some(verification) = q
+        .select(VerificationTable::as_select())
+        .for_update()
+        .first::<VerificationTable>(con)
+        .optional()?
+    else {
+        return err::res("invalid_code", "no such verification");
+    };
+    if verification.attempts >= APPRC.verification.max_attempts {
+        return err::res("invalid_code", "too many attempts");
+    }
+    if verification.expires < utc() {
+        return err::res("invalid_code", "expired code");
+    }
+    if verification.hcode != hash_code(&inp.code) {
+        diesel::update(q)
+            .set(
+                schema::verification::attempts
+                    .eq(schema::verification::attempts + 1),
+            )
+            .execute(con)?;
+        return Ok(false);
+    }
+
+    let kind = IdentifierKind::from_str(&verification.kind)?;
+    let user_q = schema::appuser::table
+        .filter(schema::appuser::id.eq(verification.user_id));
+    let updated = match kind {
+        IdentifierKind::Email => diesel::update(
+            user_q.filter(schema::appuser::email.eq(&verification.target)),
+        )
+        .set(schema::appuser::email_verified_at.eq(utc()))
+        .execute(con),
+        IdentifierKind::Phone => diesel::update(
+            user_q.filter(schema::appuser::phone.eq(&verification.target)),
+        )
+        .set(schema::appuser::phone_verified_at.eq(utc()))
+        .execute(con),
+    };
+    match updated {
+        Ok(0) => {
+            return err::res(
+                "invalid_code",
+                format!("{} has changed", kind.to_str()).as_str(),
+            )
+        }
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => {
+            return err::res(
+                "identifier_taken",
+                format!("{} is verified by another user", kind.to_str())
+                    .as_str(),
+            )
+        }
+        updated => {
+            updated?;
+        }
+    }
+    diesel::delete(
+        schema::verification::table
+            .filter(schema::verification::user_id.eq(verification.user_id))
+            .filter(schema::verification::kind.eq(&verification.kind)),
+    )
+    .execute(con)?;
+    Ok(true)
+}
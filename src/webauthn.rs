@@ -0,0 +1,589 @@
+use std::io::Cursor;
+
+use ciborium::Value as Cbor;
+use data_encoding::BASE64URL_NOPAD;
+use diesel::prelude::*;
+use p256::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    EncodedPoint,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{Con, Id},
+    quco::Collection,
+    ryz::{
+        enm::StrEnum,
+        err,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema,
+    user::User,
+    WebauthnCfg, APPRC,
+};
+
+/// COSE algorithm id of ECDSA with P-256 and SHA-256, the only supported
+/// one.
+const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED: u8 = 0x40;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum CeremonyKind {
+    Registration,
+    Assertion,
+}
+
+impl StrEnum for CeremonyKind {
+    fn to_str(&self) -> &str {
+        match self {
+            CeremonyKind::Registration => "registration",
+            CeremonyKind::Assertion => "assertion",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "registration" => Ok(CeremonyKind::Registration),
+            "assertion" => Ok(CeremonyKind::Assertion),
+            _ => err::res_default(),
+        }
+    }
+}
+
+/// Options for `navigator.credentials.create()` or
+/// `navigator.credentials.get()`, with the id of the ceremony to pass back
+/// on finish.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CeremonyOptions {
+    pub id: Id,
+    #[serde(rename = "publicKey")]
+    pub public_key: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// Result of `navigator.credentials.create()`, binary fields encoded as
+/// base64url.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle", default)]
+    pub user_handle: Option<String>,
+}
+
+/// Result of `navigator.credentials.get()`, binary fields encoded as
+/// base64url.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Passkey {
+    pub id: Id,
+    pub user_id: Id,
+    pub name: Option<String>,
+    pub created: Time,
+    pub last_used: Option<Time>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::webauthn_credential)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PasskeyTable {
+    pub id: Id,
+    pub created: Time,
+    pub user_id: Id,
+    /// Base64url id given by the authenticator.
+    pub credential_id: String,
+    /// Base64url SEC1 encoded P-256 key.
+    pub public_key: String,
+    pub sign_count: i64,
+    pub name: Option<String>,
+    pub last_used: Option<Time>,
+}
+
+impl Collection<Passkey> for PasskeyTable {
+    fn to_msg(&self) -> Passkey {
+        Passkey {
+            id: self.id.to_owned(),
+            user_id: self.user_id.to_owned(),
+            name: self.name.to_owned(),
+            created: self.created.to_owned(),
+            last_used: self.last_used.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::webauthn_credential)]
+struct InsertPasskey {
+    created: Time,
+    user_id: Id,
+    credential_id: String,
+    public_key: String,
+    sign_count: i64,
+    name: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::webauthn_challenge)]
+struct InsertChallenge {
+    created: Time,
+    challenge: String,
+    kind: String,
+    user_id: Option<Id>,
+    expires: Time,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    typ: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthData {
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and public key, given on registration.
+    attested: Option<(Vec<u8>, VerifyingKey)>,
+}
+
+fn cfg() -> Res<&'static WebauthnCfg> {
+    match &APPRC.webauthn {
+        Some(cfg) => Ok(cfg),
+        None => err::res("no_webauthn", "webauthn is not configured"),
+    }
+}
+
+fn invalid<T>(msg: &str) -> Res<T> {
+    err::res("invalid_credential", msg)
+}
+
+fn decode(s: &str) -> Res<Vec<u8>> {
+    match BASE64URL_NOPAD.decode(s.trim_end_matches('=').as_bytes()) {
+        Ok(x) => Ok(x),
+        Err(_) => invalid("invalid base64url"),
+    }
+}
+
+fn get_cbor(map: &[(Cbor, Cbor)], key: Cbor) -> Option<&Cbor> {
+    map.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Converts COSE EC2 key to a P-256 key.
+fn parse_cose_key(key: &Cbor) -> Res<VerifyingKey> {
+    let Some(map) = key.as_map() else {
+        return invalid("invalid public key");
+    };
+    let int = |k: i64| {
+        get_cbor(map, Cbor::from(k))
+            .and_then(|x| x.as_integer())
+            .map(i128::from)
+    };
+    if int(1) != Some(2) || int(3) != Some(ES256 as i128) || int(-1) != Some(1)
+    {
+        return err::res(
+            "unsupported_algorithm",
+            "only ES256 keys on P-256 are supported",
+        );
+    }
+    let coord = |k: i64| {
+        get_cbor(map, Cbor::from(k))
+            .and_then(|x| x.as_bytes())
+            .filter(|x| x.len() == 32)
+            .cloned()
+    };
+    let (Some(x), Some(y)) = (coord(-2), coord(-3)) else {
+        return invalid("invalid public key");
+    };
+    let point = EncodedPoint::from_affine_coordinates(
+        x.as_slice().into(),
+        y.as_slice().into(),
+        false,
+    );
+    match VerifyingKey::from_encoded_point(&point) {
+        Ok(key) => Ok(key),
+        Err(_) => invalid("invalid public key"),
+    }
+}
+
+/// Parses authenticator data, checking it's made for our relying party by a
+/// present user.
+fn parse_auth_data(data: &[u8], cfg: &WebauthnCfg) -> Res<AuthData> {
+    if data.len() < 37 {
+        return invalid("authenticator data is too short");
+    }
+    if data[..32] != Sha256::digest(cfg.rp_id.as_bytes())[..] {
+        return invalid("credential is made for another relying party");
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return invalid("user is not present");
+    }
+    let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+    let mut attested = None;
+    if flags & FLAG_ATTESTED != 0 {
+        // aaguid goes first, which we don't check
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return invalid("attested data is too short");
+        }
+        let len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        if rest.len() < 18 + len {
+            return invalid("attested data is too short");
+        }
+        let credential_id = rest[18..18 + len].to_vec();
+        let Ok(key) = ciborium::de::from_reader::<Cbor, _>(Cursor::new(
+            &rest[18 + len..],
+        )) else {
+            return invalid("invalid public key");
+        };
+        attested = Some((credential_id, parse_cose_key(&key)?));
+    }
+    Ok(AuthData {
+        flags,
+        sign_count,
+        attested,
+    })
+}
+
+/// Checks client data and returns it's hash, which is signed on assertion.
+fn check_client_data(
+    client_data_json: &str,
+    typ: &str,
+    challenge: &str,
+    cfg: &WebauthnCfg,
+) -> Res<Vec<u8>> {
+    let raw = decode(client_data_json)?;
+    let Ok(client_data) = serde_json::from_slice::<ClientData>(&raw) else {
+        return invalid("invalid client data");
+    };
+    if client_data.typ != typ {
+        return invalid("invalid client data type");
+    }
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return invalid("invalid challenge");
+    }
+    if client_data.origin != cfg.origin {
+        return invalid("invalid origin");
+    }
+    Ok(Sha256::digest(&raw).to_vec())
+}
+
+fn new_ceremony(
+    kind: CeremonyKind,
+    user_id: Option<Id>,
+    con: &mut Con,
+) -> Res<(Id, String)> {
+    let cfg = cfg()?;
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let challenge = BASE64URL_NOPAD.encode(&bytes);
+    let now = utc();
+    let id = diesel::insert_into(schema::webauthn_challenge::table)
+        .values(&InsertChallenge {
+            created: now,
+            challenge: challenge.to_owned(),
+            kind: kind.to_str().to_string(),
+            user_id,
+            expires: now + cfg.challenge_ttl,
+        })
+        .returning(schema::webauthn_challenge::id)
+        .get_result(con)?;
+    Ok((id, challenge))
+}
+
+/// Takes the ceremony, so each challenge can be answered only once.
+fn take_ceremony(
+    id: Id,
+    kind: CeremonyKind,
+    con: &mut Con,
+) -> Res<(String, Option<Id>)> {
+    let row = diesel::delete(
+        schema::webauthn_challenge::table
+            .filter(schema::webauthn_challenge::id.eq(id))
+            .filter(schema::webauthn_challenge::kind.eq(kind.to_str())),
+    )
+    .returning((
+        schema::webauthn_challenge::challenge,
+        schema::webauthn_challenge::user_id,
+        schema::webauthn_challenge::expires,
+    ))
+    .get_result::<(String, Option<Id>, Time)>(con)
+    .optional()?;
+    match row {
+        Some((challenge, user_id, expires)) if expires >= utc() => {
+            Ok((challenge, user_id))
+        }
+        _ => invalid("no such ceremony"),
+    }
+}
+
+fn get_credential_ids(user_id: Id, con: &mut Con) -> Res<Vec<String>> {
+    Ok(schema::webauthn_credential::table
+        .filter(schema::webauthn_credential::user_id.eq(user_id))
+        .select(schema::webauthn_credential::credential_id)
+        .load(con)?)
+}
+
+fn to_descriptors(credential_ids: Vec<String>) -> Value {
+    credential_ids
+        .into_iter()
+        .map(|x| json!({"type": "public-key", "id": x}))
+        .collect()
+}
+
+/// Starts registration of a passkey for the user.
+pub fn begin_registration(user: &User, con: &mut Con) -> Res<CeremonyOptions> {
+    let cfg = cfg()?;
+    let (id, challenge) =
+        new_ceremony(CeremonyKind::Registration, Some(user.id), con)?;
+    Ok(CeremonyOptions {
+        id,
+        public_key: json!({
+            "rp": {"id": cfg.rp_id, "name": cfg.rp_name},
+            "user": {
+                "id": BASE64URL_NOPAD.encode(&user.id.to_be_bytes()),
+                "name": user.username,
+                "displayName": user.username
+            },
+            "challenge": challenge,
+            "pubKeyCredParams": [{"type": "public-key", "alg": ES256}],
+            "timeout": (cfg.challenge_ttl * 1000.0) as i64,
+            "excludeCredentials":
+                to_descriptors(get_credential_ids(user.id, con)?),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred"
+            },
+            "attestation": "none"
+        }),
+    })
+}
+
+/// Finishes registration, storing the new passkey.
+///
+/// Attestation statements are not verified, since we only request `none`
+/// attestation.
+pub fn finish_registration(
+    user_id: Id,
+    ceremony_id: Id,
+    credential: &RegistrationCredential,
+    name: Option<String>,
+    con: &mut Con,
+) -> Res<Passkey> {
+    let cfg = cfg()?;
+    let (challenge, ceremony_user_id) =
+        take_ceremony(ceremony_id, CeremonyKind::Registration, con)?;
+    if ceremony_user_id != Some(user_id) {
+        return invalid("ceremony is started for another user");
+    }
+    check_client_data(
+        &credential.response.client_data_json,
+        "webauthn.create",
+        &challenge,
+        cfg,
+    )?;
+    let raw = decode(&credential.response.attestation_object)?;
+    let Ok(attestation) = ciborium::de::from_reader::<Cbor, _>(&raw[..])
+    else {
+        return invalid("invalid attestation object");
+    };
+    let Some(auth_data) = attestation
+        .as_map()
+        .and_then(|x| get_cbor(x, Cbor::from("authData")))
+        .and_then(|x| x.as_bytes())
+    else {
+        return invalid("invalid attestation object");
+    };
+    let auth_data = parse_auth_data(auth_data, cfg)?;
+    let Some((credential_id, key)) = auth_data.attested else {
+        return invalid("no attested credential");
+    };
+    let credential_id = BASE64URL_NOPAD.encode(&credential_id);
+    if credential_id != credential.id.trim_end_matches('=') {
+        return invalid("credential id mismatch");
+    }
+
+    let passkey = diesel::insert_into(schema::webauthn_credential::table)
+        .values(&InsertPasskey {
+            created: utc(),
+            user_id,
+            credential_id,
+            public_key: BASE64URL_NOPAD
+                .encode(key.to_encoded_point(false).as_bytes()),
+            sign_count: auth_data.sign_count as i64,
+            name,
+        })
+        .returning(PasskeyTable::as_returning())
+        .get_result(con);
+    match passkey {
+        Ok(passkey) => Ok(passkey.to_msg()),
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => invalid("credential is already registered"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Starts assertion, for passkeys of the user if given, or for any
+/// discoverable passkey otherwise.
+pub fn begin_assertion(
+    user_id: Option<Id>,
+    con: &mut Con,
+) -> Res<CeremonyOptions> {
+    let cfg = cfg()?;
+    let (id, challenge) = new_ceremony(CeremonyKind::Assertion, user_id, con)?;
+    let allow = match user_id {
+        Some(user_id) => get_credential_ids(user_id, con)?,
+        None => vec![],
+    };
+    Ok(CeremonyOptions {
+        id,
+        public_key: json!({
+            "rpId": cfg.rp_id,
+            "challenge": challenge,
+            "timeout": (cfg.challenge_ttl * 1000.0) as i64,
+            "allowCredentials": to_descriptors(allow),
+            "userVerification": "preferred"
+        }),
+    })
+}
+
+/// Verifies assertion signature and updates the sign counter.
+///
+/// Returns the user id and whether the authenticator verified the user, e.g.
+/// by biometrics or PIN, which is required to use a passkey as the sole
+/// factor.
+pub fn finish_assertion(
+    ceremony_id: Id,
+    credential: &AssertionCredential,
+    con: &mut Con,
+) -> Res<(Id, bool)> {
+    let cfg = cfg()?;
+    let (challenge, ceremony_user_id) =
+        take_ceremony(ceremony_id, CeremonyKind::Assertion, con)?;
+    let Some(passkey) = schema::webauthn_credential::table
+        .inner_join(schema::appuser::table)
+        .filter(
+            schema::webauthn_credential::credential_id
+                .eq(credential.id.trim_end_matches('=')),
+        )
+        .filter(schema::appuser::username.not_like("archived::%"))
+        .select(PasskeyTable::as_select())
+        .first(con)
+        .optional()?
+    else {
+        return invalid("no such credential");
+    };
+    if ceremony_user_id.is_some_and(|x| x != passkey.user_id) {
+        return invalid("ceremony is started for another user");
+    }
+    let client_data_hash = check_client_data(
+        &credential.response.client_data_json,
+        "webauthn.get",
+        &challenge,
+        cfg,
+    )?;
+    let raw_auth_data = decode(&credential.response.authenticator_data)?;
+    let auth_data = parse_auth_data(&raw_auth_data, cfg)?;
+
+    let Ok(key) = VerifyingKey::from_sec1_bytes(&decode(&passkey.public_key)?)
+    else {
+        return err::res_msg("stored passkey is corrupted");
+    };
+    let Ok(signature) =
+        Signature::from_der(&decode(&credential.response.signature)?)
+    else {
+        return invalid("invalid signature");
+    };
+    let signed = [raw_auth_data, client_data_hash].concat();
+    if key.verify(&signed, &signature).is_err() {
+        return invalid("invalid signature");
+    }
+    // authenticators without counters always send 0
+    let sign_count = auth_data.sign_count as i64;
+    if (sign_count != 0 || passkey.sign_count != 0)
+        && sign_count <= passkey.sign_count
+    {
+        return invalid("sign counter went back, authenticator may be cloned");
+    }
+    diesel::update(
+        schema::webauthn_credential::table
+            .filter(schema::webauthn_credential::id.eq(passkey.id)),
+    )
+    .set((
+        schema::webauthn_credential::sign_count.eq(sign_count),
+        schema::webauthn_credential::last_used.eq(Some(utc())),
+    ))
+    .execute(con)?;
+    Ok((passkey.user_id, auth_data.flags & FLAG_USER_VERIFIED != 0))
+}
+
+pub fn get_many(user_id: Id, con: &mut Con) -> Res<Vec<Passkey>> {
+    Ok(schema::webauthn_credential::table
+        .filter(schema::webauthn_credential::user_id.eq(user_id))
+        .order(schema::webauthn_credential::id.asc())
+        .select(PasskeyTable::as_select())
+        .load(con)?
+        .iter()
+        .map(|x| x.to_msg())
+        .collect())
+}
+
+pub fn count(user_id: Id, con: &mut Con) -> Res<i64> {
+    Ok(schema::webauthn_credential::table
+        .filter(schema::webauthn_credential::user_id.eq(user_id))
+        .count()
+        .get_result(con)?)
+}
+
+pub fn del(user_id: Id, id: Id, con: &mut Con) -> Res<()> {
+    let deleted = diesel::delete(
+        schema::webauthn_credential::table
+            .filter(schema::webauthn_credential::id.eq(id))
+            .filter(schema::webauthn_credential::user_id.eq(user_id)),
+    )
+    .execute(con)?;
+    if deleted == 0 {
+        return err::res_msg("no such passkey");
+    }
+    Ok(())
+}
+
+pub fn del_all(user_id: Id, con: &mut Con) -> Res<()> {
+    diesel::delete(
+        schema::webauthn_credential::table
+            .filter(schema::webauthn_credential::user_id.eq(user_id)),
+    )
+    .execute(con)?;
+    Ok(())
+}
@@ -0,0 +1,616 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand_core::{OsRng, RngCore};
+use reqwest::{
+    dns::{Name, Resolve, Resolving},
+    redirect, Url,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    db::{self, Con, Id},
+    quco::Collection,
+    ryz::{
+        enm::StrEnum,
+        err,
+        hex::to_hex,
+        res::Res,
+        time::{utc, Time},
+    },
+    schema,
+    user_change::{ChangeAction, UserChange},
+    APPRC,
+};
+
+/// How long sending a claimed batch may take at most. Batches are shrunk
+/// for slow receivers, so the lease doesn't expire in the middle of one.
+const BATCH_DURATION: Time = 60.0;
+/// How long a claimed delivery is hidden from other workers on top of the
+/// time it's batch may take to send.
+const LEASE_MARGIN: Time = 10.0;
+/// How many deliveries are claimed by a worker at once, at most.
+const BATCH: i64 = 100;
+/// How often the worker checks for due deliveries.
+const WORKER_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WebhookEvent {
+    UserNew,
+    UserDel,
+    UserUpdate,
+    PasswordChange,
+    Login,
+    UserRoles,
+    UserMembership,
+    UserImpersonation,
+}
+
+impl StrEnum for WebhookEvent {
+    fn to_str(&self) -> &str {
+        match self {
+            WebhookEvent::UserNew => "user_new",
+            WebhookEvent::UserDel => "user_del",
+            WebhookEvent::UserUpdate => "user_update",
+            WebhookEvent::PasswordChange => "password_change",
+            WebhookEvent::Login => "login",
+            WebhookEvent::UserRoles => "user_roles",
+            WebhookEvent::UserMembership => "user_membership",
+            WebhookEvent::UserImpersonation => "user_impersonation",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "user_new" => Ok(WebhookEvent::UserNew),
+            "user_del" => Ok(WebhookEvent::UserDel),
+            "user_update" => Ok(WebhookEvent::UserUpdate),
+            "password_change" => Ok(WebhookEvent::PasswordChange),
+            "login" => Ok(WebhookEvent::Login),
+            "user_roles" => Ok(WebhookEvent::UserRoles),
+            "user_membership" => Ok(WebhookEvent::UserMembership),
+            "user_impersonation" => Ok(WebhookEvent::UserImpersonation),
+            _ => err::res_default(),
+        }
+    }
+}
+
+impl From<&ChangeAction> for WebhookEvent {
+    fn from(action: &ChangeAction) -> Self {
+        match action {
+            ChangeAction::New => WebhookEvent::UserNew,
+            ChangeAction::Del => WebhookEvent::UserDel,
+            ChangeAction::Update => WebhookEvent::UserUpdate,
+            ChangeAction::Roles => WebhookEvent::UserRoles,
+            ChangeAction::Membership => WebhookEvent::UserMembership,
+            ChangeAction::Impersonation => WebhookEvent::UserImpersonation,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DeliveryStatus {
+    Pending,
+    Done,
+    /// Delivery has run out of attempts.
+    Dead,
+}
+
+impl StrEnum for DeliveryStatus {
+    fn to_str(&self) -> &str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Done => "done",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "pending" => Ok(DeliveryStatus::Pending),
+            "done" => Ok(DeliveryStatus::Done),
+            "dead" => Ok(DeliveryStatus::Dead),
+            _ => err::res_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Webhook {
+    pub id: Id,
+    pub created: Time,
+    pub event: WebhookEvent,
+    pub url: String,
+    /// Secret used to sign payloads with HMAC-SHA256.
+    pub secret: String,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::webhook)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookTable {
+    pub id: Id,
+    pub created: Time,
+    pub event: String,
+    pub url: String,
+    pub secret: String,
+}
+
+impl Collection<Webhook> for WebhookTable {
+    fn to_msg(&self) -> Webhook {
+        Webhook {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            event: WebhookEvent::from_str(self.event.as_str()).unwrap(),
+            url: self.url.to_owned(),
+            secret: self.secret.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name=schema::webhook)]
+struct InsertWebhook {
+    pub created: Time,
+    pub event: String,
+    pub url: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewWebhook {
+    pub event: WebhookEvent,
+    pub url: String,
+}
+
+/// Body sent to webhook urls.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookPayload {
+    pub event: WebhookEvent,
+    pub created: Time,
+    pub user_id: Id,
+    /// Change which caused the event, if the event is bound to a change.
+    pub change: Option<UserChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookDelivery {
+    pub id: Id,
+    pub created: Time,
+    pub webhook_id: Id,
+    pub payload: Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt: Time,
+    /// Error of the last failed attempt.
+    pub err: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::webhook_delivery)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookDeliveryTable {
+    pub id: Id,
+    pub created: Time,
+    pub webhook_id: Id,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: Time,
+    pub err: Option<String>,
+}
+
+impl Collection<WebhookDelivery> for WebhookDeliveryTable {
+    fn to_msg(&self) -> WebhookDelivery {
+        WebhookDelivery {
+            id: self.id.to_owned(),
+            created: self.created.to_owned(),
+            webhook_id: self.webhook_id.to_owned(),
+            payload: self.payload.to_owned(),
+            status: DeliveryStatus::from_str(self.status.as_str()).unwrap(),
+            attempts: self.attempts.to_owned(),
+            next_attempt: self.next_attempt.to_owned(),
+            err: self.err.to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name=schema::webhook_delivery)]
+struct InsertWebhookDelivery {
+    pub created: Time,
+    pub webhook_id: Id,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: Time,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetWebhookDeliveries {
+    pub webhook_id: Option<Id>,
+    pub status: Option<DeliveryStatus>,
+}
+
+/// Whether an address can be reached from the internet, unlike private,
+/// loopback, link-local and other special-purpose addresses.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(x) => {
+            let [a, b, ..] = x.octets();
+            !(x.is_private()
+                || x.is_loopback()
+                || x.is_link_local()
+                || x.is_unspecified()
+                || x.is_broadcast()
+                || x.is_multicast()
+                || x.is_documentation()
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(x) => match x.to_ipv4_mapped() {
+            Some(x) => is_public(IpAddr::V4(x)),
+            None => {
+                !(x.is_loopback()
+                    || x.is_unspecified()
+                    || x.is_multicast()
+                    || x.is_unique_local()
+                    || x.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Whether the config allows a host to receive webhooks regardless of it's
+/// addresses.
+fn is_allowed_host(host: &str) -> bool {
+    APPRC.webhook.allow_hosts.iter().any(|x| x == host)
+}
+
+/// Parses a webhook url, rejecting it if it's host is an address which is
+/// not public.
+fn parse_url(url: &str) -> Res<Url> {
+    let Ok(url) = Url::parse(url) else {
+        return err::res_msg("invalid webhook url");
+    };
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return err::res_msg("webhook url must be http or https");
+    }
+    let Some(host) = url.host_str() else {
+        return err::res_msg("webhook url must have a host");
+    };
+    if is_allowed_host(host) {
+        return Ok(url);
+    }
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        if !is_public(ip) {
+            return err::res_msg("webhook url must not be a private address");
+        }
+    }
+    Ok(url)
+}
+
+/// Resolves hosts of webhook urls, failing if any of their addresses is not
+/// public.
+///
+/// Addresses are checked on each connection, so a host cannot be repointed
+/// to a private address after the webhook is created.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if !is_allowed_host(&host)
+                && addrs.iter().any(|x| !is_public(x.ip()))
+            {
+                return Err(format!(
+                    "host {} resolves to a private address",
+                    host
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as _)
+        })
+    }
+}
+
+/// How many deliveries to claim at once and for how long, for sends which
+/// each may take up to the timeout.
+///
+/// The lease covers sending the whole batch, so a delivery isn't claimed by
+/// another worker while the batch is still being sent.
+pub fn claim_size(timeout: Time) -> (i64, Time) {
+    let batch = ((BATCH_DURATION / timeout) as i64).clamp(1, BATCH);
+    (batch, batch as Time * timeout + LEASE_MARGIN)
+}
+
+pub fn new(data: &NewWebhook, con: &mut Con) -> Res<Webhook> {
+    let url = parse_url(&data.url)?;
+    if let (Some(host), Some(port)) =
+        (url.host_str(), url.port_or_known_default())
+    {
+        if !is_allowed_host(host) {
+            let Ok(addrs) =
+                (host.trim_matches(['[', ']']), port).to_socket_addrs()
+            else {
+                return err::res_msg("cannot resolve webhook host");
+            };
+            if addrs.into_iter().any(|x| !is_public(x.ip())) {
+                return err::res_msg(
+                    "webhook url must not be a private address",
+                );
+            }
+        }
+    }
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let webhook: WebhookTable = diesel::insert_into(schema::webhook::table)
+        .values(&InsertWebhook {
+            created: utc(),
+            event: data.event.to_str().to_string(),
+            url: data.url.to_owned(),
+            secret: to_hex(&secret),
+        })
+        .returning(WebhookTable::as_returning())
+        .get_result(con)?;
+    Ok(webhook.to_msg())
+}
+
+/// Deletes a webhook together with all its deliveries.
+pub fn del(id: Id, con: &mut Con) -> Res<()> {
+    diesel::delete(schema::webhook::table.filter(schema::webhook::id.eq(id)))
+        .execute(con)?;
+    Ok(())
+}
+
+pub fn get_many(con: &mut Con) -> Res<Vec<Webhook>> {
+    let webhooks = schema::webhook::table
+        .order(schema::webhook::id.asc())
+        .select(WebhookTable::as_select())
+        .load(con)?;
+    Ok(webhooks.iter().map(|x| x.to_msg()).collect())
+}
+
+pub fn get_deliveries(
+    inp: &GetWebhookDeliveries,
+    con: &mut Con,
+) -> Res<Vec<WebhookDelivery>> {
+    let mut q = schema::webhook_delivery::table.into_boxed();
+    if let Some(webhook_id) = inp.webhook_id {
+        q = q.filter(schema::webhook_delivery::webhook_id.eq(webhook_id));
+    }
+    if let Some(status) = &inp.status {
+        q = q.filter(schema::webhook_delivery::status.eq(status.to_str()));
+    }
+    let deliveries = q
+        .order(schema::webhook_delivery::id.asc())
+        .select(WebhookDeliveryTable::as_select())
+        .load(con)?;
+    Ok(deliveries.iter().map(|x| x.to_msg()).collect())
+}
+
+/// Queues a delivery for every webhook registered for the event.
+///
+/// Deliveries table serves as a transactional outbox: this should be called
+/// within the transaction of the mutation which caused the event, so a
+/// delivery is only sent if the mutation is committed.
+pub fn emit(
+    event: WebhookEvent,
+    user_id: Id,
+    change: Option<&UserChange>,
+    con: &mut Con,
+) -> Res<()> {
+    let webhook_ids = schema::webhook::table
+        .filter(schema::webhook::event.eq(event.to_str()))
+        .select(schema::webhook::id)
+        .load::<Id>(con)?;
+    if webhook_ids.is_empty() {
+        return Ok(());
+    }
+    let now = utc();
+    let Ok(payload) = serde_json::to_value(WebhookPayload {
+        event,
+        created: now,
+        user_id,
+        change: change.cloned(),
+    }) else {
+        return err::res_msg("cannot serialize webhook payload");
+    };
+    let deliveries: Vec<InsertWebhookDelivery> = webhook_ids
+        .iter()
+        .map(|webhook_id| InsertWebhookDelivery {
+            created: now,
+            webhook_id: *webhook_id,
+            payload: payload.to_owned(),
+            status: DeliveryStatus::Pending.to_str().to_string(),
+            attempts: 0,
+            next_attempt: now,
+        })
+        .collect();
+    diesel::insert_into(schema::webhook_delivery::table)
+        .values(&deliveries)
+        .execute(con)?;
+    Ok(())
+}
+
+/// Puts a delivery back to the queue, regardless of it's current status.
+pub fn redeliver(id: Id, con: &mut Con) -> Res<()> {
+    let updated = diesel::update(
+        schema::webhook_delivery::table
+            .filter(schema::webhook_delivery::id.eq(id)),
+    )
+    .set((
+        schema::webhook_delivery::status.eq(DeliveryStatus::Pending.to_str()),
+        schema::webhook_delivery::attempts.eq(0),
+        schema::webhook_delivery::next_attempt.eq(utc()),
+    ))
+    .execute(con)?;
+    if updated == 0 {
+        return err::res_msg("no such delivery");
+    }
+    Ok(())
+}
+
+/// Signs a payload body with the webhook secret.
+///
+/// Receivers should compare it with the `corund-signature` header.
+pub fn sign(body: &[u8], secret: &str) -> String {
+    let mut mac: Hmac<Sha256> =
+        Hmac::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    "sha256=".to_string() + to_hex(&mac.finalize().into_bytes()).as_str()
+}
+
+/// Claims due deliveries, so other workers won't pick them up until the
+/// lease expires.
+fn claim_due(
+    batch: i64,
+    lease: Time,
+    con: &mut Con,
+) -> Res<Vec<(WebhookDeliveryTable, WebhookTable)>> {
+    let now = utc();
+    Ok(con.transaction::<_, diesel::result::Error, _>(|con| {
+        let ids = schema::webhook_delivery::table
+            .filter(
+                schema::webhook_delivery::status
+                    .eq(DeliveryStatus::Pending.to_str()),
+            )
+            .filter(schema::webhook_delivery::next_attempt.le(now))
+            .order(schema::webhook_delivery::id.asc())
+            .limit(batch)
+            .select(schema::webhook_delivery::id)
+            .for_update()
+            .skip_locked()
+            .load::<Id>(con)?;
+        diesel::update(
+            schema::webhook_delivery::table
+                .filter(schema::webhook_delivery::id.eq_any(&ids)),
+        )
+        .set(schema::webhook_delivery::next_attempt.eq(now + lease))
+        .execute(con)?;
+        schema::webhook_delivery::table
+            .inner_join(schema::webhook::table)
+            .filter(schema::webhook_delivery::id.eq_any(&ids))
+            .order(schema::webhook_delivery::id.asc())
+            .select((
+                WebhookDeliveryTable::as_select(),
+                WebhookTable::as_select(),
+            ))
+            .load(con)
+    })?)
+}
+
+async fn send(
+    client: &reqwest::Client,
+    delivery: &WebhookDeliveryTable,
+    webhook: &WebhookTable,
+) -> Result<(), String> {
+    let url = parse_url(&webhook.url).map_err(|x| x.msg().to_string())?;
+    let body =
+        serde_json::to_vec(&delivery.payload).map_err(|x| x.to_string())?;
+    let res = client
+        .post(url)
+        .header("content-type", "application/json")
+        .header("corund-event", webhook.event.as_str())
+        .header("corund-delivery", delivery.id.to_string())
+        .header("corund-signature", sign(&body, &webhook.secret))
+        .body(body)
+        .send()
+        .await
+        .map_err(|x| x.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("unsuccessful status {}", res.status()));
+    }
+    Ok(())
+}
+
+/// Sends all due deliveries.
+///
+/// Failed deliveries are retried with exponential backoff until the
+/// configured amount of attempts is reached, after which they become dead.
+///
+/// Returns amount of processed deliveries.
+pub async fn deliver_due(con: &mut Con) -> Res<usize> {
+    let cfg = &APPRC.webhook;
+    let Ok(client) = reqwest::Client::builder()
+        .timeout(Duration::from_secs_f64(cfg.timeout))
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirect::Policy::none())
+        .build()
+    else {
+        return err::res_msg("cannot build http client");
+    };
+    let (batch, lease) = claim_size(cfg.timeout);
+    let due = claim_due(batch, lease, con)?;
+    for (delivery, webhook) in due.iter() {
+        let attempts = delivery.attempts + 1;
+        let q = schema::webhook_delivery::table
+            .filter(schema::webhook_delivery::id.eq(delivery.id));
+        match send(&client, delivery, webhook).await {
+            Ok(()) => {
+                diesel::update(q)
+                    .set((
+                        schema::webhook_delivery::status
+                            .eq(DeliveryStatus::Done.to_str()),
+                        schema::webhook_delivery::attempts.eq(attempts),
+                        schema::webhook_delivery::err
+                            .eq::<Option<String>>(None),
+                    ))
+                    .execute(con)?;
+            }
+            Err(e) => {
+                warn!("webhook delivery {} failed: {}", delivery.id, e);
+                let status = if attempts >= cfg.max_attempts {
+                    DeliveryStatus::Dead
+                } else {
+                    DeliveryStatus::Pending
+                };
+                let next_attempt =
+                    utc() + cfg.backoff * 2f64.powi(attempts - 1);
+                diesel::update(q)
+                    .set((
+                        schema::webhook_delivery::status.eq(status.to_str()),
+                        schema::webhook_delivery::attempts.eq(attempts),
+                        schema::webhook_delivery::next_attempt
+                            .eq(next_attempt),
+                        schema::webhook_delivery::err.eq(Some(e)),
+                    ))
+                    .execute(con)?;
+            }
+        }
+    }
+    Ok(due.len())
+}
+
+/// Delivers webhooks in the background for as long as the server runs.
+///
+/// Errors are logged and the connection is reestablished on the next tick.
+pub async fn run_worker() {
+    let mut con = None;
+    loop {
+        if con.is_none() {
+            con = db::con()
+                .inspect_err(|e| warn!("webhook worker: {:?}", e))
+                .ok();
+        }
+        if let Some(x) = con.as_mut() {
+            if let Err(e) = deliver_due(x).await {
+                warn!("webhook worker: {:?}", e);
+                con = None;
+            }
+        }
+        tokio::time::sleep(WORKER_INTERVAL).await;
+    }
+}
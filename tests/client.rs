@@ -1,14 +1,28 @@
 //! WARN: no parallel testing is supported for now
 
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use axum_test::TestServer;
 use corund_lib::{
+    auth_event,
     db::{self, truncate_tables_if_allowed},
-    get_router, token,
+    get_router,
+    import::{self, ImportFormat, ImportUsers},
+    mfa::{self, TotpEnrollment},
+    quco::{Query, Search},
+    ryz::time::utc,
+    token,
     user::{self, User},
+    verification::{self, CaptureSender, Verification},
+    webauthn::{self, CeremonyOptions, Passkey},
     Reg,
 };
+use data_encoding::BASE64URL_NOPAD;
+use diesel::connection::SimpleConnection;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand_core::OsRng;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 
 static URL: &str = "http://localhost:3000/rpc";
 
@@ -16,6 +30,109 @@ fn new_test_server() -> TestServer {
     TestServer::new(get_router()).unwrap()
 }
 
+/// Authenticator with a single ES256 credential, for WebAuthn ceremonies.
+struct SoftAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+    /// Whether the user is verified, e.g. by PIN.
+    uv: bool,
+    origin: String,
+}
+
+impl SoftAuthenticator {
+    fn new(uv: bool) -> Self {
+        Self {
+            key: SigningKey::random(&mut OsRng),
+            credential_id: Sha256::digest(utc().to_string()).to_vec(),
+            sign_count: 0,
+            uv,
+            origin: "http://localhost:3000".to_string(),
+        }
+    }
+
+    fn id(&self) -> String {
+        BASE64URL_NOPAD.encode(&self.credential_id)
+    }
+
+    fn client_data(&self, typ: &str, options: &CeremonyOptions) -> Vec<u8> {
+        json!({
+            "type": typ,
+            "challenge": options.public_key["challenge"],
+            "origin": self.origin
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn auth_data(&self, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        let mut flags = 0x01;
+        if self.uv {
+            flags |= 0x04;
+        }
+        if attested {
+            flags |= 0x40;
+        }
+        data.push(flags);
+        data.extend(self.sign_count.to_be_bytes());
+        if attested {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let cose = ciborium::Value::Map(vec![
+                (1.into(), 2.into()),
+                (3.into(), (-7).into()),
+                ((-1).into(), 1.into()),
+                ((-2).into(), point.x().unwrap().to_vec().into()),
+                ((-3).into(), point.y().unwrap().to_vec().into()),
+            ]);
+            data.extend([0u8; 16]);
+            data.extend((self.credential_id.len() as u16).to_be_bytes());
+            data.extend(&self.credential_id);
+            ciborium::ser::into_writer(&cose, &mut data).unwrap();
+        }
+        data
+    }
+
+    fn create(&self, options: &CeremonyOptions) -> Value {
+        let attestation = ciborium::Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), ciborium::Value::Map(vec![])),
+            ("authData".into(), self.auth_data(true).into()),
+        ]);
+        let mut attestation_object = vec![];
+        ciborium::ser::into_writer(&attestation, &mut attestation_object)
+            .unwrap();
+        json!({
+            "id": self.id(),
+            "response": {
+                "clientDataJSON": BASE64URL_NOPAD
+                    .encode(&self.client_data("webauthn.create", options)),
+                "attestationObject":
+                    BASE64URL_NOPAD.encode(&attestation_object)
+            }
+        })
+    }
+
+    fn get(&mut self, options: &CeremonyOptions) -> Value {
+        self.sign_count += 1;
+        let client_data = self.client_data("webauthn.get", options);
+        let auth_data = self.auth_data(false);
+        let signature: Signature = self.key.sign(
+            &[auth_data.clone(), Sha256::digest(&client_data).to_vec()]
+                .concat(),
+        );
+        json!({
+            "id": self.id(),
+            "response": {
+                "clientDataJSON": BASE64URL_NOPAD.encode(&client_data),
+                "authenticatorData": BASE64URL_NOPAD.encode(&auth_data),
+                "signature":
+                    BASE64URL_NOPAD.encode(&signature.to_der().to_bytes())
+            }
+        })
+    }
+}
+
 #[tokio::test]
 async fn login_std_ok() {
     truncate_tables_if_allowed();
@@ -27,6 +144,9 @@ async fn login_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -43,8 +163,8 @@ async fn login_std_ok() {
     assert!(response.status_code() == 200);
     let rt = response.text();
 
-    let user = user::get_by_id(1, con).unwrap();
-    assert!(user.rt == Some(rt));
+    let (user, _) = user::get_by_rt(&rt, con).unwrap();
+    assert!(user.id == 1);
 }
 
 #[tokio::test]
@@ -58,6 +178,9 @@ async fn logout_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -75,12 +198,11 @@ async fn logout_std_ok() {
     let rt = response.text();
     let response = server
         .post((URL.to_string() + "/logout").as_str())
-        .json(&HashMap::from([("rt", rt)]))
+        .json(&HashMap::from([("rt", &rt)]))
         .await;
     assert!(response.status_code() == 200);
 
-    let user = user::get_by_id(1, con).unwrap();
-    assert!(user.rt == None);
+    assert!(user::get_by_rt(&rt, con).is_err());
 }
 
 #[tokio::test]
@@ -94,6 +216,9 @@ async fn access_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -132,6 +257,9 @@ async fn current_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -152,12 +280,634 @@ async fn current_std_ok() {
         .json(&HashMap::from([("rt", &rt)]))
         .await;
     assert!(response.status_code() == 200);
+    let raw: Value = response.json();
+    assert!(raw.get("rt").is_none(), "must not expose refresh token");
     let user: User = response.json();
     assert_eq!(user.id, actual_user.id);
     assert_eq!(user.username, actual_user.username);
     assert_eq!(user.firstname, actual_user.firstname);
     assert_eq!(user.patronym, actual_user.patronym);
     assert_eq!(user.surname, actual_user.surname);
-    assert_eq!(user.rt, Some(rt));
-    assert_eq!(actual_user.rt, None)
+}
+
+#[tokio::test]
+async fn login_legacy_hash_rehash_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    let data = [
+        json!({"username": "bcrypt", "hpassword":
+            "$2b$04$2zECkL4aZV2kAV5ZJ2fcnOOA6ZeOnSVJMPWoHmu34zwakBPo6NPye"}),
+        json!({"username": "pbkdf2", "hpassword":
+            "$pbkdf2-sha256$i=1000,l=32$c2FsdHNhbHRzYWx0c2FsdA\
+             $Yo7f0NJD76SOoRMf9wsRfo7jhsRsIhoioGLVJlCb2Gs"}),
+        json!({"username": "scrypt", "hpassword":
+            "$scrypt$ln=10,r=8,p=1$c56VZPjfx/uGUF4J56oLEA\
+             $FoDr23jW+r7ghJypTF+iq19lndr+Nj/qpLb3U1+b0Oo"}),
+    ]
+    .map(|x| x.to_string())
+    .join("\n");
+    let res = import::import(
+        &ImportUsers {
+            format: ImportFormat::Jsonl,
+            data,
+        },
+        con,
+    )
+    .unwrap();
+    assert!(res.errs.is_empty());
+
+    let server = new_test_server();
+    for username in ["bcrypt", "pbkdf2", "scrypt"] {
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": username, "password": "4321"}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let (_, hpassword) = user::get_by_username(username, con).unwrap();
+        assert!(!hpassword.starts_with("$argon2"));
+
+        for _ in 0..2 {
+            let response = server
+                .post((URL.to_string() + "/login").as_str())
+                .json(&json!({"username": username, "password": "1234"}))
+                .await;
+            assert_eq!(response.status_code(), 200);
+            let (_, hpassword) = user::get_by_username(username, con).unwrap();
+            assert!(hpassword.starts_with("$argon2id$"));
+        }
+    }
+    assert!(user::get_by_username("nobody", con).is_err());
+}
+
+#[tokio::test]
+async fn login_outdated_argon2_rehash_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "current".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    let data = [
+        // made without the configured pepper
+        json!({"username": "unpeppered", "hpassword":
+            "$argon2id$v=19$m=19456,t=2,p=1$3fO4A4sf5Fu3H2UF04mTwg\
+             $UhxzE5Zbo/tncY76akSj+e/LVdI1lFUkxJIKkwYDc2M"}),
+        // made with the pepper, but with lower costs
+        json!({"username": "weak", "hpassword":
+            "$argon2id$v=19$m=4096,t=1,p=1$ciHT0LjCzln3pQcZ9AvqOA\
+             $jMlgTsBiAsXefb9nm+ABpjAtrhFq277eXpf16e4mdvU"}),
+    ]
+    .map(|x| x.to_string())
+    .join("\n");
+    let res = import::import(
+        &ImportUsers {
+            format: ImportFormat::Jsonl,
+            data,
+        },
+        con,
+    )
+    .unwrap();
+    assert!(res.errs.is_empty());
+
+    let server = new_test_server();
+    for (username, rehashed) in
+        [("current", false), ("unpeppered", true), ("weak", true)]
+    {
+        let (_, before) = user::get_by_username(username, con).unwrap();
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": username, "password": "1234"}))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let (_, after) = user::get_by_username(username, con).unwrap();
+        assert_eq!(before != after, rehashed, "{}", username);
+        assert!(after.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": username, "password": "1234"}))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let (_, again) = user::get_by_username(username, con).unwrap();
+        assert_eq!(after, again, "{}", username);
+    }
+}
+
+#[tokio::test]
+async fn change_password_std_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let rt = response.text();
+
+    let response = server
+        .post((URL.to_string() + "/change_password").as_str())
+        .json(&json!({"rt": rt, "password": "4321", "new_password": "5678"}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = server
+        .post((URL.to_string() + "/change_password").as_str())
+        .json(&json!({"rt": rt, "password": "1234", "new_password": "abcd"}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "password_policy");
+    assert_eq!(e["data"]["violations"][0]["rule"], "require_digit");
+
+    let response = server
+        .post((URL.to_string() + "/change_password").as_str())
+        .json(&json!({"rt": rt, "password": "1234", "new_password": "5678"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    for (password, status) in [("1234", 400), ("5678", 200)] {
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": "hello", "password": password}))
+            .await;
+        assert_eq!(response.status_code(), status);
+    }
+}
+
+#[tokio::test]
+async fn new_verification_capture_sender_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    let user = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: Some("+79001234567".to_string()),
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .await;
+    let rt = response.text();
+
+    verification::set_sender(None);
+    let response = server
+        .post((URL.to_string() + "/new_verification").as_str())
+        .json(&json!({"rt": rt, "kind": "Phone"}))
+        .await;
+    assert_eq!(response.status_code(), 400, "codes cannot go to client");
+
+    let capture = CaptureSender::default();
+    verification::set_sender(Some(Arc::new(capture.clone())));
+    let response = server
+        .post((URL.to_string() + "/new_verification").as_str())
+        .json(&json!({"rt": rt, "kind": "Email"}))
+        .await;
+    assert_eq!(response.status_code(), 400, "user has no email");
+
+    let response = server
+        .post((URL.to_string() + "/new_verification").as_str())
+        .json(&json!({"rt": rt, "kind": "Phone"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let v: Verification = response.json();
+    assert!(v.code.is_none());
+    assert_eq!(v.target, "+79001234567");
+    let sent = capture.sent.lock().unwrap().clone();
+    verification::set_sender(None);
+    assert!(sent.len() == 1);
+    assert!(sent[0].id == v.id);
+    let code = sent[0].code.clone().unwrap();
+    assert!(code.len() == 6);
+
+    // the verification dies after too many wrong codes
+    for _ in 0..5 {
+        let response = server
+            .post((URL.to_string() + "/verify").as_str())
+            .json(&json!({"id": v.id, "code": "wrong"}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+    let response = server
+        .post((URL.to_string() + "/verify").as_str())
+        .json(&json!({"id": v.id, "code": code}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let user = user::get_by_id(user.id, con).unwrap();
+    assert!(user.phone_verified_at.is_none());
+
+    // new codes are rate-limited and invalidate the earlier ones
+    verification::set_sender(Some(Arc::new(capture.clone())));
+    let new_verification = || {
+        server
+            .post((URL.to_string() + "/new_verification").as_str())
+            .json(&json!({"rt": rt, "kind": "Phone"}))
+    };
+    let e: Value = new_verification().await.json();
+    assert_eq!(e["code"], "rate_limited");
+    con.batch_execute("UPDATE verification SET created = created - 60")
+        .unwrap();
+    let response = new_verification().await;
+    assert_eq!(response.status_code(), 200);
+    let renewed: Verification = response.json();
+    let code = capture.sent.lock().unwrap().last().unwrap().code.clone();
+    verification::set_sender(None);
+    let e: Value = server
+        .post((URL.to_string() + "/verify").as_str())
+        .json(&json!({"id": v.id, "code": "wrong"}))
+        .await
+        .json();
+    assert_eq!(e["msg"], "no such verification");
+    let response = server
+        .post((URL.to_string() + "/verify").as_str())
+        .json(&json!({"id": renewed.id, "code": code.unwrap()}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let user = user::get_by_id(user.id, con).unwrap();
+    assert!(user.phone_verified_at.is_some());
+}
+
+#[tokio::test]
+async fn login_mfa_totp_ok() {
+    truncate_tables_if_allowed();
+    // RFC 6238 test vector for SHA1 at 59 seconds, truncated to 6 digits
+    assert_eq!(
+        mfa::get_totp_code("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 1).unwrap(),
+        "287082"
+    );
+
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let login = || {
+        server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": "hello", "password": "1234"}))
+    };
+    let rt = login().await.text();
+
+    let response = server
+        .post((URL.to_string() + "/new_totp").as_str())
+        .json(&json!({"rt": rt}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let enrollment: TotpEnrollment = response.json();
+    assert!(enrollment.uri.starts_with("otpauth://totp/corund:hello?"));
+    assert!(enrollment.uri.contains(&enrollment.secret));
+    let code = || {
+        mfa::get_totp_code(&enrollment.secret, mfa::get_step(utc())).unwrap()
+    };
+
+    let response = server
+        .post((URL.to_string() + "/confirm_totp").as_str())
+        .json(&json!({"rt": rt, "code": "000000x"}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let response = server
+        .post((URL.to_string() + "/confirm_totp").as_str())
+        .json(&json!({"rt": rt, "code": code()}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let recovery_codes: Vec<String> = response.json();
+    assert_eq!(recovery_codes.len(), 10);
+
+    // password alone is no longer enough
+    let response = login().await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "mfa_required");
+    assert_eq!(e["data"]["enroll"], false);
+    let challenge = e["data"]["challenge"].as_str().unwrap().to_string();
+
+    // the password alone cannot add a passkey to skip TOTP
+    let response = server
+        .post((URL.to_string() + "/webauthn/begin_registration").as_str())
+        .json(&json!({"challenge": challenge}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "mfa_enabled");
+
+    let login_mfa = |challenge: &str, code: &str| {
+        server
+            .post((URL.to_string() + "/login/mfa").as_str())
+            .json(&json!({"challenge": challenge, "code": code}))
+    };
+    assert_eq!(login_mfa(&challenge, "wrong").await.status_code(), 400);
+    let response = login_mfa(&challenge, &code()).await;
+    assert_eq!(response.status_code(), 200);
+    let rt = response.text();
+    let (user, _) = user::get_by_rt(&rt, con).unwrap();
+
+    // a challenge completes only one login
+    let e: Value = login_mfa(&challenge, &recovery_codes[0]).await.json();
+    assert_eq!(e["code"], "invalid_token");
+    // and cannot be forged without the configured secret
+    let forged = token::new_token(
+        &token::MfaChallengePayload {
+            user_id: user.id,
+            created: utc(),
+            nonce: "0".to_string(),
+        },
+        b"twofactors",
+    )
+    .unwrap();
+    let e: Value = login_mfa(&forged, &recovery_codes[0]).await.json();
+    assert_eq!(e["code"], "invalid_token");
+
+    // a code is accepted only once, but recovery codes still work
+    let e: Value = login().await.json();
+    let challenge = e["data"]["challenge"].as_str().unwrap().to_string();
+    let response = login_mfa(&challenge, &code()).await;
+    assert_eq!(response.status_code(), 400);
+    let response = login_mfa(&challenge, &recovery_codes[0]).await;
+    assert_eq!(response.status_code(), 200);
+    let e: Value = login().await.json();
+    let challenge = e["data"]["challenge"].as_str().unwrap().to_string();
+    let response = login_mfa(&challenge, &recovery_codes[0]).await;
+    assert_eq!(response.status_code(), 400);
+
+    // too many wrong codes lock the user until the window ends, even for
+    // new logins with password
+    for _ in 0..5 {
+        login_mfa(&challenge, "wrong").await;
+    }
+    let response = login_mfa(&challenge, &recovery_codes[1]).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = login().await.json();
+    let challenge = e["data"]["challenge"].as_str().unwrap().to_string();
+    let response = login_mfa(&challenge, &recovery_codes[1]).await;
+    assert_eq!(response.status_code(), 400);
+    con.batch_execute("UPDATE mfa SET attempts_since = attempts_since - 900")
+        .unwrap();
+    let response = login_mfa(&challenge, &recovery_codes[1]).await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn webauthn_passkey_login_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    let user = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let post = |path: &str, body: Value| {
+        server.post((URL.to_string() + path).as_str()).json(&body)
+    };
+    let rt = post("/login", json!({"username": "hello", "password": "1234"}))
+        .await
+        .text();
+
+    let mut passkey = SoftAuthenticator::new(true);
+    let options: CeremonyOptions =
+        post("/webauthn/begin_registration", json!({"rt": rt}))
+            .await
+            .json();
+    assert_eq!(options.public_key["rp"]["id"], "localhost");
+    let response = post(
+        "/webauthn/finish_registration",
+        json!({
+            "rt": rt,
+            "id": options.id,
+            "credential": passkey.create(&options),
+            "name": "laptop"
+        }),
+    )
+    .await;
+    assert_eq!(response.status_code(), 200);
+    let registered: Passkey = response.json();
+    assert_eq!(registered.user_id, user.id);
+
+    // passwordless login with a discoverable passkey
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({})).await.json();
+    let credential = passkey.get(&options);
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": credential}),
+    )
+    .await;
+    assert_eq!(response.status_code(), 200);
+    let rt = response.text();
+    assert_eq!(user::get_by_rt(&rt, con).unwrap().0.id, user.id);
+    let events = auth_event::get_many(
+        &Search {
+            sq: Query::from([("kind".to_string(), json!("login"))]),
+            ..Default::default()
+        },
+        con,
+    )
+    .unwrap()
+    .events;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[1]["user_id"], user.id);
+    assert_eq!(events[1]["outcome"], "Success");
+
+    // each ceremony can be finished once
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": credential}),
+    )
+    .await;
+    assert_eq!(response.status_code(), 400);
+
+    // old counters and other origins are rejected
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({"username": "hello"}))
+            .await
+            .json();
+    assert_eq!(
+        options.public_key["allowCredentials"][0]["id"],
+        passkey.id().as_str()
+    );
+    passkey.sign_count = 0;
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": passkey.get(&options)}),
+    )
+    .await;
+    assert_eq!(response.status_code(), 400);
+    let mut other_origin = SoftAuthenticator::new(true);
+    other_origin.key = passkey.key.clone();
+    other_origin.credential_id = passkey.credential_id.clone();
+    other_origin.sign_count = 10;
+    other_origin.origin = "http://evil.com".to_string();
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({})).await.json();
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": other_origin.get(&options)}),
+    )
+    .await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "invalid_credential");
+
+    // a passkey is now required after password
+    let response =
+        post("/login", json!({"username": "hello", "password": "1234"})).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "mfa_required");
+    assert_eq!(e["data"]["webauthn"], true);
+    let challenge = e["data"]["challenge"].as_str().unwrap().to_string();
+    let response = post("/new_totp", json!({"challenge": challenge})).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "mfa_enabled");
+
+    // security keys without user verification are only a second factor
+    let mut key = SoftAuthenticator::new(false);
+    let options: CeremonyOptions =
+        post("/webauthn/begin_registration", json!({"rt": rt}))
+            .await
+            .json();
+    let response = post(
+        "/webauthn/finish_registration",
+        json!({
+            "rt": rt,
+            "id": options.id,
+            "credential": key.create(&options)
+        }),
+    )
+    .await;
+    assert_eq!(response.status_code(), 200);
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({})).await.json();
+    let credential = key.get(&options);
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": credential}),
+    )
+    .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "user_verification_required");
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({})).await.json();
+    let response = post(
+        "/webauthn/login",
+        json!({
+            "id": options.id,
+            "credential": key.get(&options),
+            "challenge": challenge
+        }),
+    )
+    .await;
+    assert_eq!(response.status_code(), 200);
+    let rt = response.text();
+
+    let passkeys: Vec<Passkey> =
+        post("/webauthn/get_passkeys", json!({"rt": rt}))
+            .await
+            .json();
+    assert_eq!(passkeys.len(), 2);
+    assert_eq!(passkeys[0].name, Some("laptop".to_string()));
+    assert!(passkeys[0].last_used.is_some());
+    for passkey in passkeys {
+        let response =
+            post("/webauthn/del_passkey", json!({"rt": rt, "id": passkey.id}))
+                .await;
+        assert_eq!(response.status_code(), 200);
+    }
+    let response =
+        post("/login", json!({"username": "hello", "password": "1234"})).await;
+    assert_eq!(response.status_code(), 200);
+    let rt = response.text();
+
+    // archived users cannot login with their passkeys, which are deleted
+    let mut passkey = SoftAuthenticator::new(true);
+    let options: CeremonyOptions =
+        post("/webauthn/begin_registration", json!({"rt": rt}))
+            .await
+            .json();
+    let response = post(
+        "/webauthn/finish_registration",
+        json!({
+            "rt": rt,
+            "id": options.id,
+            "credential": passkey.create(&options)
+        }),
+    )
+    .await;
+    assert_eq!(response.status_code(), 200);
+    con.batch_execute(
+        "UPDATE appuser SET username = 'archived::' || username",
+    )
+    .unwrap();
+    let options: CeremonyOptions =
+        post("/webauthn/begin_login", json!({})).await.json();
+    let response = post(
+        "/webauthn/login",
+        json!({"id": options.id, "credential": passkey.get(&options)}),
+    )
+    .await;
+    assert_eq!(response.status_code(), 400);
+    con.batch_execute("UPDATE appuser SET username = substr(username, 11)")
+        .unwrap();
+    user::del(&Query::from([("id".to_string(), json!(user.id))]), con)
+        .unwrap();
+    assert_eq!(webauthn::count(user.id, con).unwrap(), 0);
 }
@@ -1,18 +1,53 @@
 //! WARN: no parallel testing is supported for now
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use axum::{
+    body::Bytes, extract::State, http::HeaderMap, http::StatusCode,
+    routing::post, Form, Router,
+};
 use axum_test::TestServer;
 use corund_lib::{
+    auth_event::{
+        self, AuthEvent, AuthEventKind, AuthOutcome, GetAuthEventsRes, Origin,
+    },
     db::{self, truncate_tables_if_allowed},
     get_router,
-    quco::Query,
+    import::{ImportRowErr, ImportUsersRes},
+    mailer::{self, MailSender, Sink},
+    mfa::{self, MfaStatus, TotpEnrollment},
+    oidc::{IdTokenPayload, OauthClient},
+    org::{MemberRole, Membership, Org},
+    quco::{Query, Search},
+    role::{Role, UserRole},
     ryz::time::utc,
-    user::{self, GetUsers, User},
-    user_change::{self, ChangeAction, UserChange},
+    token,
+    upstream::{Identity, UpstreamRedirect},
+    user::{self, GetUsers, GetUsersRes, User},
+    user_change::{
+        self, ChangeAction, GetUserChanges, GetUserChangesRes, NewUserChange,
+        UserChange,
+    },
+    verification::{self, Verification},
+    webhook::{
+        self, DeliveryStatus, GetWebhookDeliveries, NewWebhook, Webhook,
+        WebhookDelivery, WebhookEvent,
+    },
     Reg,
 };
+use data_encoding::BASE64URL_NOPAD;
+use diesel::connection::SimpleConnection;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
 use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
 
 static URL: &str = "http://localhost:3000/rpc";
 static DOMAIN_SECRET: &str = "backtomegaton";
@@ -21,6 +56,137 @@ fn new_test_server() -> TestServer {
     TestServer::new(get_router()).unwrap()
 }
 
+type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+/// Spawns a local HTTP stub receiving webhooks at `/ok`, failing them at
+/// `/fail` and answering after 5 seconds at `/slow`.
+///
+/// Returns the stub's base url and the requests received by `/ok`.
+async fn new_webhook_stub() -> (String, Received) {
+    let received: Received = Arc::new(Mutex::new(vec![]));
+    let router = Router::new()
+        .route(
+            "/ok",
+            post(
+                |State(received): State<Received>,
+                 headers: HeaderMap,
+                 body: Bytes| async move {
+                    received.lock().unwrap().push((headers, body));
+                    StatusCode::OK
+                },
+            ),
+        )
+        .route(
+            "/fail",
+            post(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        )
+        .route(
+            "/slow",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                StatusCode::OK
+            }),
+        )
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    (url, received)
+}
+
+/// Fetches user changes as messages, see `user_change::get_many`.
+fn get_user_changes(
+    inp: &GetUserChanges,
+    con: &mut db::Con,
+) -> Vec<UserChange> {
+    user_change::get_many(inp, con)
+        .unwrap()
+        .changes
+        .into_iter()
+        .map(|x| serde_json::from_value(x).unwrap())
+        .collect()
+}
+
+/// Minimal SMTP server which accepts any mail and keeps it's data.
+async fn new_smtp_stub() -> (u16, Arc<Mutex<Vec<String>>>) {
+    let received = Arc::new(Mutex::new(vec![]));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received_ = received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (r, mut w) = stream.into_split();
+            let mut lines = BufReader::new(r).lines();
+            w.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match line.to_uppercase().get(..4) {
+                    Some("DATA") => {
+                        w.write_all(b"354 go ahead\r\n").await.unwrap();
+                        let mut data = vec![];
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if line == "." {
+                                break;
+                            }
+                            data.push(line);
+                        }
+                        received_.lock().unwrap().push(data.join("\n"));
+                        "250 queued"
+                    }
+                    Some("QUIT") => "221 bye",
+                    _ => "250 ok",
+                };
+                w.write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+    (port, received)
+}
+
+/// Address of the mock OpenID Connect provider, as set by the `corp`
+/// upstream of test config.
+static IDP_ADDR: &str = "127.0.0.1:9016";
+
+/// Spawns a mock OpenID Connect provider, whose token endpoint returns an
+/// unsigned ID token with the currently set claims.
+async fn new_idp_stub() -> Arc<Mutex<Value>> {
+    let claims = Arc::new(Mutex::new(json!({})));
+    let router = Router::new()
+        .route(
+            "/token",
+            post(
+                |State(claims): State<Arc<Mutex<Value>>>,
+                 Form(form): Form<HashMap<String, String>>| async move {
+                    if form["client_secret"] != "corpsecret"
+                        || !form.contains_key("code_verifier")
+                    {
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                    let b64 = |x: &Value| {
+                        data_encoding::BASE64URL_NOPAD
+                            .encode(x.to_string().as_bytes())
+                    };
+                    let payload = claims.lock().unwrap().clone();
+                    Ok(axum::Json(json!({
+                        "access_token": "upstream",
+                        "token_type": "Bearer",
+                        "id_token": format!(
+                            "{}.{}.",
+                            b64(&json!({"alg": "none"})),
+                            b64(&payload)
+                        )
+                    })))
+                },
+            ),
+        )
+        .with_state(claims.clone());
+    let listener = tokio::net::TcpListener::bind(IDP_ADDR).await.unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    claims
+}
+
 #[tokio::test]
 async fn reg_std_ok() {
     truncate_tables_if_allowed();
@@ -34,6 +200,8 @@ async fn reg_std_ok() {
         .add_header("domain_secret", DOMAIN_SECRET)
         .await;
     assert_eq!(response.status_code(), 200);
+    let raw: Value = response.json();
+    assert!(raw.get("rt").is_none(), "must not expose refresh token");
     let user: User = response.json();
 
     assert_eq!(user.id, 1);
@@ -41,7 +209,6 @@ async fn reg_std_ok() {
     assert_eq!(user.firstname, None);
     assert_eq!(user.patronym, None);
     assert_eq!(user.surname, None);
-    assert_eq!(user.rt, None);
 }
 
 #[tokio::test]
@@ -57,6 +224,9 @@ async fn dereg_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -71,11 +241,20 @@ async fn dereg_std_ok() {
     assert_eq!(response.status_code(), 200);
 
     assert!(
-        user::get_many_as_ids(con).unwrap().len() == 0,
+        user::get_many_as_ids(con).unwrap().is_empty(),
         "must be no users"
     );
 
-    let changes = user_change::get_many(test_start_time, con).unwrap();
+    let changes = get_user_changes(
+        &GetUserChanges {
+            from: test_start_time,
+            actions: None,
+            user_ids: None,
+            with_data: false,
+            search: Default::default(),
+        },
+        con,
+    );
     assert!(changes.len() == 2, "must retain new and del user changes");
     assert!(changes[0].user_id == user.id);
     assert!(changes[0].action == ChangeAction::New);
@@ -83,6 +262,65 @@ async fn dereg_std_ok() {
     assert!(changes[1].action == ChangeAction::Del);
 }
 
+#[tokio::test]
+async fn reg_duplicate_username_err() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let con = &mut db::con().unwrap();
+    let reg = Reg {
+        username: "hello".to_string(),
+        password: "1234".to_string(),
+        firstname: None,
+        patronym: None,
+        surname: None,
+        email: None,
+        phone: None,
+        metadata: Default::default(),
+    };
+    user::new(&reg, con).unwrap();
+    assert!(user::new(&reg, con).is_err());
+
+    let changes = get_user_changes(
+        &GetUserChanges {
+            from: test_start_time,
+            actions: None,
+            user_ids: None,
+            with_data: false,
+            search: Default::default(),
+        },
+        con,
+    );
+    assert!(changes.len() == 1, "failed reg must not write a change");
+}
+
+#[tokio::test]
+async fn dereg_no_user_err() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/dereg").as_str())
+        .json(&HashMap::from([("username", "hello")]))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let con = &mut db::con().unwrap();
+    let changes = get_user_changes(
+        &GetUserChanges {
+            from: test_start_time,
+            actions: None,
+            user_ids: None,
+            with_data: false,
+            search: Default::default(),
+        },
+        con,
+    );
+    assert!(changes.is_empty(), "failed dereg must not write a change");
+}
+
 #[tokio::test]
 async fn get_user_changes_std_ok() {
     truncate_tables_if_allowed();
@@ -96,6 +334,9 @@ async fn get_user_changes_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -107,6 +348,9 @@ async fn get_user_changes_std_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -127,7 +371,7 @@ async fn get_user_changes_std_ok() {
         .add_header("domain_secret", DOMAIN_SECRET)
         .await;
     assert_eq!(response.status_code(), 200);
-    let changes: Vec<UserChange> = response.json();
+    let changes = response.json::<Vec<UserChange>>();
     assert!(changes.len() == 3);
     assert!(changes[0].user_id == user1.id);
     assert!(changes[0].action == ChangeAction::New);
@@ -135,6 +379,328 @@ async fn get_user_changes_std_ok() {
     assert!(changes[1].action == ChangeAction::New);
     assert!(changes[2].user_id == user2.id);
     assert!(changes[2].action == ChangeAction::Del);
+    assert!(changes.iter().all(|x| x.data.is_none()));
+
+    // newest first, by pages
+    let page = |after: Option<Vec<Value>>| {
+        server
+            .post((URL.to_string() + "/server/search_user_changes").as_str())
+            .json(&json!({
+                "from": test_start_time,
+                "sort": ["-id"],
+                "limit": 2,
+                "after": after,
+                "count": true
+            }))
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let res: GetUserChangesRes<UserChange> = page(None).await.json();
+    assert_eq!(res.total, Some(3));
+    assert_eq!(res.changes.len(), 2);
+    assert!(res.changes[0].action == ChangeAction::Del);
+    let res: GetUserChangesRes<UserChange> = page(res.next).await.json();
+    assert_eq!(res.changes.len(), 1);
+    assert!(res.changes[0].user_id == user1.id);
+    assert!(res.next.is_none());
+
+    // paging is opt-in, test mode has pages of at most 50 changes
+    user_change::new_many(
+        &(0..60)
+            .map(|_| NewUserChange {
+                user_id: user1.id,
+                action: ChangeAction::Update,
+                data: None,
+            })
+            .collect::<Vec<_>>(),
+        con,
+    )
+    .unwrap();
+    let search = |body: Value| {
+        server
+            .post((URL.to_string() + "/server/search_user_changes").as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let res: GetUserChangesRes<Value> =
+        search(json!({"from": test_start_time})).await.json();
+    assert_eq!(res.changes.len(), 63);
+    assert!(res.next.is_none());
+    let res: GetUserChangesRes<Value> =
+        search(json!({"from": test_start_time, "limit": 100}))
+            .await
+            .json();
+    assert_eq!(res.changes.len(), 50);
+
+    // only projected fields are selected, sort fields still make the cursor
+    let res: GetUserChangesRes<Value> = search(json!({
+        "from": test_start_time,
+        "sort": ["-id"],
+        "limit": 1,
+        "fields": ["action"]
+    }))
+    .await
+    .json();
+    assert_eq!(res.changes, [json!({"action": "Update"})]);
+    assert!(res.next.is_some());
+}
+
+#[tokio::test]
+async fn get_user_changes_filter_with_data_ok() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    let user2 = user::new(
+        &Reg {
+            username: "world".to_string(),
+            password: "1234".to_string(),
+            firstname: Some("Ivan".to_string()),
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::del(
+        &Query::from([(
+            "username".to_string(),
+            Value::String("world".to_string()),
+        )]),
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/get_user_changes").as_str())
+        .json(&json!({
+            "from": test_start_time,
+            "actions": ["New"],
+            "user_ids": [user2.id],
+            "with_data": true
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let changes = response.json::<Vec<UserChange>>();
+    assert!(changes.len() == 1);
+    assert!(changes[0].user_id == user2.id);
+    assert!(changes[0].action == ChangeAction::New);
+    assert!(changes[0].data.as_ref().unwrap().get("rt").is_none());
+    let data: User =
+        serde_json::from_value(changes[0].data.clone().unwrap()).unwrap();
+    assert!(data == user2);
+}
+
+#[tokio::test]
+async fn get_user_changes_query_ok() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let con = &mut db::con().unwrap();
+    let user1 = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::new(
+        &Reg {
+            username: "world".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::del(
+        &Query::from([(
+            "username".to_string(),
+            Value::String("world".to_string()),
+        )]),
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/get_user_changes").as_str())
+        .json(&json!({
+            "from": test_start_time,
+            "sq": {
+                "$or": [
+                    {"user_id": user1.id},
+                    {"action": {"$in": ["del"]}}
+                ]
+            }
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let changes = response.json::<Vec<UserChange>>();
+    assert!(changes.len() == 2);
+    assert!(changes[0].user_id == user1.id);
+    assert!(changes[1].action == ChangeAction::Del);
+
+    let response = server
+        .post((URL.to_string() + "/server/get_user_changes").as_str())
+        .json(&json!({
+            "from": test_start_time,
+            "sq": {"action": "upd"}
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let update = user_change::new(
+        &NewUserChange {
+            user_id: user1.id,
+            action: ChangeAction::Update,
+            data: None,
+        },
+        con,
+    )
+    .unwrap();
+    let response = server
+        .post((URL.to_string() + "/server/get_user_changes").as_str())
+        .json(&json!({
+            "from": test_start_time,
+            "sq": {"action": "update"}
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let changes = response.json::<Vec<UserChange>>();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].id, update.id);
+}
+
+#[tokio::test]
+async fn stream_user_changes_std_ok() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let con = &mut db::con().unwrap();
+    let user1 = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let (tx, mut rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        user_change::stream(
+            GetUserChanges {
+                from: test_start_time,
+                actions: None,
+                user_ids: None,
+                with_data: false,
+                search: Default::default(),
+            },
+            tx,
+        )
+        .await
+        .unwrap()
+    });
+
+    // replayed
+    let change = rx.recv().await.unwrap();
+    assert!(change.user_id == user1.id);
+    assert!(change.action == ChangeAction::New);
+
+    // pushed
+    let user2 = user::new(
+        &Reg {
+            username: "world".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    let change = rx.recv().await.unwrap();
+    assert!(change.user_id == user2.id);
+    assert!(change.action == ChangeAction::New);
+
+    // committed out of the order of ids
+    let other = &mut db::con().unwrap();
+    other.batch_execute("BEGIN").unwrap();
+    let earlier = user_change::new(
+        &NewUserChange {
+            user_id: user1.id,
+            action: ChangeAction::Update,
+            data: None,
+        },
+        other,
+    )
+    .unwrap();
+    let later = user_change::new(
+        &NewUserChange {
+            user_id: user2.id,
+            action: ChangeAction::Update,
+            data: None,
+        },
+        con,
+    )
+    .unwrap();
+    assert!(earlier.id < later.id);
+    assert_eq!(rx.recv().await.unwrap().id, later.id);
+    other.batch_execute("COMMIT").unwrap();
+    assert_eq!(rx.recv().await.unwrap().id, earlier.id);
+}
+
+#[tokio::test]
+async fn stream_user_changes_no_secret_err() {
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/stream_user_changes").as_str())
+        .json(&json!({"from": utc()}))
+        .await;
+    assert_eq!(response.status_code(), 400);
 }
 
 #[tokio::test]
@@ -149,6 +715,9 @@ async fn get_users_direct_id_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -160,6 +729,9 @@ async fn get_users_direct_id_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -171,11 +743,12 @@ async fn get_users_direct_id_ok() {
         .add_header("domain_secret", DOMAIN_SECRET)
         .json(&GetUsers {
             sq: Query::from([("id".to_string(), json!(1))]),
+            ..Default::default()
         })
         .await;
 
     assert!(response.status_code() == 200, "{}", response.text());
-    let users: Vec<User> = response.json();
+    let users = response.json::<GetUsersRes<User>>().users;
     assert!(users.len() == 1);
     assert!(users[0] == user1);
 }
@@ -192,6 +765,9 @@ async fn get_users_in_id_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -203,6 +779,9 @@ async fn get_users_in_id_ok() {
             firstname: None,
             patronym: None,
             surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
         },
         con,
     )
@@ -222,8 +801,2000 @@ async fn get_users_in_id_ok() {
         .await;
 
     assert!(response.status_code() == 200, "{}", response.text());
-    let users: Vec<User> = response.json();
+    let users = response.json::<GetUsersRes<User>>().users;
     assert!(users.len() == 2);
     assert!(users[0] == user1);
     assert!(users[1] == user2);
 }
+
+#[tokio::test]
+async fn webhook_deliver_std_ok() {
+    truncate_tables_if_allowed();
+    let (url, received) = new_webhook_stub().await;
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/new_webhook").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"event": "UserNew", "url": url.clone() + "/ok"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let hook: Webhook = response.json();
+    assert!(hook.event == WebhookEvent::UserNew);
+
+    let con = &mut db::con().unwrap();
+    let user = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 1);
+
+    let received = received.lock().unwrap().clone();
+    assert!(received.len() == 1);
+    let (headers, body) = &received[0];
+    assert_eq!(
+        headers.get("corund-signature").unwrap().to_str().unwrap(),
+        webhook::sign(body, &hook.secret)
+    );
+    let payload: webhook::WebhookPayload =
+        serde_json::from_slice(body).unwrap();
+    assert!(payload.event == WebhookEvent::UserNew);
+    assert!(payload.user_id == user.id);
+    assert!(payload.change.unwrap().action == ChangeAction::New);
+
+    let response = server
+        .post((URL.to_string() + "/server/get_webhook_deliveries").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"webhook_id": hook.id}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let deliveries: Vec<WebhookDelivery> = response.json();
+    assert!(deliveries.len() == 1);
+    assert!(deliveries[0].status == DeliveryStatus::Done);
+    assert!(deliveries[0].attempts == 1);
+}
+
+#[tokio::test]
+async fn webhook_dead_redeliver_ok() {
+    truncate_tables_if_allowed();
+    let (url, _) = new_webhook_stub().await;
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/new_webhook").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"event": "UserNew", "url": url + "/fail"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    // test mode allows 2 attempts without backoff
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 1);
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 1);
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 0);
+
+    let response = server
+        .post((URL.to_string() + "/server/get_webhook_deliveries").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"status": "Dead"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let deliveries: Vec<WebhookDelivery> = response.json();
+    assert!(deliveries.len() == 1);
+    assert!(deliveries[0].attempts == 2);
+    assert!(deliveries[0].err.is_some());
+
+    let response = server
+        .post((URL.to_string() + "/server/redeliver_webhook").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"id": deliveries[0].id}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn webhook_timeout_err() {
+    truncate_tables_if_allowed();
+    let (url, _) = new_webhook_stub().await;
+    let con = &mut db::con().unwrap();
+    webhook::new(
+        &NewWebhook {
+            event: WebhookEvent::UserNew,
+            url: url + "/slow",
+        },
+        con,
+    )
+    .unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    // test mode waits for a receiver for 1 second
+    let start = utc();
+    assert_eq!(webhook::deliver_due(con).await.unwrap(), 1);
+    assert!(utc() - start < 3.0);
+    let deliveries = webhook::get_deliveries(
+        &GetWebhookDeliveries {
+            webhook_id: None,
+            status: None,
+        },
+        con,
+    )
+    .unwrap();
+    assert_eq!(deliveries[0].attempts, 1);
+    assert!(deliveries[0].err.is_some());
+
+    // the batch is sent within it's lease
+    let (batch, lease) = webhook::claim_size(1.0);
+    assert!(batch as f64 * 1.0 < lease);
+    let (batch, lease) = webhook::claim_size(30.0);
+    assert_eq!(batch, 2);
+    assert!(batch as f64 * 30.0 < lease);
+}
+
+#[tokio::test]
+async fn webhook_private_url_err() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    for url in [
+        "http://10.0.0.1/hook",
+        "http://192.168.1.1/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+        "http://[::ffff:127.0.0.1]/hook",
+        "http://localhost/hook",
+        "ftp://example.com/hook",
+    ] {
+        let response = server
+            .post((URL.to_string() + "/server/new_webhook").as_str())
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .json(&json!({"event": "UserNew", "url": url}))
+            .await;
+        assert_eq!(response.status_code(), 400, "{}", url);
+    }
+
+    // test mode allows the local stub explicitly
+    let (url, _) = new_webhook_stub().await;
+    let response = server
+        .post((URL.to_string() + "/server/new_webhook").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({"event": "UserNew", "url": url + "/ok"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn get_users_rich_query_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+
+    let user1 = user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: Some("Ivan".to_string()),
+            patronym: None,
+            surname: Some("Petrov".to_string()),
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    let user2 = user::new(
+        &Reg {
+            username: "world".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::new(
+        &Reg {
+            username: "bar".to_string(),
+            password: "1234".to_string(),
+            firstname: Some("Boris".to_string()),
+            patronym: None,
+            surname: Some("Sidorov".to_string()),
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::new(
+        &Reg {
+            username: "baz".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+    user::del(&Query::from([("username".to_string(), json!("baz"))]), con)
+        .unwrap();
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/get_users").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({
+            "sq": {
+                "status": "active",
+                "$or": [
+                    {"firstname": {"$ilike": "iv%"}},
+                    {"surname": {"$exists": false}}
+                ],
+                "created": {"$ne": 0.0}
+            }
+        }))
+        .await;
+
+    assert!(response.status_code() == 200, "{}", response.text());
+    let users = response.json::<GetUsersRes<User>>().users;
+    assert!(users.len() == 2);
+    assert!(users[0] == user1);
+    assert!(users[1] == user2);
+
+    let response = server
+        .post((URL.to_string() + "/server/get_users").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({
+            "sq": {
+                "id": {"$nin": [1, 2]},
+                "username": {"$like": "ba%"}
+            }
+        }))
+        .await;
+
+    assert!(response.status_code() == 200, "{}", response.text());
+    let users = response.json::<GetUsersRes<User>>().users;
+    assert!(users.len() == 1);
+    assert!(users[0].username == "bar");
+}
+
+#[tokio::test]
+async fn get_users_invalid_query_err() {
+    let server = new_test_server();
+    for sq in [
+        json!({"id": "1"}),
+        json!({"rt": "hello"}),
+        json!({"id": {"$like": "1%"}}),
+        json!({"username": {"$exists": true}}),
+        json!({"status": "deleted"}),
+        json!({"$or": {"id": 1}}),
+        json!({"status": {"$gt": "active"}}),
+        json!({"id": {"$regex": "1"}}),
+    ] {
+        let response = server
+            .post((URL.to_string() + "/server/get_users").as_str())
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .json(&json!({ "sq": sq }))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let err: Value = response.json();
+        assert_eq!(err["code"], "query_err", "{}", sq);
+    }
+}
+
+#[tokio::test]
+async fn get_users_page_ok() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    for username in ["a", "b", "c", "d", "e"] {
+        user::new(
+            &Reg {
+                username: username.to_string(),
+                password: "1234".to_string(),
+                firstname: None,
+                patronym: None,
+                surname: None,
+                email: None,
+                phone: None,
+                metadata: Default::default(),
+            },
+            con,
+        )
+        .unwrap();
+    }
+
+    let server = new_test_server();
+    let mut usernames = vec![];
+    let mut after: Option<Vec<Value>> = None;
+    loop {
+        let response = server
+            .post((URL.to_string() + "/server/get_users").as_str())
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .json(&GetUsers {
+                sq: Query::new(),
+                sort: vec!["-username".to_string()],
+                limit: Some(2),
+                after: after.clone(),
+                count: true,
+                ..Default::default()
+            })
+            .await;
+        assert!(response.status_code() == 200, "{}", response.text());
+        let res: GetUsersRes<User> = response.json();
+        assert_eq!(res.total, Some(5));
+        usernames.extend(res.users.into_iter().map(|x| x.username));
+        if res.next.is_none() {
+            break;
+        }
+        after = res.next;
+    }
+    assert_eq!(usernames, vec!["e", "d", "c", "b", "a"]);
+
+    let response = server
+        .post((URL.to_string() + "/server/get_users").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .json(&json!({
+            "sq": {},
+            "offset": 3,
+            "fields": ["id", "username"]
+        }))
+        .await;
+    assert!(response.status_code() == 200, "{}", response.text());
+    let res: GetUsersRes<Value> = response.json();
+    assert_eq!(res.total, None);
+    assert_eq!(res.next, None);
+    assert_eq!(
+        res.users,
+        vec![
+            json!({"id": 4, "username": "d"}),
+            json!({"id": 5, "username": "e"})
+        ]
+    );
+}
+
+#[tokio::test]
+async fn get_users_invalid_page_err() {
+    let server = new_test_server();
+    for inp in [
+        json!({"sq": {}, "sort": ["rt"]}),
+        json!({"sq": {}, "sort": ["firstname"], "after": ["a", 1]}),
+        json!({"sq": {}, "after": [1, 2]}),
+        json!({"sq": {}, "fields": ["hpassword"]}),
+    ] {
+        let response = server
+            .post((URL.to_string() + "/server/get_users").as_str())
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .json(&inp)
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let err: Value = response.json();
+        assert_eq!(err["code"], "query_err", "{}", inp);
+    }
+}
+
+static ARGON2_1234: &str = "$argon2id$v=19$m=19456,t=2,p=1$3fO4A4sf5Fu3H2UF04mTwg$UhxzE5Zbo/tncY76akSj+e/LVdI1lFUkxJIKkwYDc2M";
+
+#[tokio::test]
+async fn import_users_std_ok() {
+    truncate_tables_if_allowed();
+    let test_start_time = utc();
+
+    let server = new_test_server();
+    let data = [
+        json!({"username": "hello", "password": "1234"}),
+        json!({"username": "world", "hpassword": ARGON2_1234,
+               "firstname": "Ivan"}),
+    ]
+    .map(|x| x.to_string())
+    .join("\n");
+    let response = server
+        .post((URL.to_string() + "/server/import_users").as_str())
+        .json(&json!({"format": "Jsonl", "data": data}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let res: ImportUsersRes = response.json();
+    assert!(res.errs.is_empty());
+    assert!(res.ids.len() == 2);
+
+    let data = "username,password,hpassword,firstname,patronym,surname\n\
+                foo,1234,,,,Ivanov\n\
+                bar,,\"$pbkdf2-sha256$i=1000,l=32$c2FsdHNhbHRzYWx0c2FsdA\
+                $Yo7f0NJD76SOoRMf9wsRfo7jhsRsIhoioGLVJlCb2Gs\",,,\n";
+    let response = server
+        .post((URL.to_string() + "/server/import_users").as_str())
+        .json(&json!({"format": "Csv", "data": data}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let res: ImportUsersRes = response.json();
+    assert!(res.errs.is_empty(), "{:?}", res.errs);
+    assert!(res.ids.len() == 2);
+    let con = &mut db::con().unwrap();
+    let foo = user::get_by_id(res.ids[0], con).unwrap();
+    assert!(foo.username == "foo");
+    assert!(foo.firstname.is_none());
+    assert!(foo.surname == Some("Ivanov".to_string()));
+
+    for (username, password) in
+        [("hello", "1234"), ("world", "1234"), ("foo", "1234")]
+    {
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": username, "password": password}))
+            .await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    let changes = get_user_changes(
+        &GetUserChanges {
+            from: test_start_time,
+            actions: None,
+            user_ids: None,
+            with_data: true,
+            search: Default::default(),
+        },
+        con,
+    );
+    assert!(changes.len() == 4);
+    assert!(changes.iter().all(|x| x.action == ChangeAction::New));
+    let world: User =
+        serde_json::from_value(changes[1].data.clone().unwrap()).unwrap();
+    assert!(world.firstname == Some("Ivan".to_string()));
+}
+
+#[tokio::test]
+async fn import_users_invalid_rows_err() {
+    truncate_tables_if_allowed();
+    let con = &mut db::con().unwrap();
+    user::new(
+        &Reg {
+            username: "hello".to_string(),
+            password: "1234".to_string(),
+            firstname: None,
+            patronym: None,
+            surname: None,
+            email: None,
+            phone: None,
+            metadata: Default::default(),
+        },
+        con,
+    )
+    .unwrap();
+
+    let server = new_test_server();
+    let data = [
+        json!({"username": "world", "password": "1234"}).to_string(),
+        json!({"username": "hello", "password": "1234"}).to_string(),
+        "".to_string(),
+        json!({"username": "world", "password": "1234"}).to_string(),
+        json!({"username": "foo", "hpassword": "md5$1234"}).to_string(),
+        json!({"username": "bar"}).to_string(),
+        "{\"username\": ".to_string(),
+        json!({"username": "baz", "password": "abc"}).to_string(),
+    ]
+    .join("\n");
+    let response = server
+        .post((URL.to_string() + "/server/import_users").as_str())
+        .json(&json!({"format": "Jsonl", "data": data}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let res: ImportUsersRes = response.json();
+    assert!(res.ids.is_empty());
+    let errs: Vec<(usize, &str)> =
+        res.errs.iter().map(|x| (x.row, x.code.as_str())).collect();
+    assert_eq!(
+        errs,
+        vec![
+            (2, "username_taken"),
+            (4, "username_taken"),
+            (5, "invalid_hpassword"),
+            (6, "invalid_password"),
+            (7, "parse_err"),
+            (8, "password_policy"),
+        ]
+    );
+
+    let response = server
+        .post((URL.to_string() + "/server/import_users").as_str())
+        .json(&json!({
+            "format": "Csv",
+            "data": "username,password\nfoo,1234\nbar,1234,extra\n"
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let res: ImportUsersRes = response.json();
+    assert!(res.ids.is_empty());
+    assert_eq!(
+        res.errs,
+        vec![ImportRowErr {
+            row: 3,
+            code: "parse_err".to_string(),
+            msg: res.errs[0].msg.clone(),
+        }]
+    );
+
+    assert!(user::get_many_as_ids(con).unwrap().len() == 1);
+}
+
+#[tokio::test]
+async fn reg_password_policy_err() {
+    truncate_tables_if_allowed();
+
+    let server = new_test_server();
+    for (password, rules) in [
+        ("hello", vec!["require_digit", "forbid_username"]),
+        ("12", vec!["min_len"]),
+        ("QWERTY123", vec!["breached"]),
+        (&"1".repeat(65), vec!["max_len"]),
+    ] {
+        let response = server
+            .post((URL.to_string() + "/server/reg").as_str())
+            .json(&json!({"username": "hello", "password": password}))
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let e: Value = response.json();
+        assert_eq!(e["code"], "password_policy");
+        let violated: Vec<&str> = e["data"]["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["rule"].as_str().unwrap())
+            .collect();
+        assert_eq!(violated, rules);
+    }
+
+    let con = &mut db::con().unwrap();
+    assert!(user::get_many_as_ids(con).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn reg_username_normalized_ok() {
+    truncate_tables_if_allowed();
+
+    let server = new_test_server();
+    let reg = |username: &str| {
+        server
+            .post((URL.to_string() + "/server/reg").as_str())
+            .json(&json!({"username": username, "password": "1234"}))
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+
+    // fullwidth letters are normalized
+    let response = reg("Ａlice").await;
+    assert_eq!(response.status_code(), 200);
+    let alice: User = response.json();
+    assert_eq!(alice.username, "Alice");
+
+    for username in ["alice", "ALICE", "ａｌｉｃｅ"] {
+        let response = reg(username).await;
+        assert_eq!(response.status_code(), 400, "{}", username);
+    }
+    // cyrillic "а" and greek "ο" within latin names
+    for username in [
+        "bad name",
+        "bad:name",
+        "Admin",
+        &"a".repeat(65),
+        "",
+        "\u{430}lice",
+        "b\u{3bf}b",
+    ] {
+        let response = reg(username).await;
+        assert_eq!(response.status_code(), 400, "{}", username);
+        let e: Value = response.json();
+        assert_eq!(e["code"], "invalid_username", "{}", username);
+    }
+
+    // single scripts and latin with the scripts of east asian writing
+    for username in [
+        "\u{430}\u{43b}\u{438}\u{441}\u{430}",
+        "taro\u{7530}\u{4e2d}\u{305f}",
+    ] {
+        let response = reg(username).await;
+        assert_eq!(response.status_code(), 200, "{}", username);
+    }
+
+    let response = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "aLiCe", "password": "1234"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    // archived users don't hold their usernames
+    let response = server
+        .post((URL.to_string() + "/server/dereg").as_str())
+        .json(&json!({"username": "ALICE"}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = reg("alice").await;
+    assert_eq!(response.status_code(), 200);
+    let user: User = response.json();
+    assert!(user.id != alice.id);
+}
+
+#[tokio::test]
+async fn verification_domain_ok() {
+    truncate_tables_if_allowed();
+    verification::set_sender(None);
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({
+            "username": "hello",
+            "password": "1234",
+            "email": " Hello@Example.com",
+            "phone": "+7 (900) 123-45-67"
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let user: User = response.json();
+    assert_eq!(user.email, Some("hello@example.com".to_string()));
+    assert_eq!(user.phone, Some("+79001234567".to_string()));
+    assert!(user.email_verified_at.is_none());
+    assert!(user.phone_verified_at.is_none());
+
+    let login = |login: &str| {
+        server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": login, "password": "1234"}))
+    };
+    assert_eq!(login("hello@example.com").await.status_code(), 400);
+    assert_eq!(login("+79001234567").await.status_code(), 400);
+
+    for (kind, identifier) in [
+        ("Email", "HELLO@example.com"),
+        ("Phone", "+7 900 123 45 67"),
+    ] {
+        let response = server
+            .post((URL.to_string() + "/server/new_verification").as_str())
+            .json(&json!({"user_id": user.id, "kind": kind}))
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let v: Verification = response.json();
+        let code = v.code.unwrap();
+
+        let response = server
+            .post((URL.to_string() + "/verify").as_str())
+            .json(&json!({"id": v.id, "code": "wrong"}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let response = server
+            .post((URL.to_string() + "/verify").as_str())
+            .json(&json!({"id": v.id, "code": code}))
+            .await;
+        assert_eq!(response.status_code(), 200);
+
+        assert_eq!(login(identifier).await.status_code(), 200, "{}", kind);
+    }
+
+    let con = &mut db::con().unwrap();
+    let user = user::get_by_id(user.id, con).unwrap();
+    assert!(user.email_verified_at.is_some());
+    assert!(user.phone_verified_at.is_some());
+
+    // a verified identifier cannot be verified by another user
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({
+            "username": "world",
+            "password": "1234",
+            "email": "hello@example.com"
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let world: User = response.json();
+    let response = server
+        .post((URL.to_string() + "/server/new_verification").as_str())
+        .json(&json!({"user_id": world.id, "kind": "Email"}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let v: Verification = response.json();
+    let response = server
+        .post((URL.to_string() + "/verify").as_str())
+        .json(&json!({"id": v.id, "code": v.code.unwrap()}))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "identifier_taken");
+
+    for (email, phone) in [("hello", "+79001234567"), ("a@b.c", "79001234567")]
+    {
+        let response = server
+            .post((URL.to_string() + "/server/reg").as_str())
+            .json(&json!({
+                "username": "foo",
+                "password": "1234",
+                "email": email,
+                "phone": phone
+            }))
+            .add_header("domain_secret", DOMAIN_SECRET)
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+}
+
+#[tokio::test]
+async fn mail_verification_file_ok() {
+    truncate_tables_if_allowed();
+    verification::set_sender(Some(Arc::new(MailSender)));
+
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({
+            "username": "hello",
+            "password": "1234",
+            "email": "hello@example.com"
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let user: User = response.json();
+    let response = server
+        .post((URL.to_string() + "/server/new_verification").as_str())
+        .json(&json!({"user_id": user.id, "kind": "Email"}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let v: Verification = response.json();
+    verification::set_sender(None);
+
+    let file = "target/test_mail_verification_file_ok.jsonl";
+    let _ = std::fs::remove_file(file);
+    let con = &mut db::con().unwrap();
+    let sink = Sink::File(file.to_string());
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 1);
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 0);
+
+    let content = std::fs::read_to_string(file).unwrap();
+    let mails: Vec<Value> = content
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0]["to"], "hello@example.com");
+    assert_eq!(mails[0]["from"], "corund@localhost");
+    assert_eq!(mails[0]["subject"], "Verify your email");
+    assert!(mails[0]["body"]
+        .as_str()
+        .unwrap()
+        .contains(&v.code.unwrap()));
+    let mail = mailer::get(mails[0]["id"].as_i64().unwrap() as i32, con);
+    assert!(mail.unwrap().status == DeliveryStatus::Done);
+}
+
+#[tokio::test]
+async fn mail_smtp_ok() {
+    truncate_tables_if_allowed();
+    let (port, received) = new_smtp_stub().await;
+
+    let con = &mut db::con().unwrap();
+    let vars = HashMap::from([
+        ("id", "1".to_string()),
+        ("code", "123456".to_string()),
+        ("target", "hello@example.com".to_string()),
+    ]);
+    let en =
+        mailer::enqueue("verification", "hello@example.com", None, &vars, con)
+            .unwrap();
+    let ru = mailer::enqueue(
+        "verification",
+        "world@example.com",
+        Some("ru"),
+        &vars,
+        con,
+    )
+    .unwrap();
+    // missing locales fall back to the default one
+    let fallback = mailer::enqueue(
+        "verification",
+        "foo@example.com",
+        Some("de"),
+        &vars,
+        con,
+    )
+    .unwrap();
+    assert_eq!(mailer::get(ru, con).unwrap().subject, "Подтвердите почту");
+    assert_eq!(
+        mailer::get(fallback, con).unwrap().subject,
+        "Verify your email"
+    );
+    let e = mailer::enqueue("nothing", "foo@example.com", None, &vars, con)
+        .unwrap_err();
+    assert_eq!(serde_json::to_value(e).unwrap()["code"], "no_template");
+
+    let sink = Sink::Smtp {
+        host: "127.0.0.1".to_string(),
+        port,
+        credentials: None,
+        tls: false,
+    };
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 3);
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 3);
+    assert!(received[0].contains("To: hello@example.com"));
+    assert!(received[0].contains("Subject: Verify your email"));
+    assert!(received[0].contains("123456"));
+    assert!(mailer::get(en, con).unwrap().status == DeliveryStatus::Done);
+}
+
+#[tokio::test]
+async fn mail_smtp_dead_err() {
+    truncate_tables_if_allowed();
+    // nothing listens on the port once the listener is dropped
+    let port = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let con = &mut db::con().unwrap();
+    let vars = HashMap::from([
+        ("code", "123456".to_string()),
+        ("target", "hello@example.com".to_string()),
+    ]);
+    let id =
+        mailer::enqueue("verification", "hello@example.com", None, &vars, con)
+            .unwrap();
+    let sink = Sink::Smtp {
+        host: "127.0.0.1".to_string(),
+        port,
+        credentials: None,
+        tls: false,
+    };
+    // test mode allows 2 attempts without backoff
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 1);
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 1);
+    assert_eq!(mailer::deliver_due(&sink, con).await.unwrap(), 0);
+    let mail = mailer::get(id, con).unwrap();
+    assert!(mail.status == DeliveryStatus::Dead);
+    assert_eq!(mail.attempts, 2);
+    assert!(mail.err.is_some());
+}
+
+#[tokio::test]
+async fn mfa_require_reset_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let user: User = response.json();
+    let get_mfa = || {
+        server
+            .post((URL.to_string() + "/server/get_mfa").as_str())
+            .json(&json!({"user_id": user.id}))
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let login = || async {
+        let response = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": "hello", "password": "1234"}))
+            .await;
+        assert_eq!(response.status_code(), 400);
+        let e: Value = response.json();
+        assert_eq!(e["code"], "mfa_required");
+        e["data"].clone()
+    };
+
+    let response = server
+        .post((URL.to_string() + "/server/require_mfa").as_str())
+        .json(&json!({"user_id": user.id, "required": true}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    // the user must enroll before getting a refresh token
+    let data = login().await;
+    assert_eq!(data["enroll"], true);
+    let challenge = data["challenge"].as_str().unwrap();
+    let response = server
+        .post((URL.to_string() + "/new_totp").as_str())
+        .json(&json!({"challenge": challenge}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let enrollment: TotpEnrollment = response.json();
+    let code =
+        mfa::get_totp_code(&enrollment.secret, mfa::get_step(utc())).unwrap();
+    let response = server
+        .post((URL.to_string() + "/confirm_totp").as_str())
+        .json(&json!({"challenge": challenge, "code": code}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = server
+        .post((URL.to_string() + "/login/mfa").as_str())
+        .json(&json!({"challenge": challenge, "code": code}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let status: MfaStatus = get_mfa().await.json();
+    assert_eq!(
+        status,
+        MfaStatus {
+            user_id: user.id,
+            required: true,
+            enabled: true,
+            totp: true,
+            passkeys: 0,
+            recovery_codes_left: 10
+        }
+    );
+
+    let response = server
+        .post((URL.to_string() + "/server/reset_mfa").as_str())
+        .json(&json!({"user_id": user.id}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let status: MfaStatus = get_mfa().await.json();
+    assert!(status.required);
+    assert!(!status.enabled);
+    assert_eq!(status.recovery_codes_left, 0);
+    assert_eq!(login().await["enroll"], true);
+
+    let response = server
+        .post((URL.to_string() + "/server/require_mfa").as_str())
+        .json(&json!({"user_id": user.id, "required": false}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn oidc_code_flow_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({
+            "username": "hello",
+            "password": "1234",
+            "firstname": "Ivan",
+            "email": "hello@example.com"
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let user: User = response.json();
+    let response = server
+        .post((URL.to_string() + "/server/new_oauth_client").as_str())
+        .json(&json!({
+            "name": "Grafana",
+            "redirect_uris": ["https://grafana.example.com/login"]
+        }))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let client: OauthClient = response.json();
+    let secret = client.secret.clone().unwrap();
+    // secrets are stored hashed, so they're only returned on creation
+    let clients: Vec<OauthClient> = server
+        .post((URL.to_string() + "/server/get_oauth_clients").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await
+        .json();
+    assert_eq!(clients[0].client_id, client.client_id);
+    assert!(clients[0].secret.is_none());
+
+    let response = server.get("/.well-known/openid-configuration").await;
+    let discovery: Value = response.json();
+    assert_eq!(discovery["issuer"], "http://localhost:3000");
+    assert_eq!(
+        discovery["token_endpoint"],
+        "http://localhost:3000/oauth/token"
+    );
+
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    let params = [
+        ("response_type", "code"),
+        ("client_id", client.client_id.as_str()),
+        ("redirect_uri", "https://grafana.example.com/login"),
+        ("scope", "openid profile email"),
+        ("state", "xyz"),
+        ("nonce", "n-0S6"),
+        ("code_challenge", challenge),
+        ("code_challenge_method", "S256"),
+    ];
+    let response = server
+        .get("/oauth/authorize")
+        .add_query_params(params)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("Sign in to Grafana"));
+
+    // unregistered redirects are never followed
+    let mut bad = params;
+    bad[2] = ("redirect_uri", "https://evil.com");
+    let response = server.get("/oauth/authorize").add_query_params(bad).await;
+    assert_eq!(response.status_code(), 400);
+    assert!(response.maybe_header("location").is_none());
+
+    let authorize = |password: &'static str| {
+        let mut form: Vec<(&str, &str)> = params.to_vec();
+        form.push(("username", "hello"));
+        form.push(("password", password));
+        server.post("/oauth/authorize").form(&form)
+    };
+    let response = authorize("wrong").await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("Incorrect username or password"));
+    let response = authorize("1234").await;
+    assert_eq!(response.status_code(), 303);
+    let location = response.header("location").to_str().unwrap().to_string();
+    assert!(location.starts_with("https://grafana.example.com/login?code="));
+    assert!(location.ends_with("&state=xyz"));
+    let con = &mut db::con().unwrap();
+    let events = auth_event::get_many(
+        &Search {
+            sq: Query::from([("kind".to_string(), json!("login"))]),
+            ..Default::default()
+        },
+        con,
+    )
+    .unwrap()
+    .events;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["outcome"], "Failure");
+    assert_eq!(events[1]["outcome"], "Success");
+    assert_eq!(events[1]["user_id"], user.id);
+    assert_eq!(events[1]["domain"], client.client_id);
+    let code = location
+        .split_once("code=")
+        .unwrap()
+        .1
+        .split_once('&')
+        .unwrap()
+        .0
+        .to_string();
+
+    let basic = format!(
+        "Basic {}",
+        data_encoding::BASE64
+            .encode(format!("{}:{}", client.client_id, secret).as_bytes())
+    );
+    let exchange = |verifier: &'static str| {
+        server
+            .post("/oauth/token")
+            .add_header("authorization", basic.as_str())
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code.as_str()),
+                ("redirect_uri", "https://grafana.example.com/login"),
+                ("code_verifier", verifier),
+            ])
+    };
+    let response = exchange(verifier).await;
+    assert_eq!(response.status_code(), 200);
+    let tokens: Value = response.json();
+    assert_eq!(tokens["token_type"], "Bearer");
+    // ID tokens are verified with the published key
+    let jwks: Value = server.get("/oauth/jwks").await.json();
+    let jwk = &jwks["keys"][0];
+    assert_eq!(jwk["alg"], "ES256");
+    let coord = |name: &str| {
+        BASE64URL_NOPAD
+            .decode(jwk[name].as_str().unwrap().as_bytes())
+            .unwrap()
+    };
+    let key = VerifyingKey::from_sec1_bytes(
+        &[vec![4], coord("x"), coord("y")].concat(),
+    )
+    .unwrap();
+    let (input, signature) = tokens["id_token"]
+        .as_str()
+        .unwrap()
+        .rsplit_once('.')
+        .unwrap();
+    let signature = Signature::from_slice(
+        &BASE64URL_NOPAD.decode(signature.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    key.verify(input.as_bytes(), &signature).unwrap();
+    let payload = input.split_once('.').unwrap().1;
+    let id_token: IdTokenPayload = serde_json::from_slice(
+        &BASE64URL_NOPAD.decode(payload.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(id_token.iss, "http://localhost:3000");
+    assert_eq!(id_token.sub, user.id.to_string());
+    assert_eq!(id_token.aud, client.client_id);
+    assert_eq!(id_token.nonce, Some("n-0S6".to_string()));
+    assert_eq!(id_token.claims["preferred_username"], "hello");
+    assert_eq!(id_token.claims["email_verified"], false);
+
+    // codes are single-use
+    let response = exchange(verifier).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["error"], "invalid_grant");
+
+    let at = tokens["access_token"].as_str().unwrap();
+    // first-party services don't accept tokens of third-party clients
+    assert!(token::verify_at(at, 300.0, false).is_err());
+    let response = server
+        .get("/oauth/userinfo")
+        .add_header("authorization", format!("Bearer {}", at).as_str())
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let info: Value = response.json();
+    assert_eq!(info["sub"], user.id.to_string());
+    assert_eq!(info["given_name"], "Ivan");
+    assert_eq!(info["email"], "hello@example.com");
+    assert!(info.get("phone_number").is_none());
+    let response = server
+        .get("/oauth/userinfo")
+        .add_header("authorization", "Bearer nope")
+        .await;
+    assert_eq!(response.status_code(), 401);
+    let forged = token::new_token(
+        &token::OauthTokenPayload {
+            user_id: user.id,
+            created: utc(),
+            client_id: client.client_id.to_owned(),
+            scope: "openid email phone".to_string(),
+        },
+        b"oauthgrant",
+    )
+    .unwrap();
+    let response = server
+        .get("/oauth/userinfo")
+        .add_header("authorization", format!("Bearer {}", forged).as_str())
+        .await;
+    assert_eq!(response.status_code(), 401);
+
+    // a wrong verifier burns the code
+    let location = authorize("1234")
+        .await
+        .header("location")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let code = location
+        .split_once("code=")
+        .unwrap()
+        .1
+        .split_once('&')
+        .unwrap()
+        .0;
+    let response = server
+        .post("/oauth/token")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", "https://grafana.example.com/login"),
+            ("code_verifier", "wrong"),
+            ("client_id", client.client_id.as_str()),
+            ("client_secret", secret.as_str()),
+        ])
+        .await;
+    assert_eq!(response.status_code(), 400);
+}
+
+#[tokio::test]
+async fn service_token_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let response = server
+        .post((URL.to_string() + "/server/new_oauth_client").as_str())
+        .json(&json!({"name": "billing", "scopes": ["users"]}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let client: OauthClient = response.json();
+    assert_eq!(client.scopes, vec!["users"]);
+    let secret = client.secret.clone().unwrap();
+
+    let grant = |scope: Option<&'static str>| {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", client.client_id.as_str()),
+            ("client_secret", secret.as_str()),
+        ];
+        if let Some(scope) = scope {
+            form.push(("scope", scope));
+        }
+        server.post("/oauth/token").form(&form)
+    };
+    let response = grant(Some("users mfa")).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["error"], "invalid_scope");
+    let response = grant(None).await;
+    assert_eq!(response.status_code(), 200);
+    let tokens: Value = response.json();
+    assert_eq!(tokens["scope"], "users");
+    assert_eq!(tokens["expires_in"], 300);
+    let bearer =
+        format!("Bearer {}", tokens["access_token"].as_str().unwrap());
+
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .add_header("authorization", bearer.as_str())
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = server
+        .post((URL.to_string() + "/server/get_webhooks").as_str())
+        .add_header("authorization", bearer.as_str())
+        .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "insufficient_scope");
+    // services cannot manage clients
+    let response = server
+        .post((URL.to_string() + "/server/new_oauth_client").as_str())
+        .json(&json!({"name": "evil", "scopes": ["webhooks"]}))
+        .add_header("authorization", bearer.as_str())
+        .await;
+    assert_ne!(response.status_code(), 200);
+
+    // user access tokens are not service tokens
+    let rt = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .await
+        .text();
+    let at = server
+        .post((URL.to_string() + "/access").as_str())
+        .json(&json!({"rt": rt}))
+        .await
+        .text();
+    let response = server
+        .post((URL.to_string() + "/server/get_users").as_str())
+        .json(&json!({"sq": {}}))
+        .add_header("authorization", format!("Bearer {}", at).as_str())
+        .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "invalid_token");
+
+    // tokens signed without the configured secret are forged
+    let forged = token::new_token(
+        &token::ServiceTokenPayload {
+            client_id: client.client_id.to_owned(),
+            created: utc(),
+            scope: "users webhooks impersonate".to_string(),
+        },
+        b"backendsvc",
+    )
+    .unwrap();
+    let response = server
+        .post((URL.to_string() + "/server/get_webhooks").as_str())
+        .add_header("authorization", format!("Bearer {}", forged).as_str())
+        .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "invalid_token");
+
+    // tokens of deleted clients are rejected before they expire
+    let response = server
+        .post((URL.to_string() + "/server/del_oauth_client").as_str())
+        .json(&json!({"id": client.id}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let response = server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({"username": "world", "password": "1234"}))
+        .add_header("authorization", bearer.as_str())
+        .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "invalid_token");
+}
+
+#[tokio::test]
+async fn upstream_login_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let claims = new_idp_stub().await;
+
+    let response = server
+        .post((URL.to_string() + "/upstream/begin_login").as_str())
+        .json(&json!({"provider": "nope"}))
+        .await;
+    let e: Value = response.json();
+    assert_eq!(e["code"], "no_upstream");
+
+    // begins a login and lets the provider sign the subject in
+    let begin = |sub: &'static str, username: &'static str| {
+        let server = &server;
+        let claims = claims.clone();
+        async move {
+            let redirect: UpstreamRedirect = server
+                .post((URL.to_string() + "/upstream/begin_login").as_str())
+                .json(&json!({"provider": "corp"}))
+                .await
+                .json();
+            assert!(redirect
+                .url
+                .starts_with("http://127.0.0.1:9016/authorize?"));
+            let param = |name: &str| {
+                redirect
+                    .url
+                    .split(['?', '&'])
+                    .find_map(|x| x.strip_prefix(&(name.to_string() + "=")))
+                    .unwrap()
+                    .to_string()
+            };
+            *claims.lock().unwrap() = json!({
+                "iss": "http://127.0.0.1:9016",
+                "sub": sub,
+                "aud": "corund",
+                "exp": utc() + 60.0,
+                "nonce": param("nonce"),
+                "preferred_username": username,
+                "email": username.to_string() + "@corp.com",
+                "given_name": "Alice"
+            });
+            param("state")
+        }
+    };
+    let login = |state: String| {
+        server
+            .post((URL.to_string() + "/upstream/login").as_str())
+            .json(&json!({"state": state, "code": "c0de"}))
+    };
+    let current = |rt: String| {
+        server
+            .post((URL.to_string() + "/current").as_str())
+            .json(&json!({"rt": rt}))
+    };
+
+    let state = begin("42", "alice").await;
+    let response = login(state.clone()).await;
+    assert_eq!(response.status_code(), 200);
+    let alice: User = current(response.text()).await.json();
+    assert_eq!(alice.username, "alice");
+    assert_eq!(alice.firstname, Some("Alice".to_string()));
+    // states are single-use
+    let e: Value = login(state).await.json();
+    assert_eq!(e["code"], "invalid_state");
+    let changes = get_user_changes(
+        &GetUserChanges {
+            from: 0.0,
+            actions: None,
+            user_ids: None,
+            with_data: false,
+            search: Default::default(),
+        },
+        &mut db::con().unwrap(),
+    );
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].action, ChangeAction::New);
+
+    // the same subject signs into the same user
+    let rt = login(begin("42", "alice").await).await.text();
+    let user: User = current(rt).await.json();
+    assert_eq!(user.id, alice.id);
+
+    // taken usernames are replaced
+    let rt = login(begin("43", "alice").await).await.text();
+    let user: User = current(rt).await.json();
+    assert_ne!(user.id, alice.id);
+    assert!(user.username.starts_with("user"));
+
+    let state = begin("44", "eve").await;
+    claims.lock().unwrap()["nonce"] = json!("replayed");
+    let e: Value = login(state).await.json();
+    assert_eq!(e["code"], "upstream_err");
+
+    // existing users link their accounts
+    server
+        .post((URL.to_string() + "/server/reg").as_str())
+        .json(&json!({"username": "bob", "password": "1234"}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await;
+    let rt = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "bob", "password": "1234"}))
+        .await
+        .text();
+    let state = begin("77", "robert").await;
+    let response = server
+        .post((URL.to_string() + "/upstream/link").as_str())
+        .json(&json!({"rt": rt, "state": state, "code": "c0de"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let identity: Identity = response.json();
+    assert_eq!(identity.provider, "corp");
+    assert_eq!(identity.subject, "77");
+    let rt = login(begin("77", "robert").await).await.text();
+    let user: User = current(rt).await.json();
+    assert_eq!(user.username, "bob");
+
+    let identities: Vec<Identity> = server
+        .post((URL.to_string() + "/server/get_identities").as_str())
+        .json(&json!({"user_id": user.id}))
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .await
+        .json();
+    assert_eq!(identities.len(), 1);
+}
+
+#[tokio::test]
+async fn roles_access_token_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let rpc = |name: &str, body: Value| {
+        server
+            .post((URL.to_string() + "/server/" + name).as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let user: User =
+        rpc("reg", json!({"username": "hello", "password": "1234"}))
+            .await
+            .json();
+    let role: Role = rpc(
+        "set_role",
+        json!({"name": "editor", "permissions": ["post.write", "post.read"]}),
+    )
+    .await
+    .json();
+    assert_eq!(role.permissions, vec!["post.read", "post.write"]);
+    rpc(
+        "set_role",
+        json!({"name": "viewer", "permissions": ["post.read"]}),
+    )
+    .await;
+    rpc("assign_role", json!({"user_id": user.id, "role": "viewer"})).await;
+    rpc(
+        "assign_role",
+        json!({"user_id": user.id, "role": "editor", "domain": "blog"}),
+    )
+    .await;
+    let e: Value =
+        rpc("assign_role", json!({"user_id": user.id, "role": "admin"}))
+            .await
+            .json();
+    assert_eq!(e["code"], "no_role");
+    let roles: Vec<UserRole> =
+        rpc("get_user_roles", json!({"user_id": user.id}))
+            .await
+            .json();
+    assert_eq!(
+        roles,
+        vec![
+            UserRole {
+                role: "editor".to_string(),
+                domain: Some("blog".to_string())
+            },
+            UserRole {
+                role: "viewer".to_string(),
+                domain: None
+            }
+        ]
+    );
+
+    let rt = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "hello", "password": "1234"}))
+        .await
+        .text();
+    let access = |domain: Option<&str>| {
+        server
+            .post((URL.to_string() + "/access").as_str())
+            .json(&json!({"rt": rt, "domain": domain}))
+    };
+    let verify = |at: String| {
+        token::verify_token::<token::UserTokenPayload>(&at, b"helloworld")
+            .unwrap()
+    };
+    let claims = verify(access(None).await.text());
+    assert_eq!(claims.domain, None);
+    assert_eq!(claims.roles, vec!["viewer"]);
+    assert_eq!(claims.permissions, vec!["post.read"]);
+    let claims = verify(access(Some("blog")).await.text());
+    assert_eq!(claims.domain, Some("blog".to_string()));
+    assert_eq!(claims.roles, vec!["editor", "viewer"]);
+    assert_eq!(claims.permissions, vec!["post.read", "post.write"]);
+
+    rpc(
+        "revoke_role",
+        json!({"user_id": user.id, "role": "editor", "domain": "blog"}),
+    )
+    .await;
+    let claims = verify(access(Some("blog")).await.text());
+    assert_eq!(claims.roles, vec!["viewer"]);
+    rpc("del_role", json!({"name": "viewer"})).await;
+    let claims = verify(access(None).await.text());
+    assert!(claims.roles.is_empty());
+
+    let changes = rpc(
+        "get_user_changes",
+        json!({"from": 0, "actions": ["Roles"], "with_data": true}),
+    )
+    .await
+    .json::<Vec<UserChange>>();
+    assert_eq!(changes.len(), 4);
+    assert_eq!(changes[3].data, Some(json!({"roles": []})));
+}
+
+#[tokio::test]
+async fn org_membership_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let rpc = |name: &str, body: Value| {
+        server
+            .post((URL.to_string() + "/server/" + name).as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let mut ids = vec![];
+    for username in ["alice", "bob", "carol", "dave"] {
+        let user: User =
+            rpc("reg", json!({"username": username, "password": "1234"}))
+                .await
+                .json();
+        ids.push(user.id);
+    }
+    let (alice, bob, carol, dave) = (ids[0], ids[1], ids[2], ids[3]);
+    let org: Org = rpc("new_org", json!({"name": "Acme", "owner_id": alice}))
+        .await
+        .json();
+    rpc(
+        "set_member",
+        json!({"org_id": org.id, "user_id": bob, "role": "Member"}),
+    )
+    .await;
+    let membership: Membership = rpc(
+        "set_member",
+        json!({"org_id": org.id, "user_id": carol, "role": "Admin"}),
+    )
+    .await
+    .json();
+    assert_eq!(membership.role, MemberRole::Admin);
+    let members: Vec<Membership> =
+        rpc("get_members", json!({"org_id": org.id})).await.json();
+    assert_eq!(members.len(), 3);
+
+    let find = |sq: Value| async move {
+        let res: GetUsersRes<User> =
+            rpc("get_users", json!({"sq": sq})).await.json();
+        res.users.iter().map(|x| x.id).collect::<Vec<i32>>()
+    };
+    assert_eq!(
+        find(json!({"org_id": org.id})).await,
+        vec![alice, bob, carol]
+    );
+    assert_eq!(find(json!({"org_id": {"$ne": org.id}})).await, vec![dave]);
+    assert_eq!(
+        find(json!({"org_id": {"$exists": false}})).await,
+        vec![dave]
+    );
+    let e: Value = rpc("get_users", json!({"sq": {}, "sort": ["org_id"]}))
+        .await
+        .json();
+    assert_eq!(e["code"], "query_err");
+
+    // the only owner can neither leave nor step down
+    let e: Value =
+        rpc("del_member", json!({"org_id": org.id, "user_id": alice}))
+            .await
+            .json();
+    assert_eq!(e["code"], "last_owner");
+    let e: Value = rpc(
+        "set_member",
+        json!({"org_id": org.id, "user_id": alice, "role": "Admin"}),
+    )
+    .await
+    .json();
+    assert_eq!(e["code"], "last_owner");
+    rpc(
+        "set_member",
+        json!({"org_id": org.id, "user_id": bob, "role": "Owner"}),
+    )
+    .await;
+    let response =
+        rpc("del_member", json!({"org_id": org.id, "user_id": alice})).await;
+    assert_eq!(response.status_code(), 200);
+
+    let server = &server;
+    let access = |username: &'static str| async move {
+        let rt = server
+            .post((URL.to_string() + "/login").as_str())
+            .json(&json!({"username": username, "password": "1234"}))
+            .await
+            .text();
+        server
+            .post((URL.to_string() + "/access").as_str())
+            .json(&json!({"rt": rt, "org_id": org.id}))
+            .await
+    };
+    let claims = token::verify_token::<token::UserTokenPayload>(
+        &access("carol").await.text(),
+        b"helloworld",
+    )
+    .unwrap();
+    assert_eq!(claims.org_id, Some(org.id));
+    assert_eq!(claims.org_role, Some("admin".to_string()));
+    let e: Value = access("dave").await.json();
+    assert_eq!(e["code"], "not_member");
+
+    rpc("del_org", json!({"id": org.id})).await;
+    let changes = rpc(
+        "get_user_changes",
+        json!({"from": 0, "actions": ["Membership"], "with_data": true}),
+    )
+    .await
+    .json::<Vec<UserChange>>();
+    assert_eq!(changes.len(), 7);
+    assert_eq!(changes[4].user_id, alice);
+    assert_eq!(
+        changes[4].data,
+        Some(json!({"org_id": org.id, "role": null}))
+    );
+}
+
+#[tokio::test]
+async fn metadata_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let rpc = |name: &str, body: Value| {
+        server
+            .post((URL.to_string() + "/server/" + name).as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let alice: User = rpc(
+        "reg",
+        json!({
+            "username": "alice",
+            "password": "1234",
+            "metadata": {"domain": {"plan": "pro", "seats": 5}}
+        }),
+    )
+    .await
+    .json();
+    assert_eq!(alice.metadata.domain["plan"], "pro");
+    let bob: User = rpc("reg", json!({"username": "bob", "password": "1234"}))
+        .await
+        .json();
+    assert!(bob.metadata.domain.is_empty());
+
+    let user: User = rpc(
+        "update_metadata",
+        json!({"user_id": bob.id, "domain": {"plan": "free", "seats": 1}}),
+    )
+    .await
+    .json();
+    assert_eq!(user.metadata.domain["seats"], 1);
+    let e: Value = rpc(
+        "update_metadata",
+        json!({"user_id": bob.id, "domain": {"notes": "x".repeat(256)}}),
+    )
+    .await
+    .json();
+    assert_eq!(e["code"], "metadata_too_large");
+
+    // users can only write their own namespace
+    let rt = server
+        .post((URL.to_string() + "/login").as_str())
+        .json(&json!({"username": "alice", "password": "1234"}))
+        .await
+        .text();
+    let user: User = server
+        .post((URL.to_string() + "/update_metadata").as_str())
+        .json(&json!({
+            "rt": rt,
+            "user": {"locale": "en"},
+            "domain": {"plan": "enterprise"}
+        }))
+        .await
+        .json();
+    assert_eq!(user.metadata.domain["plan"], "pro");
+    assert_eq!(user.metadata.user["locale"], "en");
+
+    let find = |sq: Value| async move {
+        let res: GetUsersRes<User> =
+            rpc("get_users", json!({"sq": sq})).await.json();
+        res.users.iter().map(|x| x.id).collect::<Vec<i32>>()
+    };
+    assert_eq!(
+        find(json!({"metadata.domain.plan": "pro"})).await,
+        [alice.id]
+    );
+    assert_eq!(
+        find(json!({"metadata.domain.seats": {"$gte": 1}})).await,
+        [alice.id, bob.id]
+    );
+    assert_eq!(
+        find(json!({"metadata.user.locale": {"$exists": false}})).await,
+        [bob.id]
+    );
+    assert_eq!(
+        find(json!({"metadata.domain.plan": {"$like": "fr%"}})).await,
+        [bob.id]
+    );
+    assert_eq!(
+        find(json!({"metadata.user.locale": {"$ne": "en"}})).await,
+        [bob.id]
+    );
+    let e: Value = rpc("get_users", json!({"sq": {"metadata": "pro"}}))
+        .await
+        .json();
+    assert_eq!(e["code"], "query_err");
+
+    let at = server
+        .post((URL.to_string() + "/access").as_str())
+        .json(&json!({"rt": rt}))
+        .await
+        .text();
+    let claims =
+        token::verify_token::<token::UserTokenPayload>(&at, b"helloworld")
+            .unwrap();
+    assert_eq!(
+        claims.metadata,
+        Some(json!({"domain": {"plan": "pro"}, "user": {"locale": "en"}}))
+    );
+
+    let changes = rpc(
+        "get_user_changes",
+        json!({"from": 0, "actions": ["Update"], "with_data": true}),
+    )
+    .await
+    .json::<Vec<UserChange>>();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(
+        changes[1].data,
+        Some(json!({"metadata": {
+            "domain": {"plan": "pro", "seats": 5},
+            "user": {"locale": "en"}
+        }}))
+    );
+}
+
+#[tokio::test]
+async fn impersonate_ok() {
+    truncate_tables_if_allowed();
+    let server = new_test_server();
+    let rpc = |name: &str, body: Value| {
+        server
+            .post((URL.to_string() + "/server/" + name).as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let user: User =
+        rpc("reg", json!({"username": "alice", "password": "1234"}))
+            .await
+            .json();
+    rpc(
+        "set_role",
+        json!({"name": "editor", "permissions": ["edit"]}),
+    )
+    .await;
+    rpc("assign_role", json!({"user_id": user.id, "role": "editor"})).await;
+
+    let at = rpc(
+        "impersonate",
+        json!({"user_id": user.id, "actor": "support:bob", "reason": "#42"}),
+    )
+    .await
+    .text();
+    let claims = token::verify_at(&at, 3600.0, true).unwrap();
+    assert_eq!(claims.user_id, user.id);
+    assert_eq!(claims.permissions, vec!["edit".to_string()]);
+    assert_eq!(claims.act.unwrap().sub, "support:bob");
+    assert!((claims.exp.unwrap() - claims.created - 300.0).abs() < 1.0);
+    let e = token::verify_at(&at, 3600.0, false).unwrap_err();
+    assert_eq!(
+        serde_json::to_value(e).unwrap()["code"],
+        "impersonation_denied"
+    );
+
+    let e: Value =
+        rpc("impersonate", json!({"user_id": user.id, "actor": ""}))
+            .await
+            .json();
+    assert_eq!(e["code"], "err");
+
+    let changes = rpc(
+        "get_user_changes",
+        json!({"from": 0, "actions": ["Impersonation"], "with_data": true}),
+    )
+    .await
+    .json::<Vec<UserChange>>();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].data,
+        Some(json!({
+            "actor": "support:bob",
+            "reason": "#42",
+            "domain": null,
+            "org_id": null,
+            "client_id": null
+        }))
+    );
+
+    // recorded together with the calling domain
+    let events =
+        rpc("get_auth_events", json!({"sq": {"kind": "impersonation"}}))
+            .await
+            .json::<Value>();
+    let events = events["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["user_id"], user.id);
+    assert_eq!(events[0]["domain"], Value::Null);
+    assert_eq!(events[0]["rpc"], "impersonate");
+}
+
+#[tokio::test]
+async fn auth_events_ok() {
+    truncate_tables_if_allowed();
+    // real connections, so forwarded headers of trusted proxies are used
+    let server = TestServer::new(
+        get_router().into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .unwrap();
+    let url = "/rpc";
+    let rpc = |name: &str, body: Value| {
+        server
+            .post((url.to_string() + "/server/" + name).as_str())
+            .json(&body)
+            .add_header("domain_secret", DOMAIN_SECRET)
+    };
+    let user: User =
+        rpc("reg", json!({"username": "alice", "password": "1234"}))
+            .await
+            .json();
+    let login = |password: &str| {
+        server
+            .post((url.to_string() + "/login").as_str())
+            .json(&json!({"username": "alice", "password": password}))
+            .add_header("x-forwarded-for", "10.0.0.1, 10.0.0.2")
+            .add_header("user-agent", "tester")
+    };
+    login("4321").await;
+    let rt = login("1234").await.text();
+    server
+        .post((url.to_string() + "/access").as_str())
+        .json(&json!({"rt": rt, "domain": "shop"}))
+        .await;
+    server
+        .post((url.to_string() + "/logout").as_str())
+        .json(&json!({"rt": rt}))
+        .await;
+    server
+        .post((url.to_string() + "/server/get_roles").as_str())
+        .add_header("domain_secret", "wrong")
+        .await;
+
+    let res: GetAuthEventsRes<AuthEvent> = rpc(
+        "get_auth_events",
+        json!({"sq": {"user_id": user.id}, "count": true}),
+    )
+    .await
+    .json();
+    assert_eq!(res.total, Some(4));
+    let kinds: Vec<AuthEventKind> =
+        res.events.iter().map(|x| x.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            AuthEventKind::Login,
+            AuthEventKind::Login,
+            AuthEventKind::Access,
+            AuthEventKind::Logout
+        ]
+    );
+    let failed = &res.events[0];
+    assert_eq!(failed.outcome, AuthOutcome::Failure);
+    assert_eq!(failed.reason, Some("incorrect password".to_string()));
+    assert_eq!(failed.ip, Some("10.0.0.1".to_string()));
+    assert_eq!(failed.user_agent, Some("tester".to_string()));
+    assert_eq!(res.events[1].outcome, AuthOutcome::Success);
+    assert_eq!(res.events[2].domain, Some("shop".to_string()));
+
+    let res: GetAuthEventsRes<AuthEvent> = rpc(
+        "get_auth_events",
+        json!({
+            "sq": {"kind": "server", "rpc": {"$in": ["reg", "get_roles"]}},
+            "sort": ["-id"]
+        }),
+    )
+    .await
+    .json();
+    assert_eq!(res.events[0].rpc, Some("get_roles".to_string()));
+    assert_eq!(res.events[0].outcome, AuthOutcome::Failure);
+    assert_eq!(res.events[0].reason, Some("invalid secret".to_string()));
+    assert_eq!(res.events[1].rpc, Some("reg".to_string()));
+    assert_eq!(res.events[1].outcome, AuthOutcome::Success);
+
+    let response = rpc(
+        "export_auth_events",
+        json!({"sq": {"kind": {"$in": ["login", "logout"]}}}),
+    )
+    .await;
+    assert_eq!(response.header("content-type"), "application/jsonl");
+    let events: Vec<AuthEvent> = response
+        .text()
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert_eq!(events.len(), 3);
+    let response = rpc("export_auth_events", json!({"sq": {}})).await;
+    assert_eq!(response.status_code(), 400);
+    let e: Value = response.json();
+    assert_eq!(e["code"], "export_too_large");
+    let response =
+        rpc("export_auth_events", json!({"sq": {}, "limit": 2})).await;
+    assert_eq!(response.text().lines().count(), 2);
+
+    // forwarded headers of other peers are ignored
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+    let origin = Origin::new(&headers, Some(([10, 9, 9, 9], 80).into()));
+    assert_eq!(origin.ip, Some("10.9.9.9".to_string()));
+    let origin = Origin::new(&headers, None);
+    assert_eq!(origin.ip, None);
+
+    // bodies of server routes are read up to a limit
+    let response = server
+        .post((url.to_string() + "/server/get_roles").as_str())
+        .add_header("domain_secret", DOMAIN_SECRET)
+        .text("x".repeat(3 * 1024 * 1024))
+        .await;
+    assert_eq!(response.status_code(), 413);
+
+    // events are append-only
+    let con = &mut db::con().unwrap();
+    assert!(con
+        .batch_execute("UPDATE auth_event SET reason = NULL")
+        .is_err());
+}